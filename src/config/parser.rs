@@ -50,7 +50,9 @@ fn ensure_yaml_path(path: &Path) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::Config;
+    use crate::config::{
+        Action, AppMatch, CompareOp, Condition, Config, RepeatPolicy, RuleType, VarValue, Weekday,
+    };
 
     #[test]
     fn load_from_file_should_reject_non_yaml_extension() {
@@ -59,6 +61,286 @@ mod tests {
         assert!(msg.contains(".yaml or .yml"), "unexpected error: {}", msg);
     }
 
+    #[test]
+    fn remap_should_accept_numeric_and_named_sources() {
+        let yaml = "device_name: gpio-keys\nhardware_map:\n  115: VOL_UP\nremap:\n  VOL_UP: 24\n  114: 25\n";
+        let cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let table = cfg.remap_table();
+        assert_eq!(table.get(&115), Some(&24));
+        assert_eq!(table.get(&114), Some(&25));
+    }
+
+    #[test]
+    fn layer_actions_should_reference_existing_layers() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: fn\n    trigger: \"116\"\n    rule_type: SHORT_PRESS\n    action:\n      type: layer_momentary\n      layer: media\nlayers:\n  - name: media\n    rules:\n      - id: next\n        trigger: \"115\"\n        rule_type: CLICK\n        action:\n          type: send_key\n          key_code: 163\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.validate().unwrap();
+        assert_eq!(cfg.all_rules().count(), 2);
+
+        cfg.layers[0].name = "other".to_string();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("unknown layer"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn passthrough_should_be_rejected_for_tap_hold() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: dual\n    trigger: \"114\"\n    rule_type: TAP_HOLD\n    passthrough: true\n    tap_hold:\n      hold_key: 42\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("passthrough"), "unexpected error: {}", msg);
+
+        cfg.rules[0].passthrough = false;
+        cfg.validate().unwrap();
+    }
+
+    #[test]
+    fn on_release_should_only_be_accepted_for_press_rules() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: tap\n    trigger: \"114\"\n    rule_type: CLICK\n    on_release: true\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("on_release"), "unexpected error: {}", msg);
+
+        cfg.rules[0].rule_type = RuleType::LongPress;
+        cfg.validate().unwrap();
+    }
+
+    #[test]
+    fn repeat_policies_should_be_validated() {
+        let yaml = "device_name: gpio-keys\nkey_repeat_policy:\n  \"114\": forward\nrules:\n  - id: tap\n    trigger: \"114\"\n    rule_type: CLICK\n    repeat_policy: trigger\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("repeat_policy"), "unexpected error: {}", msg);
+
+        cfg.rules[0].rule_type = RuleType::ShortPress;
+        cfg.validate().unwrap();
+
+        cfg.key_repeat_policy
+            .insert("NO_SUCH_KEY".to_string(), RepeatPolicy::Drop);
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(
+            msg.contains("key_repeat_policy"),
+            "unexpected error: {}",
+            msg
+        );
+    }
+
+    #[test]
+    fn modifier_hold_should_require_ordered_combo() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: chord\n    trigger: \"116+115\"\n    rule_type: COMBO_CLICK\n    modifier_hold_ms: 400\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("ordered"), "unexpected error: {}", msg);
+
+        cfg.rules[0].ordered = true;
+        cfg.validate().unwrap();
+
+        cfg.rules[0].rule_type = RuleType::Click;
+        cfg.rules[0].trigger = "116".to_string();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("COMBO_*"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn pattern_rules_should_require_a_valid_pattern_and_own_their_key() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: morse\n    trigger: POWER\n    rule_type: PATTERN\n    pattern: \".-x\"\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.hardware_map.insert(116, "POWER".to_string());
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("pattern"), "unexpected error: {}", msg);
+
+        cfg.rules[0].pattern = Some(".-..".to_string());
+        cfg.validate().unwrap();
+
+        let mut click = cfg.rules[0].clone();
+        click.id = "click".to_string();
+        click.rule_type = RuleType::Click;
+        click.pattern = None;
+        cfg.rules.push(click);
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("PATTERN"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn conditions_should_parse_and_validate_time_and_weekdays() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: night\n    trigger: \"114\"\n    rule_type: CLICK\n    conditions:\n      - type: time_range\n        start: \"22:00\"\n        end: \"24:00\"\n      - type: weekdays\n        days: [sat, sun]\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            cfg.rules[0].conditions[1],
+            Condition::Weekdays {
+                days: vec![Weekday::Sat, Weekday::Sun]
+            }
+        );
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("time_range"), "unexpected error: {}", msg);
+
+        cfg.rules[0].conditions[0] = Condition::TimeRange {
+            start: "22:00".to_string(),
+            end: "06:30".to_string(),
+        };
+        cfg.validate().unwrap();
+
+        cfg.rules[0].conditions[1] = Condition::Weekdays { days: Vec::new() };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("weekdays"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn foreground_app_condition_and_file_source_should_be_validated() {
+        let yaml = "device_name: gpio-keys\nsettings:\n  foreground_app:\n    source: file\nrules:\n  - id: page\n    trigger: \"114\"\n    rule_type: CLICK\n    conditions:\n      - type: foreground_app\n        packages: [org.koreader.launcher]\n    action:\n      type: send_key\n      key_code: 109\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        assert!(cfg.uses_condition(|c| matches!(c, Condition::ForegroundApp { .. })));
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("path"), "unexpected error: {}", msg);
+
+        cfg.settings.foreground_app.path = Some("/data/local/tmp/foreground_app".to_string());
+        cfg.validate().unwrap();
+
+        cfg.rules[0].conditions[0] = Condition::ForegroundApp {
+            packages: Vec::new(),
+            mode: AppMatch::Deny,
+        };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("foreground_app"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn variable_actions_and_conditions_should_parse_typed_values() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: drive\n    trigger: \"114\"\n    rule_type: LONG_PRESS\n    action:\n      type: macro\n      actions:\n        - type: toggle_variable\n          name: driving\n        - type: set_variable\n          name: profile\n          value: car\n        - type: increment_variable\n          name: trips\n  - id: skip\n    trigger: \"115\"\n    rule_type: CLICK\n    conditions:\n      - type: variable\n        name: trips\n        op: greater_than\n        value: 2\n    action:\n      type: send_key\n      key_code: 163\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.validate().unwrap();
+        let Action::Macro { actions } = &cfg.rules[0].action else {
+            panic!("expected a macro");
+        };
+        assert_eq!(
+            actions[1],
+            Action::SetVariable {
+                name: "profile".to_string(),
+                value: VarValue::Str("car".to_string()),
+            }
+        );
+        assert_eq!(
+            actions[2],
+            Action::IncrementVariable {
+                name: "trips".to_string(),
+                by: 1,
+            }
+        );
+
+        cfg.rules[1].conditions[0] = Condition::Variable {
+            name: "driving".to_string(),
+            op: CompareOp::LessThan,
+            value: VarValue::Bool(true),
+        };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("int value"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn condition_groups_should_parse_nested_and_validate_each_level() {
+        let yaml = "device_name: gpio-keys\nsettings:\n  screen_state:\n    source: file\n    path: /data/local/tmp/screen\nrules:\n  - id: quiet\n    trigger: \"114\"\n    rule_type: CLICK\n    conditions:\n      - type: any\n        conditions:\n          - type: weekdays\n            days: [sat, sun]\n          - type: not\n            condition:\n              type: screen_state\n              state: \"on\"\n    action:\n      type: send_key\n      key_code: 113\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.validate().unwrap();
+        assert!(cfg.uses_condition(|c| matches!(c, Condition::ScreenState { .. })));
+        let Condition::Any { conditions } = &cfg.rules[0].conditions[0] else {
+            panic!("expected an any group");
+        };
+        assert!(matches!(conditions[1], Condition::Not { .. }));
+
+        let round_trip: Config =
+            serde_yaml::from_str(&serde_yaml::to_string(&cfg).unwrap()).unwrap();
+        assert_eq!(round_trip.rules, cfg.rules);
+
+        cfg.rules[0].conditions[0] = Condition::Not {
+            condition: Box::new(Condition::All {
+                conditions: vec![Condition::Weekdays { days: Vec::new() }],
+            }),
+        };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("weekdays"), "unexpected error: {}", msg);
+
+        cfg.rules[0].conditions[0] = Condition::Any {
+            conditions: Vec::new(),
+        };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("group"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn battery_conditions_and_triggers_should_be_validated() {
+        let yaml = "device_name: gpio-keys\nsettings:\n  power_supply:\n    root: /tmp/power_supply\nrules:\n  - id: saver\n    trigger: \"BATTERY:BELOW_15\"\n    rule_type: EXTERNAL\n    conditions:\n      - type: charging\n        charging: false\n    action:\n      type: set_variable\n      name: saver\n      value: true\n  - id: torch\n    trigger: \"114\"\n    rule_type: LONG_PRESS\n    conditions:\n      - type: battery_level\n        op: greater_or_equal\n        value: 20\n    action:\n      type: builtin_command\n      command: toggle_flashlight\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.validate().unwrap();
+        assert_eq!(cfg.settings.power_supply.poll_ms, 5000);
+
+        cfg.rules[0].trigger = "BATTERY:LOW".to_string();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("BATTERY"), "unexpected error: {}", msg);
+
+        cfg.rules[0].trigger = "BATTERY".to_string();
+        cfg.rules[1].conditions[0] = Condition::BatteryLevel {
+            op: CompareOp::LessThan,
+            value: 150,
+        };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("battery_level"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn switch_triggers_and_conditions_should_use_ev_sw_names() {
+        let yaml = "device_name: gpio-keys\nsettings:\n  switch_devices: [hall-sensor]\nrules:\n  - id: cover_closed\n    trigger: \"SWITCH:LID_ON\"\n    rule_type: EXTERNAL\n    action:\n      type: macro\n      actions:\n        - type: screen_off\n        - type: set_variable\n          name: covered\n          value: true\n  - id: media\n    trigger: \"115\"\n    rule_type: CLICK\n    conditions:\n      - type: switch\n        switch: HEADPHONE_INSERT\n    action:\n      type: send_key\n      key_code: 164\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.validate().unwrap();
+        // The screen_off macro step keeps the screen state poller running
+        assert!(cfg.uses_action(|a| matches!(a, Action::ScreenOff)));
+        assert!(!cfg.uses_action(|a| matches!(a, Action::ToggleScreen)));
+        assert_eq!(
+            cfg.rules[1].conditions[0],
+            Condition::Switch {
+                switch: "HEADPHONE_INSERT".to_string(),
+                on: true,
+            }
+        );
+
+        cfg.rules[0].trigger = "SWITCH:SW_LID_ON".to_string();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("SWITCH"), "unexpected error: {}", msg);
+
+        cfg.rules[0].trigger = "SWITCH:CAMERA_LENS_COVER_OFF".to_string();
+        cfg.rules[1].conditions[0] = Condition::Switch {
+            switch: "FLIP".to_string(),
+            on: true,
+        };
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("unknown switch"), "unexpected error: {}", msg);
+    }
+
+    #[test]
+    fn external_rules_should_parse_payload_and_skip_key_checks() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: home\n    trigger: \"GEOFENCE:ENTER\"\n    rule_type: EXTERNAL\n    payload:\n      location_id: HOME\n    action:\n      type: set_variable\n      name: profile\n      value: home\n  - id: any\n    trigger: GEOFENCE\n    rule_type: EXTERNAL\n    action:\n      type: send_key\n      key_code: 30\n";
+        let mut cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        cfg.validate().unwrap();
+        assert!(cfg.analyze().is_empty());
+        assert_eq!(cfg.rules[0].external_trigger(), ("GEOFENCE", Some("ENTER")));
+        assert_eq!(cfg.rules[1].external_trigger(), ("GEOFENCE", None));
+        assert_eq!(cfg.rules[0].payload["location_id"], "HOME");
+
+        cfg.rules[1].trigger = "GEOFENCE:".to_string();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("EXTERNAL"), "unexpected error: {}", msg);
+
+        cfg.rules[1].trigger = "GEOFENCE".to_string();
+        cfg.rules[1].passthrough = true;
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("passthrough"), "unexpected error: {}", msg);
+
+        cfg.rules[1].passthrough = false;
+        cfg.rules[1].rule_type = RuleType::Click;
+        cfg.rules[1].trigger = "114".to_string();
+        cfg.rules[1].payload = cfg.rules[0].payload.clone();
+        let msg = cfg.validate().unwrap_err().to_string();
+        assert!(msg.contains("payload"), "unexpected error: {}", msg);
+    }
+
     #[test]
     fn save_to_file_should_reject_non_yaml_extension() {
         let cfg = Config::default();
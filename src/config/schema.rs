@@ -13,15 +13,41 @@ pub struct Config {
     #[serde(default)]
     pub hardware_map: HashMap<u16, String>,
 
+    /// 1:1 key remapping: source keycode or hardware_map name -> target keycode.
+    /// DOWN / UP / REPEAT are forwarded as the target without gesture detection.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub remap: HashMap<String, u16>,
+
     /// List of rules
     #[serde(default)]
     pub rules: Vec<Rule>,
 
+    /// Named layers stacked on top of `rules` / `remap` by layer_* actions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+
+    /// Auto-repeat (value 2) policy per key (keycode or hardware_map name), used when
+    /// none of the key's rules sets `repeat_policy`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub key_repeat_policy: HashMap<String, RepeatPolicy>,
+
     /// Global settings
     #[serde(default)]
     pub settings: GlobalSettings,
 }
 
+/// A named rule set. While active, its rules and remaps take over the keys they use;
+/// keys it does not use fall through to the layers below it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub name: String,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub remap: HashMap<String, u16>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct Rule {
@@ -34,6 +60,321 @@ pub struct Rule {
     pub enabled: bool,
     #[serde(default)]
     pub description: String,
+    /// SEQUENCE only: what to do with buffered taps when the sequence is not completed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_fallback: Option<SequenceFallback>,
+    /// MULTI_CLICK only: number of taps required (>= 2)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+    /// Per-rule overrides of the global thresholds in `settings`
+    #[serde(default, skip_serializing_if = "RuleTiming::is_empty")]
+    pub timing: RuleTiming,
+    /// SHORT_PRESS / LONG_PRESS only: keep firing the action while the key stays down
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<HoldRepeat>,
+    /// TAP_HOLD only: hold behaviour; `action` is the tap behaviour
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tap_hold: Option<TapHold>,
+    /// Replay the raw key to the system when a gesture on this rule's key(s) matches no rule
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub passthrough: bool,
+    /// SHORT_PRESS / LONG_PRESS only: fire on key-up based on the hold time, not while held
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub on_release: bool,
+    /// What to do with the key's auto-repeat events; overrides `key_repeat_policy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_policy: Option<RepeatPolicy>,
+    /// COMBO_* only: the keys must go down in trigger order
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ordered: bool,
+    /// Ordered COMBO_* only: hold the first key this long before pressing the next one;
+    /// the window then applies to the remaining keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modifier_hold_ms: Option<u32>,
+    /// Minimum time between two fires of this rule; fires in between are suppressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_ms: Option<u32>,
+    /// Fires allowed within any 60 s span; further fires are suppressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_per_minute: Option<u32>,
+    /// PATTERN only: presses as `.` (shorter than short_press_threshold_ms) and `-`
+    /// (longer), e.g. `.-..`; presses belong together while within sequence_gap_ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Checked when the rule fires; all must hold, otherwise the action is skipped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    /// EXTERNAL only: payload fields the posted event must carry with these values
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub payload: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    /// Local time in [start, end) as "HH:MM"; an end before the start crosses midnight
+    TimeRange { start: String, end: String },
+    /// Local day of the week is one of `days`
+    Weekdays { days: Vec<Weekday> },
+    /// Foreground app package is (allow) / is not (deny) one of `packages`
+    ForegroundApp {
+        packages: Vec<String>,
+        #[serde(default)]
+        mode: AppMatch,
+    },
+    /// Screen is on / off
+    ScreenState { state: ScreenState },
+    /// Context variable compared with `value`; an unset variable only satisfies not_equals
+    Variable {
+        name: String,
+        #[serde(default)]
+        op: CompareOp,
+        value: VarValue,
+    },
+    /// Battery capacity in percent compared with `value`; an unknown level never holds
+    BatteryLevel { op: CompareOp, value: u8 },
+    /// Battery is (true) / is not (false) charging; a full battery on power counts as charging
+    Charging {
+        #[serde(default = "default_true")]
+        charging: bool,
+    },
+    /// EV_SW switch (e.g. LID) is set (on: lid shut, jack inserted, ...) or not;
+    /// a switch no device has reported never holds
+    Switch {
+        switch: String,
+        #[serde(default = "default_true")]
+        on: bool,
+    },
+    /// Every nested condition holds
+    All { conditions: Vec<Condition> },
+    /// At least one nested condition holds
+    Any { conditions: Vec<Condition> },
+    /// The nested condition does not hold
+    Not { condition: Box<Condition> },
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompareOp {
+    #[default]
+    Equals,
+    NotEquals,
+    /// Ordering comparisons apply to int values only
+    GreaterThan,
+    GreaterOrEqual,
+    LessThan,
+    LessOrEqual,
+}
+
+impl CompareOp {
+    pub fn compare<T: PartialOrd>(self, current: T, value: T) -> bool {
+        match self {
+            CompareOp::Equals => current == value,
+            CompareOp::NotEquals => current != value,
+            CompareOp::GreaterThan => current > value,
+            CompareOp::GreaterOrEqual => current >= value,
+            CompareOp::LessThan => current < value,
+            CompareOp::LessOrEqual => current <= value,
+        }
+    }
+}
+
+/// Source of the EXTERNAL rules fired by EV_SW switches, e.g. "SWITCH:LID_ON".
+pub const SWITCH_SOURCE: &str = "SWITCH";
+
+/// EV_SW switch names (input-event-codes.h without the SW_ prefix), by code.
+const SWITCH_NAMES: [&str; 17] = [
+    "LID",
+    "TABLET_MODE",
+    "HEADPHONE_INSERT",
+    "RFKILL_ALL",
+    "MICROPHONE_INSERT",
+    "DOCK",
+    "LINEOUT_INSERT",
+    "JACK_PHYSICAL_INSERT",
+    "VIDEOOUT_INSERT",
+    "CAMERA_LENS_COVER",
+    "KEYPAD_SLIDE",
+    "FRONT_PROXIMITY",
+    "ROTATE_LOCK",
+    "LINEIN_INSERT",
+    "MUTE_DEVICE",
+    "PEN_INSERTED",
+    "MACHINE_COVER",
+];
+
+pub fn switch_code(name: &str) -> Option<u16> {
+    SWITCH_NAMES
+        .iter()
+        .position(|n| *n == name)
+        .map(|code| code as u16)
+}
+
+pub fn switch_name(code: u16) -> Option<&'static str> {
+    SWITCH_NAMES.get(code as usize).copied()
+}
+
+/// (code, on) of a SWITCH trigger event: NAME_ON when the switch gets set, NAME_OFF
+/// when it is cleared.
+pub fn parse_switch_event(event: &str) -> Option<(u16, bool)> {
+    if let Some(name) = event.strip_suffix("_ON") {
+        switch_code(name).map(|code| (code, true))
+    } else {
+        switch_code(event.strip_suffix("_OFF")?).map(|code| (code, false))
+    }
+}
+
+/// Source of the EXTERNAL rules fired by battery changes, e.g. "BATTERY:BELOW_15".
+pub const BATTERY_SOURCE: &str = "BATTERY";
+
+/// Event of a BATTERY trigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatteryEvent {
+    /// BELOW_n: the level dropped below n percent
+    Below(u8),
+    /// ABOVE_n: the level rose above n percent
+    Above(u8),
+    /// CHARGING: charging started
+    Charging,
+    /// DISCHARGING: charging stopped
+    Discharging,
+}
+
+impl BatteryEvent {
+    pub fn parse(event: &str) -> Option<Self> {
+        let percent = |n: &str| n.parse::<u8>().ok().filter(|n| *n <= 100);
+        match event {
+            "CHARGING" => Some(Self::Charging),
+            "DISCHARGING" => Some(Self::Discharging),
+            _ => {
+                if let Some(n) = event.strip_prefix("BELOW_") {
+                    percent(n).map(Self::Below)
+                } else {
+                    percent(event.strip_prefix("ABOVE_")?).map(Self::Above)
+                }
+            }
+        }
+    }
+}
+
+/// Value of a runtime context variable.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum VarValue {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenState {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AppMatch {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// Resolve a trigger token (raw keycode or hardware_map name) to a keycode.
+pub fn resolve_key(token: &str, name_to_code: &HashMap<String, u16>) -> Option<u16> {
+    let t = token.trim();
+    if t.is_empty() {
+        return None;
+    }
+    if let Ok(code) = t.parse::<u16>() {
+        return Some(code);
+    }
+    name_to_code.get(t).copied()
+}
+
+/// Minutes since midnight of an "HH:MM" time of day.
+pub fn parse_time_of_day(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TapHold {
+    /// Key held down for as long as the dual-role key is held
+    pub hold_key: u16,
+    /// Hold decision deadline; defaults to the rule's short_press_threshold_ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tapping_term_ms: Option<u32>,
+    #[serde(default)]
+    pub flavor: TapHoldFlavor,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TapHoldFlavor {
+    /// Hold only once the tapping term expires
+    #[default]
+    TapPreferred,
+    /// Hold early when another key is pressed and released inside the tapping term
+    PermissiveHold,
+    /// Hold early as soon as another key is pressed
+    HoldOnOtherKeyPress,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HoldRepeat {
+    /// Steady-state delay between repeats
+    pub interval_ms: u32,
+    /// Optional leading delays used before settling on `interval_ms`, e.g. [400, 200]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acceleration_ms: Vec<u32>,
+}
+
+impl HoldRepeat {
+    /// Delay before the `step`-th repeat (0-based).
+    pub fn delay_ms(&self, step: usize) -> u32 {
+        self.acceleration_ms
+            .get(step)
+            .copied()
+            .unwrap_or(self.interval_ms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RuleTiming {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_press_threshold_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub short_press_threshold_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub double_tap_interval_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub combination_timeout_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_gap_ms: Option<u32>,
+}
+
+impl RuleTiming {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -41,11 +382,51 @@ pub struct Rule {
 pub enum RuleType {
     Click,           // < 300ms, Release
     DoubleClick,     // < 300ms interval, 2nd Release
+    MultiClick,      // < 300ms interval, Nth Release (see Rule::count)
     ShortPress,      // >= 300ms, Hold
     LongPress,       // >= 800ms, Hold
     ComboClick,      // Combination + Click
     ComboShortPress, // Combination + Hold >= 300ms
     ComboLongPress,  // Combination + Hold >= 800ms
+    Sequence,        // A->B->C taps, each within sequence_gap_ms
+    TapHold,         // Tap -> action, Hold -> tap_hold.hold_key held down
+    Pattern,         // Short (.) / long (-) presses on one key, see Rule::pattern
+    External,        // "SOURCE:EVENT" (or just "SOURCE") posted to /api/trigger
+}
+
+impl RuleType {
+    pub fn is_combo(self) -> bool {
+        matches!(
+            self,
+            RuleType::ComboClick | RuleType::ComboShortPress | RuleType::ComboLongPress
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SequenceFallback {
+    /// Re-dispatch the buffered taps as if the sequence rule did not exist
+    #[default]
+    Replay,
+    /// Swallow the buffered taps
+    Drop,
+}
+
+/// Handling of the kernel's auto-repeat (value 2) events on a mapped key.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RepeatPolicy {
+    /// Swallow the repeats
+    Drop,
+    /// Send the repeats to the system as-is
+    Forward,
+    /// Send the repeats only if the press itself was passed through to the system
+    #[default]
+    ForwardIfPassedThrough,
+    /// SHORT_PRESS / LONG_PRESS / COMBO_*_PRESS only: fire the action again on each
+    /// repeat once the rule has fired
+    Trigger,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -54,6 +435,13 @@ pub enum Action {
     SendKey {
         key_code: u16,
     },
+    /// Press and keep a key down (pair with key_up)
+    KeyDown {
+        key_code: u16,
+    },
+    KeyUp {
+        key_code: u16,
+    },
     #[serde(alias = "run_shell")]
     Shell {
         cmd: String,
@@ -75,9 +463,28 @@ pub enum Action {
         intent: IntentSpec,
     },
     ToggleScreen,
+    /// Wake the screen; nothing happens if it is already on
+    ScreenOn,
+    /// Turn the screen off; nothing happens if it is already off
+    ScreenOff,
     ToggleRule {
         rule_id: String,
     },
+    /// Runtime context variables; never written to the config file
+    SetVariable {
+        name: String,
+        value: VarValue,
+    },
+    /// Add `by` to an int variable; an unset variable starts at 0
+    IncrementVariable {
+        name: String,
+        #[serde(default = "default_increment")]
+        by: i64,
+    },
+    /// Flip a bool variable; an unset variable becomes true
+    ToggleVariable {
+        name: String,
+    },
     VolumeControl {
         direction: VolumeDirection,
     },
@@ -90,6 +497,18 @@ pub enum Action {
         duration_ms: u32,
     },
     Intercept,
+    /// Activate a layer while the triggering key(s) stay held (hold-type rules only)
+    LayerMomentary {
+        layer: String,
+    },
+    /// Activate the layer, or deactivate it if already active
+    LayerToggle {
+        layer: String,
+    },
+    /// Activate the layer for the next rule that fires
+    LayerOneShot {
+        layer: String,
+    },
     Macro {
         actions: Vec<Action>,
     },
@@ -147,6 +566,8 @@ pub struct GlobalSettings {
     pub double_tap_interval_ms: u32,
     #[serde(default = "default_combination_timeout")]
     pub combination_timeout_ms: u32,
+    #[serde(default = "default_sequence_gap")]
+    pub sequence_gap_ms: u32,
     #[serde(default = "default_true")]
     pub enable_haptic: bool,
     #[serde(default = "default_true")]
@@ -155,6 +576,81 @@ pub struct GlobalSettings {
     pub log_level: String,
     #[serde(default = "default_rule_timeout")]
     pub rule_timeout_ms: u32,
+    /// Where foreground_app conditions get the foreground package from
+    #[serde(default)]
+    pub foreground_app: ForegroundAppSettings,
+    /// Where screen_state conditions and screen actions get the screen state from
+    #[serde(default)]
+    pub screen_state: ScreenStateSettings,
+    /// File the context variables are kept in across restarts; unset keeps them in memory only
+    #[serde(default)]
+    pub variables_path: Option<String>,
+    /// Where battery_level / charging conditions and BATTERY triggers read the battery from
+    #[serde(default)]
+    pub power_supply: PowerSupplySettings,
+    /// More evdev devices (by name) whose EV_SW switches are read, without grabbing them;
+    /// the switches of the main device are always read. Opened at startup
+    #[serde(default)]
+    pub switch_devices: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ForegroundAppSettings {
+    #[serde(default)]
+    pub source: ForegroundAppSource,
+    /// `file` source: file holding the package name
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Refresh interval of the cached package; only polled while a rule needs it
+    #[serde(default = "default_foreground_poll")]
+    pub poll_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForegroundAppSource {
+    /// Parse `dumpsys activity activities`
+    #[default]
+    Dumpsys,
+    /// Read the package name from `path` (tests, or fed by another tool)
+    File,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ScreenStateSettings {
+    #[serde(default)]
+    pub source: ScreenStateSource,
+    /// `backlight` source: brightness node; `file` source: file holding "on" / "off"
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Refresh interval of the cached state; only polled while a rule needs it
+    #[serde(default = "default_screen_poll")]
+    pub poll_ms: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PowerSupplySettings {
+    /// Sysfs power-supply class directory; the first supply with a `capacity` is the battery
+    #[serde(default = "default_power_supply_root")]
+    pub root: String,
+    /// Refresh interval of the cached battery state; only polled while a rule needs it
+    #[serde(default = "default_power_poll")]
+    pub poll_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScreenStateSource {
+    /// Parse `dumpsys power`
+    #[default]
+    Dumpsys,
+    /// Sysfs backlight brightness at `path`; on while non-zero
+    Backlight,
+    /// Read "on" / "off" from `path` (tests, or fed by another tool)
+    File,
 }
 
 // Default helpers
@@ -173,15 +669,33 @@ fn default_double_tap_interval() -> u32 {
 fn default_combination_timeout() -> u32 {
     200
 }
+fn default_sequence_gap() -> u32 {
+    500
+}
 fn default_tap_interval() -> u32 {
     50
 }
+fn default_increment() -> i64 {
+    1
+}
 fn default_log_level() -> String {
     "info".to_string()
 }
 fn default_rule_timeout() -> u32 {
     5000
 }
+fn default_foreground_poll() -> u32 {
+    1000
+}
+fn default_screen_poll() -> u32 {
+    500
+}
+fn default_power_supply_root() -> String {
+    "/sys/class/power_supply".to_string()
+}
+fn default_power_poll() -> u32 {
+    5000
+}
 
 impl Default for Config {
     fn default() -> Self {
@@ -195,12 +709,138 @@ impl Default for Config {
         Self {
             device_name: "gpio-keys".to_string(),
             hardware_map,
+            remap: HashMap::new(),
             rules: Vec::new(),
+            layers: Vec::new(),
+            key_repeat_policy: HashMap::new(),
             settings: GlobalSettings::default(),
         }
     }
 }
 
+impl Config {
+    /// hardware_map inverted: key name -> keycode.
+    pub fn name_to_code(&self) -> HashMap<String, u16> {
+        self.hardware_map
+            .iter()
+            .map(|(code, name)| (name.clone(), *code))
+            .collect()
+    }
+
+    /// Remap table keyed by source keycode; unresolvable entries are skipped.
+    pub fn remap_table(&self) -> HashMap<u16, u16> {
+        self.resolve_remap(&self.remap)
+    }
+
+    /// Per-key repeat policies keyed by keycode; unresolvable entries are skipped.
+    pub fn key_repeat_policy_table(&self) -> HashMap<u16, RepeatPolicy> {
+        let name_to_code = self.name_to_code();
+        self.key_repeat_policy
+            .iter()
+            .filter_map(|(key, policy)| resolve_key(key, &name_to_code).map(|code| (code, *policy)))
+            .collect()
+    }
+
+    pub fn resolve_remap(&self, remap: &HashMap<String, u16>) -> HashMap<u16, u16> {
+        let name_to_code = self.name_to_code();
+        remap
+            .iter()
+            .filter_map(|(source, target)| {
+                resolve_key(source, &name_to_code).map(|code| (code, *target))
+            })
+            .collect()
+    }
+
+    /// Base rules followed by the rules of every layer.
+    pub fn all_rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules
+            .iter()
+            .chain(self.layers.iter().flat_map(|l| l.rules.iter()))
+    }
+
+    /// Whether any rule (base or layer) has a condition, at any depth, matching `pred`.
+    pub fn uses_condition(&self, pred: impl Fn(&Condition) -> bool) -> bool {
+        self.all_rules()
+            .any(|r| r.conditions.iter().any(|c| c.contains(&pred)))
+    }
+
+    /// Whether any rule (base or layer) has an action, including macro steps, matching `pred`.
+    pub fn uses_action(&self, pred: impl Fn(&Action) -> bool) -> bool {
+        self.all_rules().any(|r| r.action.contains(&pred))
+    }
+
+    pub fn all_rules_mut(&mut self) -> impl Iterator<Item = &mut Rule> {
+        self.rules
+            .iter_mut()
+            .chain(self.layers.iter_mut().flat_map(|l| l.rules.iter_mut()))
+    }
+}
+
+impl Condition {
+    /// Whether this condition or any condition nested in it matches `pred`.
+    pub fn contains(&self, pred: &impl Fn(&Condition) -> bool) -> bool {
+        pred(self)
+            || match self {
+                Condition::All { conditions } | Condition::Any { conditions } => {
+                    conditions.iter().any(|c| c.contains(pred))
+                }
+                Condition::Not { condition } => condition.contains(pred),
+                _ => false,
+            }
+    }
+}
+
+impl Rule {
+    /// Number of taps a tap-based rule waits for, or None for non-tap rules.
+    pub fn tap_count(&self) -> Option<u32> {
+        match self.rule_type {
+            RuleType::Click => Some(1),
+            RuleType::DoubleClick => Some(2),
+            RuleType::MultiClick => self.count,
+            _ => None,
+        }
+    }
+
+    /// EXTERNAL trigger as (source, event); no event matches every event of the source.
+    pub fn external_trigger(&self) -> (&str, Option<&str>) {
+        match self.trigger.split_once(':') {
+            Some((source, event)) => (source.trim(), Some(event.trim())),
+            None => (self.trigger.trim(), None),
+        }
+    }
+}
+
+impl Action {
+    /// Whether this action or any step of it, if a macro, matches `pred`.
+    pub fn contains(&self, pred: &impl Fn(&Action) -> bool) -> bool {
+        pred(self)
+            || match self {
+                Action::Macro { actions } => actions.iter().any(|a| a.contains(pred)),
+                _ => false,
+            }
+    }
+
+    /// Layer name targeted by a layer_* action.
+    pub fn layer_target(&self) -> Option<&str> {
+        match self {
+            Action::LayerMomentary { layer }
+            | Action::LayerToggle { layer }
+            | Action::LayerOneShot { layer } => Some(layer),
+            _ => None,
+        }
+    }
+
+    /// Variable changed by a *_variable action.
+    pub fn variable_name(&self) -> Option<&str> {
+        match self {
+            Action::SetVariable { name, .. }
+            | Action::IncrementVariable { name, .. }
+            | Action::ToggleVariable { name } => Some(name),
+            _ => None,
+        }
+    }
+}
+
 impl Default for GlobalSettings {
     fn default() -> Self {
         Self {
@@ -208,10 +848,45 @@ impl Default for GlobalSettings {
             short_press_threshold_ms: default_short_press_threshold(),
             double_tap_interval_ms: default_double_tap_interval(),
             combination_timeout_ms: default_combination_timeout(),
+            sequence_gap_ms: default_sequence_gap(),
             enable_haptic: default_true(),
             enable_wakelock: default_true(),
             log_level: default_log_level(),
             rule_timeout_ms: default_rule_timeout(),
+            foreground_app: ForegroundAppSettings::default(),
+            screen_state: ScreenStateSettings::default(),
+            variables_path: None,
+            power_supply: PowerSupplySettings::default(),
+            switch_devices: Vec::new(),
+        }
+    }
+}
+
+impl Default for PowerSupplySettings {
+    fn default() -> Self {
+        Self {
+            root: default_power_supply_root(),
+            poll_ms: default_power_poll(),
+        }
+    }
+}
+
+impl Default for ScreenStateSettings {
+    fn default() -> Self {
+        Self {
+            source: ScreenStateSource::default(),
+            path: None,
+            poll_ms: default_screen_poll(),
+        }
+    }
+}
+
+impl Default for ForegroundAppSettings {
+    fn default() -> Self {
+        Self {
+            source: ForegroundAppSource::default(),
+            path: None,
+            poll_ms: default_foreground_poll(),
         }
     }
 }
@@ -1,5 +1,10 @@
-use super::{Config, RuleType};
+use super::{
+    parse_switch_event, parse_time_of_day, resolve_key, switch_code, Action, BatteryEvent,
+    CompareOp, Condition, Config, ForegroundAppSource, RepeatPolicy, Rule, RuleType,
+    ScreenStateSource, VarValue, BATTERY_SOURCE, SWITCH_SOURCE,
+};
 use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
 
 impl Config {
@@ -20,26 +25,64 @@ impl Config {
         if self.settings.combination_timeout_ms == 0 {
             bail!("settings.combination_timeout_ms must be > 0");
         }
+        if self.settings.sequence_gap_ms == 0 {
+            bail!("settings.sequence_gap_ms must be > 0");
+        }
+        let foreground_app = &self.settings.foreground_app;
+        if foreground_app.poll_ms == 0 {
+            bail!("settings.foreground_app.poll_ms must be > 0");
+        }
+        if foreground_app.source == ForegroundAppSource::File
+            && foreground_app
+                .path
+                .as_deref()
+                .is_none_or(|p| p.trim().is_empty())
+        {
+            bail!("settings.foreground_app source 'file' requires a path");
+        }
+        let screen_state = &self.settings.screen_state;
+        if screen_state.poll_ms == 0 {
+            bail!("settings.screen_state.poll_ms must be > 0");
+        }
+        if screen_state.source != ScreenStateSource::Dumpsys
+            && screen_state
+                .path
+                .as_deref()
+                .is_none_or(|p| p.trim().is_empty())
+        {
+            bail!("settings.screen_state source 'backlight' / 'file' requires a path");
+        }
+        let power_supply = &self.settings.power_supply;
+        if power_supply.poll_ms == 0 {
+            bail!("settings.power_supply.poll_ms must be > 0");
+        }
+        if power_supply.root.trim().is_empty() {
+            bail!("settings.power_supply.root cannot be empty");
+        }
+        if self
+            .settings
+            .switch_devices
+            .iter()
+            .any(|d| d.trim().is_empty())
+        {
+            bail!("settings.switch_devices cannot contain empty device names");
+        }
 
         let mut seen_ids = HashSet::new();
-        let name_to_code: HashMap<&str, u16> = self
-            .hardware_map
-            .iter()
-            .map(|(code, name)| (name.as_str(), *code))
-            .collect();
+        let name_to_code = self.name_to_code();
+        let resolve_token = |token: &str| resolve_key(token, &name_to_code);
 
-        let resolve_token = |token: &str| -> Option<u16> {
-            let t = token.trim();
-            if t.is_empty() {
-                return None;
+        let mut layer_names = HashSet::new();
+        for layer in &self.layers {
+            if layer.name.trim().is_empty() {
+                bail!("Layer name cannot be empty");
             }
-            if let Ok(code) = t.parse::<u16>() {
-                return Some(code);
+            if !layer_names.insert(layer.name.as_str()) {
+                bail!("Duplicate layer name: {}", layer.name);
             }
-            name_to_code.get(t).copied()
-        };
+        }
 
-        for rule in &self.rules {
+        for rule in self.all_rules() {
             if rule.id.trim().is_empty() {
                 bail!("Rule id cannot be empty");
             }
@@ -50,7 +93,263 @@ impl Config {
                 bail!("Duplicate rule ID: {}", rule.id);
             }
 
+            if rule.sequence_fallback.is_some() && rule.rule_type != RuleType::Sequence {
+                bail!(
+                    "Rule '{}' sequence_fallback is only valid for SEQUENCE rules",
+                    rule.id
+                );
+            }
+
+            let timing = &rule.timing;
+            for (name, value) in [
+                ("long_press_threshold_ms", timing.long_press_threshold_ms),
+                ("short_press_threshold_ms", timing.short_press_threshold_ms),
+                ("double_tap_interval_ms", timing.double_tap_interval_ms),
+                ("combination_timeout_ms", timing.combination_timeout_ms),
+                ("sequence_gap_ms", timing.sequence_gap_ms),
+            ] {
+                if value == Some(0) {
+                    bail!("Rule '{}' timing.{} must be > 0", rule.id, name);
+                }
+            }
+            let short_ms = timing
+                .short_press_threshold_ms
+                .unwrap_or(self.settings.short_press_threshold_ms);
+            let long_ms = timing
+                .long_press_threshold_ms
+                .unwrap_or(self.settings.long_press_threshold_ms);
+            if long_ms < short_ms {
+                bail!(
+                    "Rule '{}' effective long_press_threshold_ms must be >= short_press_threshold_ms",
+                    rule.id
+                );
+            }
+
+            if let Some(repeat) = rule.repeat.as_ref() {
+                if !matches!(rule.rule_type, RuleType::ShortPress | RuleType::LongPress) {
+                    bail!(
+                        "Rule '{}' repeat is only valid for SHORT_PRESS / LONG_PRESS rules",
+                        rule.id
+                    );
+                }
+                if repeat.interval_ms == 0 || repeat.acceleration_ms.contains(&0) {
+                    bail!("Rule '{}' repeat intervals must be > 0", rule.id);
+                }
+            }
+
+            if rule.on_release {
+                if !matches!(rule.rule_type, RuleType::ShortPress | RuleType::LongPress) {
+                    bail!(
+                        "Rule '{}' on_release is only valid for SHORT_PRESS / LONG_PRESS rules",
+                        rule.id
+                    );
+                }
+                if rule.repeat.is_some() {
+                    bail!(
+                        "Rule '{}' repeat cannot be combined with on_release (the key is already up)",
+                        rule.id
+                    );
+                }
+                if matches!(rule.action, Action::LayerMomentary { .. }) {
+                    bail!(
+                        "Rule '{}' layer_momentary cannot fire on release (use layer_toggle)",
+                        rule.id
+                    );
+                }
+            }
+
+            if rule.cooldown_ms == Some(0) || rule.max_per_minute == Some(0) {
+                bail!(
+                    "Rule '{}' cooldown_ms / max_per_minute must be > 0",
+                    rule.id
+                );
+            }
+
+            for condition in &rule.conditions {
+                validate_condition(&rule.id, condition)?;
+            }
+
+            if (rule.ordered || rule.modifier_hold_ms.is_some()) && !rule.rule_type.is_combo() {
+                bail!(
+                    "Rule '{}' ordered / modifier_hold_ms are only valid for COMBO_* rules",
+                    rule.id
+                );
+            }
+            if let Some(ms) = rule.modifier_hold_ms {
+                if !rule.ordered {
+                    bail!(
+                        "Rule '{}' modifier_hold_ms requires ordered: true (the first key is the modifier)",
+                        rule.id
+                    );
+                }
+                if ms == 0 {
+                    bail!("Rule '{}' modifier_hold_ms must be > 0", rule.id);
+                }
+            }
+
+            if rule.repeat_policy == Some(RepeatPolicy::Trigger) {
+                if !matches!(
+                    rule.rule_type,
+                    RuleType::ShortPress
+                        | RuleType::LongPress
+                        | RuleType::ComboShortPress
+                        | RuleType::ComboLongPress
+                ) || rule.on_release
+                {
+                    bail!(
+                        "Rule '{}' repeat_policy trigger needs a hold rule type (SHORT_PRESS / LONG_PRESS / COMBO_*_PRESS) that fires while held",
+                        rule.id
+                    );
+                }
+                if rule.repeat.is_some() {
+                    bail!(
+                        "Rule '{}' repeat_policy trigger cannot be combined with repeat",
+                        rule.id
+                    );
+                }
+                if rule.action.layer_target().is_some() {
+                    bail!(
+                        "Rule '{}' repeat_policy trigger cannot be used with layer actions",
+                        rule.id
+                    );
+                }
+            }
+
+            match (rule.rule_type, rule.tap_hold.as_ref()) {
+                (RuleType::TapHold, Some(tap_hold)) => {
+                    if tap_hold.hold_key > 767 {
+                        bail!(
+                            "Rule '{}' tap_hold.hold_key is out of range (0..=767)",
+                            rule.id
+                        );
+                    }
+                    if tap_hold.tapping_term_ms == Some(0) {
+                        bail!("Rule '{}' tap_hold.tapping_term_ms must be > 0", rule.id);
+                    }
+                }
+                (RuleType::TapHold, None) => {
+                    bail!("Rule '{}' TAP_HOLD requires a tap_hold section", rule.id);
+                }
+                (_, Some(_)) => {
+                    bail!(
+                        "Rule '{}' tap_hold is only valid for TAP_HOLD rules",
+                        rule.id
+                    );
+                }
+                (_, None) => {}
+            }
+
+            if rule.passthrough && rule.rule_type == RuleType::TapHold {
+                bail!(
+                    "Rule '{}' passthrough is not valid for TAP_HOLD rules (tap and hold are both handled)",
+                    rule.id
+                );
+            }
+            if !rule.payload.is_empty() && rule.rule_type != RuleType::External {
+                bail!(
+                    "Rule '{}' payload is only valid for EXTERNAL rules",
+                    rule.id
+                );
+            }
+            if rule.passthrough && rule.rule_type == RuleType::External {
+                bail!(
+                    "Rule '{}' passthrough is not valid for EXTERNAL rules (no key is involved)",
+                    rule.id
+                );
+            }
+            if rule.passthrough && rule.rule_type == RuleType::Pattern {
+                bail!(
+                    "Rule '{}' passthrough is not valid for PATTERN rules",
+                    rule.id
+                );
+            }
+
+            if let Some(layer) = rule.action.layer_target() {
+                if !layer_names.contains(layer) {
+                    bail!("Rule '{}' references unknown layer '{}'", rule.id, layer);
+                }
+            }
+            if matches!(rule.action, Action::LayerMomentary { .. })
+                && !matches!(
+                    rule.rule_type,
+                    RuleType::ShortPress
+                        | RuleType::LongPress
+                        | RuleType::ComboShortPress
+                        | RuleType::ComboLongPress
+                )
+            {
+                bail!(
+                    "Rule '{}' layer_momentary needs a hold rule type (SHORT_PRESS / LONG_PRESS / COMBO_*_PRESS)",
+                    rule.id
+                );
+            }
+            let actions = match &rule.action {
+                Action::Macro { actions } => actions.as_slice(),
+                action => std::slice::from_ref(action),
+            };
+            if actions
+                .iter()
+                .any(|a| a.variable_name().is_some_and(|n| n.trim().is_empty()))
+            {
+                bail!("Rule '{}' variable actions need a name", rule.id);
+            }
+            if let Action::Macro { actions } = &rule.action {
+                if actions.iter().any(|a| a.layer_target().is_some()) {
+                    bail!(
+                        "Rule '{}' layer actions cannot be used inside a macro",
+                        rule.id
+                    );
+                }
+            }
+
+            match (rule.rule_type, rule.count) {
+                (RuleType::MultiClick, Some(count)) if count >= 2 => {}
+                (RuleType::MultiClick, _) => {
+                    bail!("Rule '{}' MULTI_CLICK requires count >= 2", rule.id);
+                }
+                (_, Some(_)) => {
+                    bail!(
+                        "Rule '{}' count is only valid for MULTI_CLICK rules",
+                        rule.id
+                    );
+                }
+                (_, None) => {}
+            }
+
+            match (rule.rule_type, rule.pattern.as_deref()) {
+                (RuleType::Pattern, Some(pattern))
+                    if !pattern.is_empty() && pattern.chars().all(|c| c == '.' || c == '-') => {}
+                (RuleType::Pattern, _) => {
+                    bail!(
+                        "Rule '{}' PATTERN requires a pattern of '.' (short) and '-' (long) presses",
+                        rule.id
+                    );
+                }
+                (_, Some(_)) => {
+                    bail!("Rule '{}' pattern is only valid for PATTERN rules", rule.id);
+                }
+                (_, None) => {}
+            }
+
             match rule.rule_type {
+                RuleType::Sequence => {
+                    if rule.trigger.contains('+') {
+                        bail!("Rule '{}' sequence type cannot use '+' trigger", rule.id);
+                    }
+
+                    let steps: Vec<&str> = rule.trigger.split("->").map(|s| s.trim()).collect();
+                    if steps.len() < 2 {
+                        bail!(
+                            "Rule '{}' sequence type requires at least 2 steps separated by '->'",
+                            rule.id
+                        );
+                    }
+                    for step in &steps {
+                        if resolve_token(step).is_none() {
+                            bail!("Rule '{}' has unknown sequence token '{}'", rule.id, step);
+                        }
+                    }
+                }
                 RuleType::ComboClick | RuleType::ComboShortPress | RuleType::ComboLongPress => {
                     if rule.trigger.contains("->") {
                         bail!("Rule '{}' combo type cannot contain '->'", rule.id);
@@ -63,22 +362,48 @@ impl Config {
                         .filter(|s| !s.is_empty())
                         .collect();
 
-                    if parts.len() != 2 {
+                    if parts.len() < 2 {
                         bail!(
-                            "Rule '{}' combo type requires exactly 2 keys separated by '+'",
+                            "Rule '{}' combo type requires at least 2 keys separated by '+'",
                             rule.id
                         );
                     }
 
-                    let left = resolve_token(parts[0]).ok_or_else(|| {
-                        anyhow!("Rule '{}' has unknown combo token '{}'", rule.id, parts[0])
-                    })?;
-                    let right = resolve_token(parts[1]).ok_or_else(|| {
-                        anyhow!("Rule '{}' has unknown combo token '{}'", rule.id, parts[1])
-                    })?;
-
-                    if left == right {
-                        bail!("Rule '{}' combo trigger cannot use identical keys", rule.id);
+                    let mut combo_codes = HashSet::new();
+                    for part in &parts {
+                        let code = resolve_token(part).ok_or_else(|| {
+                            anyhow!("Rule '{}' has unknown combo token '{}'", rule.id, part)
+                        })?;
+                        if !combo_codes.insert(code) {
+                            bail!("Rule '{}' combo trigger cannot use identical keys", rule.id);
+                        }
+                    }
+                }
+                RuleType::External => {
+                    let (source, event) = rule.external_trigger();
+                    if source.is_empty() || event.is_some_and(str::is_empty) {
+                        bail!(
+                            "Rule '{}' EXTERNAL trigger must be \"SOURCE\" or \"SOURCE:EVENT\"",
+                            rule.id
+                        );
+                    }
+                    if source == SWITCH_SOURCE {
+                        if let Some(event) = event.filter(|e| parse_switch_event(e).is_none()) {
+                            bail!(
+                                "Rule '{}' unknown SWITCH event '{}' (expected NAME_ON or NAME_OFF, e.g. LID_ON)",
+                                rule.id,
+                                event
+                            );
+                        }
+                    }
+                    if source == BATTERY_SOURCE {
+                        if let Some(event) = event.filter(|e| BatteryEvent::parse(e).is_none()) {
+                            bail!(
+                                "Rule '{}' unknown BATTERY event '{}' (expected BELOW_n, ABOVE_n, CHARGING or DISCHARGING, n = 0..100)",
+                                rule.id,
+                                event
+                            );
+                        }
                     }
                 }
                 _ => {
@@ -98,6 +423,561 @@ impl Config {
                 }
             }
         }
+
+        for key in self.key_repeat_policy.keys() {
+            if resolve_token(key).is_none() {
+                bail!("key_repeat_policy has unknown key token '{}'", key);
+            }
+        }
+
+        check_rule_set("rules", &self.rules, &self.remap, &resolve_token)?;
+        for layer in &self.layers {
+            check_rule_set(
+                &format!("layer '{}'", layer.name),
+                &layer.rules,
+                &layer.remap,
+                &resolve_token,
+            )?;
+        }
         Ok(())
     }
 }
+
+/// Checks that only make sense within one rule set (base or a single layer).
+fn check_rule_set(
+    scope: &str,
+    rules: &[Rule],
+    remap: &HashMap<String, u16>,
+    resolve_token: &dyn Fn(&str) -> Option<u16>,
+) -> Result<()> {
+    // EXTERNAL triggers name event sources, not keys
+    let rules: Vec<&Rule> = rules
+        .iter()
+        .filter(|r| r.rule_type != RuleType::External)
+        .collect();
+
+    // A dual-role key owns every event of its key, so it cannot share it with other rules.
+    for rule in rules.iter().filter(|r| r.rule_type == RuleType::TapHold) {
+        let code = resolve_token(&rule.trigger);
+        let shared = rules.iter().find(|other| {
+            other.id != rule.id
+                && other
+                    .trigger
+                    .split('+')
+                    .flat_map(|part| part.split("->"))
+                    .any(|token| resolve_token(token) == code)
+        });
+        if let Some(other) = shared {
+            bail!(
+                "Rule '{}' is TAP_HOLD, its key cannot also be used by rule '{}'",
+                rule.id,
+                other.id
+            );
+        }
+    }
+
+    // Every press of a PATTERN key is part of a pattern, so only combos may share it.
+    for rule in rules.iter().filter(|r| r.rule_type == RuleType::Pattern) {
+        let code = resolve_token(&rule.trigger);
+        let shared = rules.iter().find(|other| {
+            other.rule_type != RuleType::Pattern
+                && !other.rule_type.is_combo()
+                && other
+                    .trigger
+                    .split("->")
+                    .any(|token| resolve_token(token) == code)
+        });
+        if let Some(other) = shared {
+            bail!(
+                "Rule '{}' is PATTERN, its key can only be shared with PATTERN and combo rules, not '{}'",
+                rule.id,
+                other.id
+            );
+        }
+    }
+
+    let mut remapped = HashSet::new();
+    for (source, target) in remap {
+        let code = resolve_token(source)
+            .ok_or_else(|| anyhow!("{} remap has unknown source token '{}'", scope, source))?;
+        if *target > 767 {
+            bail!(
+                "{} remap target {} for '{}' is out of range (0..=767)",
+                scope,
+                target,
+                source
+            );
+        }
+        if !remapped.insert(code) {
+            bail!(
+                "{} remap source '{}' is mapped more than once",
+                scope,
+                source
+            );
+        }
+    }
+
+    for rule in rules {
+        let conflict = rule
+            .trigger
+            .split('+')
+            .flat_map(|part| part.split("->"))
+            .filter_map(resolve_token)
+            .find(|code| remapped.contains(code));
+        if let Some(code) = conflict {
+            bail!(
+                "Rule '{}' uses key {} which is already remapped; remapped keys cannot trigger rules",
+                rule.id,
+                code
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Kind of a rule-conflict warning reported by `Config::analyze`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// Two rules of the same type on the same trigger both fire
+    DuplicateTrigger,
+    /// A press rule on the same trigger always fires before a longer one
+    ShadowedPress,
+    /// A multi-tap rule delays the single tap rule on the same key
+    TapLatency,
+    /// A sequence starting with the key delays its tap rules
+    SequenceLatency,
+    /// A combo shares a key with a single-key rule
+    ComboOverlap,
+}
+
+/// A config that is valid but probably does not behave as intended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigWarning {
+    pub kind: WarningKind,
+    pub rule_ids: Vec<String>,
+    pub message: String,
+}
+
+impl Config {
+    /// Report ambiguous or shadowed rules. Each rule set (base rules, every layer) is
+    /// analyzed on its own; the config is expected to pass `validate` first.
+    pub fn analyze(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+        self.analyze_rule_set(&self.rules, &mut warnings);
+        for layer in &self.layers {
+            self.analyze_rule_set(&layer.rules, &mut warnings);
+        }
+        warnings
+    }
+
+    fn analyze_rule_set(&self, rules: &[Rule], warnings: &mut Vec<ConfigWarning>) {
+        let rules: Vec<(&Rule, Vec<u16>)> = rules
+            .iter()
+            .filter(|r| r.enabled && r.rule_type != RuleType::External)
+            .map(|r| (r, self.trigger_codes(r)))
+            .collect();
+        let mut warn = |kind, a: &Rule, b: &Rule, message: String| {
+            warnings.push(ConfigWarning {
+                kind,
+                rule_ids: vec![a.id.clone(), b.id.clone()],
+                message,
+            })
+        };
+
+        for (i, (a, a_keys)) in rules.iter().enumerate() {
+            for (b, b_keys) in &rules[i + 1..] {
+                // Sequences and ordered combos are told apart by key order
+                let same_keys = if a.rule_type == RuleType::Sequence || (a.ordered && b.ordered) {
+                    a_keys == b_keys
+                } else {
+                    a_keys.len() == b_keys.len() && a_keys.iter().all(|k| b_keys.contains(k))
+                };
+
+                if a.rule_type == b.rule_type
+                    && same_keys
+                    && a.count == b.count
+                    && a.pattern == b.pattern
+                    && a.on_release == b.on_release
+                    && a.conditions == b.conditions
+                {
+                    warn(
+                        WarningKind::DuplicateTrigger,
+                        a,
+                        b,
+                        format!(
+                            "'{}' and '{}' are both {:?} on {}; both actions fire together",
+                            a.id,
+                            b.id,
+                            a.rule_type,
+                            self.describe_keys(a_keys)
+                        ),
+                    );
+                }
+
+                if same_keys {
+                    if let Some((short, long)) = self.press_pair(a, b) {
+                        let (short_ms, long_ms) = (self.press_ms(short), self.press_ms(long));
+                        warn(
+                            WarningKind::ShadowedPress,
+                            a,
+                            b,
+                            format!(
+                                "'{}' fires after {} ms of holding {}, so it always fires on the way to '{}' at {} ms",
+                                short.id,
+                                short_ms,
+                                self.describe_keys(a_keys),
+                                long.id,
+                                long_ms
+                            ),
+                        );
+                    }
+                }
+
+                if a_keys.len() == 1 && a_keys == b_keys {
+                    if let Some((single, multi)) = tap_pair(a, b) {
+                        let interval = multi
+                            .timing
+                            .double_tap_interval_ms
+                            .unwrap_or(self.settings.double_tap_interval_ms);
+                        warn(
+                            WarningKind::TapLatency,
+                            a,
+                            b,
+                            format!(
+                                "'{}' waits up to {} ms after each tap of {} in case '{}' ({} taps) follows",
+                                single.id,
+                                interval,
+                                self.describe_keys(a_keys),
+                                multi.id,
+                                multi.tap_count().unwrap_or(0)
+                            ),
+                        );
+                    }
+                }
+
+                for ((seq, seq_keys), (tap, tap_keys)) in
+                    [((a, a_keys), (b, b_keys)), ((b, b_keys), (a, a_keys))]
+                {
+                    if seq.rule_type == RuleType::Sequence
+                        && tap.tap_count().is_some_and(|n| n > 0)
+                        && tap_keys.len() == 1
+                        && seq_keys.first() == tap_keys.first()
+                    {
+                        let gap = seq
+                            .timing
+                            .sequence_gap_ms
+                            .unwrap_or(self.settings.sequence_gap_ms);
+                        warn(
+                            WarningKind::SequenceLatency,
+                            a,
+                            b,
+                            format!(
+                                "'{}' is delayed up to {} ms after a tap of {} while sequence '{}' may continue",
+                                tap.id,
+                                gap,
+                                self.describe_keys(tap_keys),
+                                seq.id
+                            ),
+                        );
+                    }
+                }
+
+                for ((combo, combo_keys), (single, single_keys)) in
+                    [((a, a_keys), (b, b_keys)), ((b, b_keys), (a, a_keys))]
+                {
+                    if combo.rule_type.is_combo()
+                        && single_keys.len() == 1
+                        && single.rule_type != RuleType::Sequence
+                        && combo_keys.contains(&single_keys[0])
+                    {
+                        warn(
+                            WarningKind::ComboOverlap,
+                            a,
+                            b,
+                            format!(
+                                "'{}' uses {}, which is also part of combo '{}'; it is suppressed while the combo is pressed and may fire first if {} is pressed well before the rest",
+                                single.id,
+                                self.describe_keys(single_keys),
+                                combo.id,
+                                self.describe_keys(single_keys)
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn trigger_codes(&self, rule: &Rule) -> Vec<u16> {
+        let tokens: Vec<&str> = match rule.rule_type {
+            RuleType::Sequence => rule.trigger.split("->").collect(),
+            _ => rule.trigger.split('+').collect(),
+        };
+        let name_to_code = self.name_to_code();
+        tokens
+            .into_iter()
+            .filter_map(|token| resolve_key(token, &name_to_code))
+            .collect()
+    }
+
+    fn describe_keys(&self, keys: &[u16]) -> String {
+        keys.iter()
+            .map(|code| match self.hardware_map.get(code) {
+                Some(name) => name.clone(),
+                None => code.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// (shorter, longer) when both rules are held-press rules on the same trigger.
+    fn press_pair<'a>(&self, a: &'a Rule, b: &'a Rule) -> Option<(&'a Rule, &'a Rule)> {
+        let is_press = |r: &Rule| {
+            matches!(
+                r.rule_type,
+                RuleType::ShortPress
+                    | RuleType::LongPress
+                    | RuleType::ComboShortPress
+                    | RuleType::ComboLongPress
+            )
+        };
+        if !is_press(a) || !is_press(b) || a.rule_type.is_combo() != b.rule_type.is_combo() {
+            return None;
+        }
+        let pair = match self.press_ms(a).cmp(&self.press_ms(b)) {
+            std::cmp::Ordering::Less => (a, b),
+            std::cmp::Ordering::Greater => (b, a),
+            // Same threshold on the same type is reported as a duplicate
+            std::cmp::Ordering::Equal if a.rule_type == b.rule_type => return None,
+            std::cmp::Ordering::Equal => (a, b),
+        };
+        // A release-timed shorter rule only fires when the key comes up before the longer one
+        (!pair.0.on_release).then_some(pair)
+    }
+
+    fn press_ms(&self, rule: &Rule) -> u32 {
+        match rule.rule_type {
+            RuleType::LongPress | RuleType::ComboLongPress => rule
+                .timing
+                .long_press_threshold_ms
+                .unwrap_or(self.settings.long_press_threshold_ms),
+            _ => rule
+                .timing
+                .short_press_threshold_ms
+                .unwrap_or(self.settings.short_press_threshold_ms),
+        }
+    }
+}
+
+/// (single tap, multi tap) when one rule is a CLICK and the other waits for more taps.
+fn tap_pair<'a>(a: &'a Rule, b: &'a Rule) -> Option<(&'a Rule, &'a Rule)> {
+    match (a.tap_count(), b.tap_count()) {
+        (Some(1), Some(n)) if n > 1 => Some((a, b)),
+        (Some(n), Some(1)) if n > 1 => Some((b, a)),
+        _ => None,
+    }
+}
+
+fn validate_condition(rule_id: &str, condition: &Condition) -> Result<()> {
+    match condition {
+        Condition::TimeRange { start, end } => {
+            let (Some(from), Some(to)) = (parse_time_of_day(start), parse_time_of_day(end)) else {
+                bail!(
+                    "Rule '{}' time_range needs start / end as HH:MM, got '{}' - '{}'",
+                    rule_id,
+                    start,
+                    end
+                );
+            };
+            if from == to {
+                bail!("Rule '{}' time_range start and end must differ", rule_id);
+            }
+        }
+        Condition::Weekdays { days } => {
+            if days.is_empty() {
+                bail!(
+                    "Rule '{}' weekdays condition needs at least one day",
+                    rule_id
+                );
+            }
+        }
+        Condition::ForegroundApp { packages, .. } => {
+            if packages.is_empty() || packages.iter().any(|p| p.trim().is_empty()) {
+                bail!(
+                    "Rule '{}' foreground_app condition needs non-empty package names",
+                    rule_id
+                );
+            }
+        }
+        Condition::ScreenState { .. } => {}
+        Condition::Variable { name, op, value } => {
+            if name.trim().is_empty() {
+                bail!("Rule '{}' variable condition needs a name", rule_id);
+            }
+            let ordering = !matches!(op, CompareOp::Equals | CompareOp::NotEquals);
+            if ordering && !matches!(value, VarValue::Int(_)) {
+                bail!(
+                    "Rule '{}' variable condition '{}' compares order, which needs an int value",
+                    rule_id,
+                    name
+                );
+            }
+        }
+        Condition::BatteryLevel { value, .. } => {
+            if *value > 100 {
+                bail!(
+                    "Rule '{}' battery_level value must be a percentage (0-100), got {}",
+                    rule_id,
+                    value
+                );
+            }
+        }
+        Condition::Charging { .. } => {}
+        Condition::Switch { switch, .. } => {
+            if switch_code(switch).is_none() {
+                bail!(
+                    "Rule '{}' unknown switch '{}' (expected an EV_SW name without SW_, e.g. LID)",
+                    rule_id,
+                    switch
+                );
+            }
+        }
+        Condition::All { conditions } | Condition::Any { conditions } => {
+            if conditions.is_empty() {
+                bail!(
+                    "Rule '{}' all / any condition group needs at least one condition",
+                    rule_id
+                );
+            }
+            for nested in conditions {
+                validate_condition(rule_id, nested)?;
+            }
+        }
+        Condition::Not { condition } => validate_condition(rule_id, condition)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Action, Rule, RuleTiming};
+
+    fn rule(id: &str, trigger: &str, rule_type: RuleType) -> Rule {
+        Rule {
+            id: id.to_string(),
+            trigger: trigger.to_string(),
+            rule_type,
+            action: Action::SendKey { key_code: 1 },
+            enabled: true,
+            description: String::new(),
+            sequence_fallback: None,
+            count: None,
+            timing: RuleTiming::default(),
+            repeat: None,
+            tap_hold: None,
+            passthrough: false,
+            on_release: false,
+            repeat_policy: None,
+            ordered: false,
+            modifier_hold_ms: None,
+            cooldown_ms: None,
+            max_per_minute: None,
+            pattern: None,
+            conditions: Vec::new(),
+            payload: HashMap::new(),
+        }
+    }
+
+    fn analyze(rules: Vec<Rule>) -> Vec<(WarningKind, Vec<String>)> {
+        let mut cfg = Config::default();
+        cfg.hardware_map.insert(115, "VOL_UP".to_string());
+        cfg.rules = rules;
+        cfg.validate().expect("config should be valid");
+        cfg.analyze()
+            .into_iter()
+            .map(|w| (w.kind, w.rule_ids))
+            .collect()
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn analyze_should_report_duplicates_and_shadowed_presses() {
+        let warnings = analyze(vec![
+            rule("a", "VOL_UP", RuleType::Click),
+            rule("b", "115", RuleType::Click),
+            rule("short", "115", RuleType::ShortPress),
+            rule("long", "115", RuleType::LongPress),
+        ]);
+        assert_eq!(
+            warnings,
+            vec![
+                (WarningKind::DuplicateTrigger, ids(&["a", "b"])),
+                (WarningKind::ShadowedPress, ids(&["short", "long"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn analyze_should_not_report_release_timed_short_press_as_shadowed() {
+        let mut short = rule("short", "115", RuleType::ShortPress);
+        short.on_release = true;
+        let warnings = analyze(vec![short, rule("long", "115", RuleType::LongPress)]);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn analyze_should_tell_ordered_combos_apart_by_key_order() {
+        let mut power_first = rule("power_first", "116+115", RuleType::ComboClick);
+        let mut vol_first = rule("vol_first", "115+116", RuleType::ComboClick);
+        power_first.ordered = true;
+        vol_first.ordered = true;
+        assert!(analyze(vec![power_first.clone(), vol_first]).is_empty());
+
+        let unordered = rule("unordered", "115+116", RuleType::ComboClick);
+        assert_eq!(
+            analyze(vec![power_first, unordered]),
+            vec![(
+                WarningKind::DuplicateTrigger,
+                ids(&["power_first", "unordered"])
+            )]
+        );
+    }
+
+    #[test]
+    fn analyze_should_report_tap_latency_and_combo_overlap() {
+        let warnings = analyze(vec![
+            rule("click", "115", RuleType::Click),
+            rule("double", "115", RuleType::DoubleClick),
+            rule("combo", "115+114", RuleType::ComboClick),
+        ]);
+        assert_eq!(
+            warnings,
+            vec![
+                (WarningKind::TapLatency, ids(&["click", "double"])),
+                (WarningKind::ComboOverlap, ids(&["click", "combo"])),
+                (WarningKind::ComboOverlap, ids(&["double", "combo"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn analyze_should_ignore_disabled_and_unrelated_rules() {
+        let mut disabled = rule("off", "115", RuleType::Click);
+        disabled.enabled = false;
+        let warnings = analyze(vec![
+            rule("up", "115", RuleType::Click),
+            disabled,
+            rule("down", "114", RuleType::Click),
+            rule("seq", "114->116", RuleType::Sequence),
+        ]);
+        assert_eq!(
+            warnings,
+            vec![(WarningKind::SequenceLatency, ids(&["down", "seq"]))]
+        );
+    }
+}
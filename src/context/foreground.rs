@@ -0,0 +1,110 @@
+use super::{poll_state, read_state_file, StateCache, StateProvider};
+use crate::config::{Condition, Config, ForegroundAppSettings, ForegroundAppSource};
+use crate::utils::command::command_output;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Last polled foreground package.
+pub type ForegroundAppCache = StateCache<String>;
+
+/// Android: the resumed activity in `dumpsys activity activities`.
+pub struct DumpsysProvider;
+
+impl StateProvider<String> for DumpsysProvider {
+    fn read(&self) -> BoxFuture<'_, Result<Option<String>>> {
+        Box::pin(async {
+            let args = vec!["activity".to_string(), "activities".to_string()];
+            let dump = command_output("dumpsys", &args).await?;
+            Ok(parse_resumed_package(&dump))
+        })
+    }
+}
+
+/// Stand-in that reads the package name from a file.
+pub struct FileProvider {
+    pub path: PathBuf,
+}
+
+impl StateProvider<String> for FileProvider {
+    fn read(&self) -> BoxFuture<'_, Result<Option<String>>> {
+        Box::pin(read_state_file(&self.path))
+    }
+}
+
+pub fn provider_for(settings: &ForegroundAppSettings) -> Option<Box<dyn StateProvider<String>>> {
+    match settings.source {
+        ForegroundAppSource::Dumpsys => Some(Box::new(DumpsysProvider)),
+        ForegroundAppSource::File => settings.path.as_ref().map(|path| {
+            Box::new(FileProvider {
+                path: PathBuf::from(path),
+            }) as Box<dyn StateProvider<String>>
+        }),
+    }
+}
+
+/// Package of the resumed activity, e.g. `com.example` from
+/// `topResumedActivity=ActivityRecord{5e3c1d u0 com.example/.MainActivity t8}`.
+pub fn parse_resumed_package(dump: &str) -> Option<String> {
+    dump.lines()
+        .map(str::trim_start)
+        .filter(|line| {
+            line.starts_with("topResumedActivity")
+                || line.starts_with("mResumedActivity")
+                || line.starts_with("ResumedActivity")
+        })
+        .find_map(|line| {
+            line.split_whitespace()
+                .find_map(|token| token.split_once('/').map(|(package, _)| package))
+                .filter(|package| !package.is_empty())
+        })
+        .map(str::to_string)
+}
+
+/// Keep `cache` fresh while any rule has a foreground_app condition.
+pub async fn poll_foreground_app(config: Arc<RwLock<Config>>, cache: Arc<ForegroundAppCache>) {
+    poll_state(config, cache, |cfg| {
+        let settings = &cfg.settings.foreground_app;
+        let needed = cfg.uses_condition(|c| matches!(c, Condition::ForegroundApp { .. }));
+        (provider_for(settings).filter(|_| needed), settings.poll_ms)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_resumed_package_should_handle_old_and_new_dumpsys_formats() {
+        let new = "ACTIVITY MANAGER ACTIVITIES (dumpsys activity activities)\n  Task display areas in top down Z order:\n    * Task{8b1 #12 type=standard A=10123:org.koreader.launcher}\n  topResumedActivity=ActivityRecord{5e3c1d u0 org.koreader.launcher/.MainActivity t12}\n";
+        assert_eq!(
+            parse_resumed_package(new),
+            Some("org.koreader.launcher".to_string())
+        );
+
+        let old = "  Stack #0:\n    mResumedActivity: ActivityRecord{41a u0 com.android.launcher3/.Launcher t1}\n";
+        assert_eq!(
+            parse_resumed_package(old),
+            Some("com.android.launcher3".to_string())
+        );
+
+        assert_eq!(parse_resumed_package("  mFocusedApp=null\n"), None);
+    }
+
+    #[tokio::test]
+    async fn file_provider_should_read_a_trimmed_package_or_none() {
+        let path = std::env::temp_dir().join(format!("foreground_app_{}", std::process::id()));
+        let provider = FileProvider { path: path.clone() };
+        assert_eq!(provider.read().await.unwrap(), None);
+
+        std::fs::write(&path, "com.example.reader\n").unwrap();
+        assert_eq!(
+            provider.read().await.unwrap(),
+            Some("com.example.reader".to_string())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+pub mod foreground;
+pub mod power;
+pub mod screen;
+pub mod switches;
+pub mod variables;
+
+use crate::config::Config;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use parking_lot::RwLock as StdRwLock;
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Source of one piece of system state; `None` when it is unknown.
+pub trait StateProvider<T>: Send + Sync {
+    fn read(&self) -> BoxFuture<'_, Result<Option<T>>>;
+}
+
+/// Last polled value of a provider; read by rule conditions without querying the system.
+pub struct StateCache<T> {
+    name: &'static str,
+    value: StdRwLock<Option<T>>,
+}
+
+impl<T: Clone + PartialEq + Debug> StateCache<T> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: StdRwLock::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<T> {
+        self.value.read().clone()
+    }
+
+    pub fn set(&self, value: Option<T>) {
+        let mut current = self.value.write();
+        if *current != value {
+            debug!("{}: {:?}", self.name, value);
+            *current = value;
+        }
+    }
+}
+
+/// Keep `cache` fresh with the provider `select` picks from the current config;
+/// `select` returns no provider while no rule needs the value. The config is
+/// re-read every round, so reloads apply.
+pub async fn poll_state<T, F>(config: Arc<RwLock<Config>>, cache: Arc<StateCache<T>>, select: F)
+where
+    T: Clone + PartialEq + Debug,
+    F: Fn(&Config) -> (Option<Box<dyn StateProvider<T>>>, u32),
+{
+    poll_state_with(config, cache, select, |_, _, _| {}).await
+}
+
+/// `poll_state` that also calls `on_change` with the previous and the new value
+/// (already cached) whenever the value changes.
+pub async fn poll_state_with<T, F, C>(
+    config: Arc<RwLock<Config>>,
+    cache: Arc<StateCache<T>>,
+    select: F,
+    mut on_change: C,
+) where
+    T: Clone + PartialEq + Debug,
+    F: Fn(&Config) -> (Option<Box<dyn StateProvider<T>>>, u32),
+    C: FnMut(&Config, Option<&T>, Option<&T>),
+{
+    let mut failing = false;
+    loop {
+        let (provider, poll_ms) = select(&*config.read().await);
+        if let Some(provider) = provider {
+            let value = match provider.read().await {
+                Ok(value) => {
+                    failing = false;
+                    value
+                }
+                Err(e) => {
+                    if !failing {
+                        warn!("{} query failed: {}", cache.name, e);
+                    }
+                    failing = true;
+                    None
+                }
+            };
+            let previous = cache.get();
+            if previous != value {
+                cache.set(value.clone());
+                on_change(&*config.read().await, previous.as_ref(), value.as_ref());
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(poll_ms.max(1) as u64)).await;
+    }
+}
+
+/// Trimmed content of a small state file; a missing or empty file means none.
+pub async fn read_state_file(path: &Path) -> Result<Option<String>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(content) => Ok(Some(content.trim().to_string()).filter(|s| !s.is_empty())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,220 @@
+use super::{poll_state_with, read_state_file, StateCache, StateProvider};
+use crate::config::{BatteryEvent, Condition, Config, Rule, RuleType, BATTERY_SOURCE};
+use crate::event::external::ExternalEvent;
+use anyhow::{bail, Result};
+use futures::future::BoxFuture;
+use log::warn;
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+
+/// Battery reading of the power supply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerState {
+    /// Capacity in percent
+    pub level: u8,
+    /// `status` is Charging, or Full (on power)
+    pub charging: bool,
+}
+
+/// Last polled battery state.
+pub type PowerStateCache = StateCache<PowerState>;
+
+/// Sysfs power-supply class: `capacity` and `status` of the first supply with a capacity,
+/// supplies whose `type` is Battery first and the rest by name.
+pub struct SysfsProvider {
+    pub root: PathBuf,
+}
+
+impl StateProvider<PowerState> for SysfsProvider {
+    fn read(&self) -> BoxFuture<'_, Result<Option<PowerState>>> {
+        Box::pin(async {
+            let mut entries = tokio::fs::read_dir(&self.root).await?;
+            let mut supplies = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                let supply = entry.path();
+                // Chargers and USB supplies may report a capacity too
+                let battery =
+                    read_state_file(&supply.join("type")).await?.as_deref() == Some("Battery");
+                supplies.push((!battery, supply));
+            }
+            supplies.sort();
+
+            for (_, supply) in supplies {
+                let Some(capacity) = read_state_file(&supply.join("capacity")).await? else {
+                    continue;
+                };
+                let Ok(level) = capacity.parse::<u8>() else {
+                    bail!("unexpected capacity '{}' in {:?}", capacity, supply);
+                };
+                let status = read_state_file(&supply.join("status")).await?;
+                return Ok(Some(PowerState {
+                    level: level.min(100),
+                    charging: matches!(status.as_deref(), Some("Charging") | Some("Full")),
+                }));
+            }
+            Ok(None)
+        })
+    }
+}
+
+/// BATTERY events for a change from `previous` to `current`: CHARGING / DISCHARGING when
+/// charging flips, and each BELOW_n / ABOVE_n of an enabled EXTERNAL rule that was crossed.
+pub fn battery_events(
+    cfg: &Config,
+    previous: &PowerState,
+    current: &PowerState,
+) -> Vec<ExternalEvent> {
+    let mut names = BTreeSet::new();
+    if previous.charging != current.charging {
+        names.insert(if current.charging {
+            "CHARGING"
+        } else {
+            "DISCHARGING"
+        });
+    }
+    for rule in battery_rules(cfg) {
+        let Some(name) = rule.external_trigger().1 else {
+            continue;
+        };
+        let crossed = match BatteryEvent::parse(name) {
+            Some(BatteryEvent::Below(n)) => previous.level >= n && current.level < n,
+            Some(BatteryEvent::Above(n)) => previous.level <= n && current.level > n,
+            _ => false,
+        };
+        if crossed {
+            names.insert(name);
+        }
+    }
+
+    let payload = HashMap::from([
+        ("level".to_string(), current.level.to_string()),
+        ("charging".to_string(), current.charging.to_string()),
+    ]);
+    names
+        .into_iter()
+        .map(|name| ExternalEvent {
+            source: BATTERY_SOURCE.to_string(),
+            event: name.to_string(),
+            payload: payload.clone(),
+        })
+        .collect()
+}
+
+fn battery_rules(cfg: &Config) -> impl Iterator<Item = &Rule> {
+    cfg.all_rules().filter(|r| {
+        r.enabled && r.rule_type == RuleType::External && r.external_trigger().0 == BATTERY_SOURCE
+    })
+}
+
+/// Keep `cache` fresh while any rule has a battery condition or BATTERY trigger, and
+/// post the BATTERY events of each change to `events`.
+pub async fn poll_power_supply(
+    config: Arc<RwLock<Config>>,
+    cache: Arc<PowerStateCache>,
+    events: mpsc::Sender<ExternalEvent>,
+) {
+    let select = |cfg: &Config| {
+        let settings = &cfg.settings.power_supply;
+        let needed = battery_rules(cfg).next().is_some()
+            || cfg.uses_condition(|c| {
+                matches!(
+                    c,
+                    Condition::BatteryLevel { .. } | Condition::Charging { .. }
+                )
+            });
+        let provider = SysfsProvider {
+            root: PathBuf::from(&settings.root),
+        };
+        let provider = needed.then(|| Box::new(provider) as Box<dyn StateProvider<PowerState>>);
+        (provider, settings.poll_ms)
+    };
+    poll_state_with(config, cache, select, |cfg, previous, current| {
+        let (Some(previous), Some(current)) = (previous, current) else {
+            return;
+        };
+        for event in battery_events(cfg, previous, current) {
+            if let Err(e) = events.try_send(event) {
+                warn!("Battery event dropped: {}", e);
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn power(level: u8, charging: bool) -> PowerState {
+        PowerState { level, charging }
+    }
+
+    #[tokio::test]
+    async fn sysfs_provider_should_read_the_first_supply_with_a_capacity() {
+        let root = std::env::temp_dir().join(format!("power_supply_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("AC")).unwrap();
+        std::fs::create_dir_all(root.join("battery")).unwrap();
+        std::fs::write(root.join("AC/online"), "1\n").unwrap();
+        let provider = SysfsProvider { root: root.clone() };
+        assert_eq!(provider.read().await.unwrap(), None);
+
+        std::fs::write(root.join("battery/capacity"), "42\n").unwrap();
+        std::fs::write(root.join("battery/status"), "Discharging\n").unwrap();
+        assert_eq!(provider.read().await.unwrap(), Some(power(42, false)));
+        std::fs::write(root.join("battery/status"), "Full\n").unwrap();
+        assert_eq!(provider.read().await.unwrap(), Some(power(42, true)));
+
+        std::fs::write(root.join("battery/capacity"), "unknown\n").unwrap();
+        assert!(provider.read().await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sysfs_provider_should_prefer_the_supply_typed_battery() {
+        let root = std::env::temp_dir().join(format!("power_supply_type_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("axp_usb")).unwrap();
+        std::fs::create_dir_all(root.join("bms")).unwrap();
+        std::fs::write(root.join("axp_usb/capacity"), "80\n").unwrap();
+        std::fs::write(root.join("axp_usb/type"), "USB\n").unwrap();
+        std::fs::write(root.join("bms/capacity"), "55\n").unwrap();
+        std::fs::write(root.join("bms/status"), "Charging\n").unwrap();
+        std::fs::write(root.join("bms/type"), "Battery\n").unwrap();
+        let provider = SysfsProvider { root: root.clone() };
+        assert_eq!(provider.read().await.unwrap(), Some(power(55, true)));
+
+        // Without a Battery type the first supply by name wins
+        std::fs::remove_file(root.join("bms/type")).unwrap();
+        assert_eq!(provider.read().await.unwrap(), Some(power(80, false)));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn battery_events_should_report_crossed_thresholds_and_charging_changes() {
+        let yaml = "device_name: gpio-keys\nrules:\n  - id: low\n    trigger: \"BATTERY:BELOW_15\"\n    rule_type: EXTERNAL\n    action:\n      type: send_key\n      key_code: 30\n  - id: full\n    trigger: \"BATTERY:ABOVE_80\"\n    rule_type: EXTERNAL\n    action:\n      type: send_key\n      key_code: 31\n";
+        let cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let names = |previous, current| {
+            battery_events(&cfg, &previous, &current)
+                .into_iter()
+                .map(|e| e.event)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(power(15, false), power(14, false)), vec!["BELOW_15"]);
+        assert!(names(power(14, false), power(13, false)).is_empty());
+        assert!(names(power(14, false), power(15, false)).is_empty());
+        assert_eq!(
+            names(power(80, false), power(81, true)),
+            vec!["ABOVE_80", "CHARGING"]
+        );
+        assert_eq!(
+            names(power(90, true), power(90, false)),
+            vec!["DISCHARGING"]
+        );
+
+        let events = battery_events(&cfg, &power(20, false), &power(10, false));
+        assert_eq!(events[0].source, BATTERY_SOURCE);
+        assert_eq!(events[0].payload["level"], "10");
+    }
+}
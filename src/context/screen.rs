@@ -0,0 +1,151 @@
+use super::{poll_state, read_state_file, StateCache, StateProvider};
+use crate::config::{
+    Action, Condition, Config, ScreenState, ScreenStateSettings, ScreenStateSource,
+};
+use crate::utils::command::command_output;
+use anyhow::{bail, Result};
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Last polled screen state.
+pub type ScreenStateCache = StateCache<ScreenState>;
+
+/// Android: wakefulness in `dumpsys power`.
+pub struct DumpsysProvider;
+
+impl StateProvider<ScreenState> for DumpsysProvider {
+    fn read(&self) -> BoxFuture<'_, Result<Option<ScreenState>>> {
+        Box::pin(async {
+            let dump = command_output("dumpsys", &["power".to_string()]).await?;
+            Ok(parse_power_dump(&dump))
+        })
+    }
+}
+
+/// Sysfs backlight brightness node; the screen is on while it is non-zero.
+pub struct BacklightProvider {
+    pub path: PathBuf,
+}
+
+impl StateProvider<ScreenState> for BacklightProvider {
+    fn read(&self) -> BoxFuture<'_, Result<Option<ScreenState>>> {
+        Box::pin(async {
+            let Some(value) = read_state_file(&self.path).await? else {
+                return Ok(None);
+            };
+            match value.parse::<u32>() {
+                Ok(0) => Ok(Some(ScreenState::Off)),
+                Ok(_) => Ok(Some(ScreenState::On)),
+                Err(_) => bail!("unexpected brightness '{}' in {:?}", value, self.path),
+            }
+        })
+    }
+}
+
+/// Stand-in that reads "on" / "off" from a file.
+pub struct FileProvider {
+    pub path: PathBuf,
+}
+
+impl StateProvider<ScreenState> for FileProvider {
+    fn read(&self) -> BoxFuture<'_, Result<Option<ScreenState>>> {
+        Box::pin(async {
+            let value = read_state_file(&self.path).await?;
+            match value.as_deref().map(str::to_ascii_lowercase).as_deref() {
+                None => Ok(None),
+                Some("on") => Ok(Some(ScreenState::On)),
+                Some("off") => Ok(Some(ScreenState::Off)),
+                Some(other) => bail!("unexpected screen state '{}' in {:?}", other, self.path),
+            }
+        })
+    }
+}
+
+pub fn provider_for(settings: &ScreenStateSettings) -> Option<Box<dyn StateProvider<ScreenState>>> {
+    let path = settings.path.as_ref().map(PathBuf::from);
+    match settings.source {
+        ScreenStateSource::Dumpsys => Some(Box::new(DumpsysProvider)),
+        ScreenStateSource::Backlight => {
+            path.map(|path| Box::new(BacklightProvider { path }) as Box<dyn StateProvider<_>>)
+        }
+        ScreenStateSource::File => {
+            path.map(|path| Box::new(FileProvider { path }) as Box<dyn StateProvider<_>>)
+        }
+    }
+}
+
+/// Screen state from `dumpsys power`: `mWakefulness=Awake` (or `Dreaming`) is on,
+/// `Asleep` / `Dozing` is off; older dumps only have `Display Power: state=ON`.
+pub fn parse_power_dump(dump: &str) -> Option<ScreenState> {
+    let field = |key: &str| {
+        dump.lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .map(str::trim)
+    };
+    match field("mWakefulness=") {
+        Some("Awake") | Some("Dreaming") => Some(ScreenState::On),
+        Some("Asleep") | Some("Dozing") => Some(ScreenState::Off),
+        _ => match field("Display Power: state=") {
+            Some("ON") => Some(ScreenState::On),
+            Some("OFF") | Some("DOZE") => Some(ScreenState::Off),
+            _ => None,
+        },
+    }
+}
+
+/// Keep `cache` fresh while any rule has a screen_state condition or a screen action.
+pub async fn poll_screen_state(config: Arc<RwLock<Config>>, cache: Arc<ScreenStateCache>) {
+    poll_state(config, cache, |cfg| {
+        let settings = &cfg.settings.screen_state;
+        let needed = cfg.uses_condition(|c| matches!(c, Condition::ScreenState { .. }))
+            || cfg.uses_action(|a| {
+                matches!(
+                    a,
+                    Action::ToggleScreen | Action::ScreenOn | Action::ScreenOff
+                )
+            });
+        (provider_for(settings).filter(|_| needed), settings.poll_ms)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_power_dump_should_read_wakefulness_or_display_power() {
+        let awake = "POWER MANAGER (dumpsys power)\n\nPower Manager State:\n  mDirty=0x0\n  mWakefulness=Awake\n  mWakefulnessChanging=false\n";
+        assert_eq!(parse_power_dump(awake), Some(ScreenState::On));
+        assert_eq!(
+            parse_power_dump("  mWakefulness=Dozing\n"),
+            Some(ScreenState::Off)
+        );
+        assert_eq!(
+            parse_power_dump("Display Power: state=OFF\n"),
+            Some(ScreenState::Off)
+        );
+        assert_eq!(parse_power_dump("mDirty=0x0\n"), None);
+    }
+
+    #[tokio::test]
+    async fn backlight_and_file_providers_should_read_the_state() {
+        let path = std::env::temp_dir().join(format!("screen_state_{}", std::process::id()));
+        let backlight = BacklightProvider { path: path.clone() };
+        let file = FileProvider { path: path.clone() };
+        assert_eq!(backlight.read().await.unwrap(), None);
+
+        std::fs::write(&path, "0\n").unwrap();
+        assert_eq!(backlight.read().await.unwrap(), Some(ScreenState::Off));
+        std::fs::write(&path, "1023\n").unwrap();
+        assert_eq!(backlight.read().await.unwrap(), Some(ScreenState::On));
+
+        std::fs::write(&path, "off").unwrap();
+        assert_eq!(file.read().await.unwrap(), Some(ScreenState::Off));
+        std::fs::write(&path, "dim").unwrap();
+        assert!(file.read().await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use crate::config::{switch_name, SWITCH_SOURCE};
+use crate::event::external::ExternalEvent;
+use log::debug;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+
+/// Last known state of each EV_SW switch by code; true while set (lid shut, jack
+/// inserted, ...). Updated from the input devices as switch events arrive.
+#[derive(Default)]
+pub struct SwitchStates {
+    states: RwLock<HashMap<u16, bool>>,
+}
+
+impl SwitchStates {
+    pub fn get(&self, code: u16) -> Option<bool> {
+        self.states.read().get(&code).copied()
+    }
+
+    /// Record the state a device reports when opened; fires nothing.
+    pub fn seed(&self, code: u16, on: bool) {
+        self.states.write().insert(code, on);
+    }
+
+    /// Record a switch event; the SWITCH event for the EXTERNAL rules when the state changed.
+    pub fn update(&self, code: u16, on: bool) -> Option<ExternalEvent> {
+        if self.states.write().insert(code, on) == Some(on) {
+            return None;
+        }
+        debug!("Switch {}: {}", code, if on { "on" } else { "off" });
+        switch_event(code, on)
+    }
+}
+
+/// SWITCH:NAME_ON / NAME_OFF, with the switch name and state as payload.
+pub fn switch_event(code: u16, on: bool) -> Option<ExternalEvent> {
+    let name = switch_name(code)?;
+    let state = if on { "on" } else { "off" };
+    Some(ExternalEvent {
+        source: SWITCH_SOURCE.to_string(),
+        event: format!("{}_{}", name, state.to_ascii_uppercase()),
+        payload: HashMap::from([
+            ("switch".to_string(), name.to_string()),
+            ("state".to_string(), state.to_string()),
+        ]),
+    })
+}
+
+/// Seed `states` with the current switches of an opened device.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn seed_switches(device: &evdev::Device, states: &SwitchStates) {
+    let Some(supported) = device.supported_switches() else {
+        return;
+    };
+    match device.get_switch_state() {
+        Ok(set) => {
+            for switch in supported.iter() {
+                states.seed(switch.0, set.contains(switch));
+            }
+        }
+        Err(e) => log::warn!("Failed to read switch state: {}", e),
+    }
+}
+
+/// Read the EV_SW switches of another device (not grabbed, so the system still sees
+/// them) and post their SWITCH events to `events`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub async fn watch_switches(
+    path: std::path::PathBuf,
+    states: std::sync::Arc<SwitchStates>,
+    events: tokio::sync::mpsc::Sender<ExternalEvent>,
+) {
+    use evdev::{Device, InputEventKind};
+    use futures::stream::StreamExt;
+    use log::warn;
+
+    let device = match Device::open(&path) {
+        Ok(device) => device,
+        Err(e) => {
+            warn!("Failed to open switch device {:?}: {}", path, e);
+            return;
+        }
+    };
+    seed_switches(&device, &states);
+    let mut stream = match device.into_event_stream() {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to read switch device {:?}: {}", path, e);
+            return;
+        }
+    };
+    log::info!("Reading switches of {:?}", path);
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(event) => {
+                if let InputEventKind::Switch(switch) = event.kind() {
+                    if let Some(event) = states.update(switch.0, event.value() != 0) {
+                        if let Err(e) = events.try_send(event) {
+                            warn!("Switch event dropped: {}", e);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Error reading switch device {:?}: {}", path, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_switch_event;
+
+    #[test]
+    fn update_should_report_changes_as_switch_events() {
+        let states = SwitchStates::default();
+        assert_eq!(states.get(0), None);
+        states.seed(0, false);
+        assert_eq!(states.get(0), Some(false));
+
+        let closed = states.update(0, true).expect("lid closed");
+        assert_eq!(closed.source, SWITCH_SOURCE);
+        assert_eq!(closed.event, "LID_ON");
+        assert_eq!(closed.payload["state"], "on");
+        assert_eq!(parse_switch_event(&closed.event), Some((0, true)));
+        assert!(states.update(0, true).is_none());
+
+        let unplugged = states.update(2, false).expect("first report");
+        assert_eq!(unplugged.event, "HEADPHONE_INSERT_OFF");
+        assert_eq!(parse_switch_event("CAMERA_LENS_COVER_ON"), Some((9, true)));
+        assert_eq!(parse_switch_event("SW_LID_ON"), None);
+        assert!(states.update(99, true).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::VarValue;
+use anyhow::Result;
+use log::{debug, info, warn};
+use parking_lot::RwLock as StdRwLock;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Named runtime variables set by rule actions and read by rule conditions.
+/// Kept apart from the config, and only written to `path` when one is given.
+#[derive(Default)]
+pub struct VariableStore {
+    values: StdRwLock<BTreeMap<String, VarValue>>,
+    path: Option<PathBuf>,
+}
+
+impl VariableStore {
+    /// Store backed by `path`, starting from the variables saved there.
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let values = path
+            .as_deref()
+            .map(|p| match load(p) {
+                Ok(values) => {
+                    info!("Loaded {} variable(s) from {:?}", values.len(), p);
+                    values
+                }
+                Err(e) => {
+                    warn!("Failed to load variables from {:?}: {}", p, e);
+                    BTreeMap::new()
+                }
+            })
+            .unwrap_or_default();
+        Self {
+            values: StdRwLock::new(values),
+            path,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<VarValue> {
+        self.values.read().get(name).cloned()
+    }
+
+    pub fn set(&self, name: &str, value: VarValue) {
+        self.update(name, |_| Some(value));
+    }
+
+    pub fn increment(&self, name: &str, by: i64) {
+        self.update(name, |current| match current {
+            None => Some(VarValue::Int(by)),
+            Some(VarValue::Int(n)) => Some(VarValue::Int(n.saturating_add(by))),
+            Some(other) => {
+                warn!(
+                    "Variable '{}' is {:?}, not an int; not incremented",
+                    name, other
+                );
+                None
+            }
+        });
+    }
+
+    pub fn toggle(&self, name: &str) {
+        self.update(name, |current| match current {
+            None => Some(VarValue::Bool(true)),
+            Some(VarValue::Bool(b)) => Some(VarValue::Bool(!b)),
+            Some(other) => {
+                warn!(
+                    "Variable '{}' is {:?}, not a bool; not toggled",
+                    name, other
+                );
+                None
+            }
+        });
+    }
+
+    /// Apply `change` to the current value; `None` leaves it as is.
+    fn update(&self, name: &str, change: impl FnOnce(Option<&VarValue>) -> Option<VarValue>) {
+        let snapshot = {
+            let mut values = self.values.write();
+            let Some(value) = change(values.get(name)) else {
+                return;
+            };
+            debug!("Variable '{}' = {:?}", name, value);
+            if values.get(name) == Some(&value) {
+                return;
+            }
+            values.insert(name.to_string(), value);
+            values.clone()
+        };
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, &snapshot) {
+                warn!("Failed to save variables to {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<BTreeMap<String, VarValue>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) if content.trim().is_empty() => Ok(BTreeMap::new()),
+        Ok(content) => Ok(serde_yaml::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write through a temp file so a crash never leaves a half-written store.
+fn save(path: &Path, values: &BTreeMap<String, VarValue>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_yaml::to_string(values)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_should_update_variables_and_reject_mismatched_types() {
+        let store = VariableStore::default();
+        store.toggle("driving");
+        store.increment("count", 2);
+        store.increment("count", -5);
+        store.set("profile", VarValue::Str("car".to_string()));
+        assert_eq!(store.get("driving"), Some(VarValue::Bool(true)));
+        assert_eq!(store.get("count"), Some(VarValue::Int(-3)));
+
+        store.toggle("driving");
+        store.toggle("count");
+        store.increment("profile", 1);
+        assert_eq!(store.get("driving"), Some(VarValue::Bool(false)));
+        assert_eq!(store.get("count"), Some(VarValue::Int(-3)));
+        assert_eq!(store.get("profile"), Some(VarValue::Str("car".to_string())));
+    }
+
+    #[test]
+    fn variables_should_persist_across_reopen_when_a_path_is_set() {
+        let path = std::env::temp_dir().join(format!("variables_{}.yaml", std::process::id()));
+        let store = VariableStore::open(Some(path.clone()));
+        store.set("driving", VarValue::Bool(true));
+        store.increment("trips", 1);
+        drop(store);
+
+        let reopened = VariableStore::open(Some(path.clone()));
+        assert_eq!(reopened.get("driving"), Some(VarValue::Bool(true)));
+        assert_eq!(reopened.get("trips"), Some(VarValue::Int(1)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,14 +1,21 @@
 use crate::config::{
-    Action, BrightnessDirection, BuiltinCommand, Config, IntentSpec, VolumeDirection,
+    Action, BrightnessDirection, BuiltinCommand, Config, IntentSpec, ScreenState, VolumeDirection,
 };
+use crate::context::screen::ScreenStateCache;
+use crate::event::conditions::SystemContext;
 use crate::hardware::uinput::UinputHandler;
+use crate::utils::command::{command_output, shell_output};
 use anyhow::Result;
 use log::{debug, error, info, warn};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::process::Command;
 use tokio::sync::{Mutex, RwLock};
 
+// Linux input codes; Android's Generic.kl maps them to KEYCODE_SLEEP / KEYCODE_WAKEUP.
+// (223 / 224 are the Android keycodes; on uinput 224 is KEY_BRIGHTNESSDOWN.)
+const KEY_SLEEP: u16 = 142;
+const KEY_WAKEUP: u16 = 143;
+
 pub struct ActionExecutor;
 
 impl ActionExecutor {
@@ -17,6 +24,7 @@ impl ActionExecutor {
         uinput: Arc<Mutex<UinputHandler>>,
         config: Arc<RwLock<Config>>,
         config_path: Option<PathBuf>,
+        context: Arc<SystemContext>,
     ) -> Result<()> {
         match action {
             Action::Macro { actions } => {
@@ -30,12 +38,13 @@ impl ActionExecutor {
                         uinput.clone(),
                         config.clone(),
                         config_path.clone(),
+                        context.clone(),
                     )
                     .await?;
                 }
                 Ok(())
             }
-            _ => Self::execute_non_macro(action, uinput, config, config_path).await,
+            _ => Self::execute_non_macro(action, uinput, config, config_path, context).await,
         }
     }
 
@@ -44,6 +53,7 @@ impl ActionExecutor {
         uinput: Arc<Mutex<UinputHandler>>,
         config: Arc<RwLock<Config>>,
         config_path: Option<PathBuf>,
+        context: Arc<SystemContext>,
     ) -> Result<()> {
         match action {
             Action::Shell { cmd } => {
@@ -99,6 +109,16 @@ impl ActionExecutor {
             Action::SendKey { key_code } => {
                 Self::send_click_key(&uinput, *key_code).await?;
             }
+            Action::KeyDown { key_code } => {
+                let mut device = uinput.lock().await;
+                device.send_key(*key_code, 1)?;
+                device.sync()?;
+            }
+            Action::KeyUp { key_code } => {
+                let mut device = uinput.lock().await;
+                device.send_key(*key_code, 0)?;
+                device.sync()?;
+            }
             Action::MultiTap { codes, interval_ms } => {
                 for code in codes {
                     Self::send_click_key(&uinput, *code).await?;
@@ -135,21 +155,26 @@ impl ActionExecutor {
                     warn!("Ignored launch_intent with empty intent payload");
                 }
             }
-            Action::ToggleScreen => {
-                // KEY_SLEEP
-                Self::send_click_key(&uinput, 223).await?;
+            Action::ToggleScreen | Action::ScreenOn | Action::ScreenOff => {
+                if let Some(key) = screen_key(action, &context.screen_state) {
+                    Self::send_click_key(&uinput, key).await?;
+                }
             }
             Action::ToggleRule { rule_id } => {
                 let save_needed = {
                     let mut cfg = config.write().await;
-                    if let Some(rule) = cfg.rules.iter_mut().find(|r| r.id == *rule_id) {
-                        rule.enabled = !rule.enabled;
-                        debug!("Rule '{}' toggled to enabled={}", rule_id, rule.enabled);
-                        true
-                    } else {
-                        warn!("ToggleRule target not found: {}", rule_id);
-                        false
-                    }
+                    let found = match cfg.all_rules_mut().find(|r| r.id == *rule_id) {
+                        Some(rule) => {
+                            rule.enabled = !rule.enabled;
+                            debug!("Rule '{}' toggled to enabled={}", rule_id, rule.enabled);
+                            true
+                        }
+                        None => {
+                            warn!("ToggleRule target not found: {}", rule_id);
+                            false
+                        }
+                    };
+                    found
                 }; // write lock released here
                 if save_needed {
                     if let Some(path) = config_path.as_ref() {
@@ -160,6 +185,9 @@ impl ActionExecutor {
                     }
                 }
             }
+            Action::SetVariable { name, value } => context.variables.set(name, value.clone()),
+            Action::IncrementVariable { name, by } => context.variables.increment(name, *by),
+            Action::ToggleVariable { name } => context.variables.toggle(name),
             Action::VolumeControl { direction } => {
                 let key_code = match direction {
                     VolumeDirection::Up => 115,
@@ -183,6 +211,12 @@ impl ActionExecutor {
             Action::Intercept => {
                 debug!("Intercept action: event consumed without side effects");
             }
+            Action::LayerMomentary { layer }
+            | Action::LayerToggle { layer }
+            | Action::LayerOneShot { layer } => {
+                // Layer switching is applied by the state machine before execution.
+                debug!("Layer action for '{}' handled by state machine", layer);
+            }
             Action::Macro { .. } => {
                 warn!("Unexpected macro branch in execute_non_macro");
             }
@@ -201,19 +235,8 @@ impl ActionExecutor {
 
     fn spawn_shell(cmd_str: String) {
         tokio::spawn(async move {
-            let output = Command::new("sh").arg("-c").arg(&cmd_str).output().await;
-
-            match output {
-                Ok(out) => {
-                    if !out.status.success() {
-                        error!(
-                            "Command '{}' failed: {}",
-                            cmd_str,
-                            String::from_utf8_lossy(&out.stderr)
-                        );
-                    }
-                }
-                Err(e) => error!("Failed to execute command '{}': {}", cmd_str, e),
+            if let Err(e) = shell_output(&cmd_str).await {
+                error!("Failed to execute command '{}': {}", cmd_str, e);
             }
         });
     }
@@ -221,24 +244,34 @@ impl ActionExecutor {
     fn spawn_process(program: &str, args: Vec<String>) {
         let program = program.to_string();
         tokio::spawn(async move {
-            let output = Command::new(&program).args(&args).output().await;
-            match output {
-                Ok(out) => {
-                    if !out.status.success() {
-                        error!(
-                            "Command '{} {:?}' failed: {}",
-                            program,
-                            args,
-                            String::from_utf8_lossy(&out.stderr)
-                        );
-                    }
-                }
-                Err(e) => error!("Failed to execute command '{} {:?}': {}", program, args, e),
+            if let Err(e) = command_output(&program, &args).await {
+                error!("{}", e);
             }
         });
     }
 }
 
+/// Key carrying out a screen action, or None when the cached state says it is already
+/// done. The cache takes the new state right away: the poller only catches up later,
+/// and a screen action following this one must not act on the state from before it.
+fn screen_key(action: &Action, screen_state: &ScreenStateCache) -> Option<u16> {
+    let current = screen_state.get();
+    let target = match action {
+        // KEY_WAKEUP when known to be off, KEY_SLEEP otherwise
+        Action::ToggleScreen if current == Some(ScreenState::Off) => ScreenState::On,
+        Action::ToggleScreen => ScreenState::Off,
+        // KEY_WAKEUP is a no-op on an awake device; skipped anyway when known to be on
+        Action::ScreenOn if current != Some(ScreenState::On) => ScreenState::On,
+        Action::ScreenOff if current != Some(ScreenState::Off) => ScreenState::Off,
+        _ => return None,
+    };
+    screen_state.set(Some(target));
+    Some(match target {
+        ScreenState::On => KEY_WAKEUP,
+        ScreenState::Off => KEY_SLEEP,
+    })
+}
+
 fn build_intent_args(intent: &IntentSpec) -> Option<Vec<String>> {
     let mut args = vec!["start".to_string()];
     let mut has_payload = false;
@@ -303,6 +336,21 @@ mod tests {
     use super::*;
     use std::collections::HashMap;
 
+    #[test]
+    fn screen_actions_should_follow_the_state_set_by_the_previous_one() {
+        let cache = ScreenStateCache::new("Screen state");
+        cache.set(Some(ScreenState::On));
+        assert_eq!(screen_key(&Action::ScreenOff, &cache), Some(KEY_SLEEP));
+        assert_eq!(screen_key(&Action::ScreenOff, &cache), None);
+        assert_eq!(screen_key(&Action::ScreenOn, &cache), Some(KEY_WAKEUP));
+        assert_eq!(cache.get(), Some(ScreenState::On));
+
+        let cache = ScreenStateCache::new("Screen state");
+        assert_eq!(screen_key(&Action::ToggleScreen, &cache), Some(KEY_SLEEP));
+        assert_eq!(screen_key(&Action::ToggleScreen, &cache), Some(KEY_WAKEUP));
+        assert_eq!(cache.get(), Some(ScreenState::On));
+    }
+
     #[test]
     fn build_intent_args_should_infer_types() {
         let mut extras = HashMap::new();
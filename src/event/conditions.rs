@@ -0,0 +1,433 @@
+use crate::config::{
+    parse_time_of_day, switch_code, AppMatch, CompareOp, Condition, ScreenState, VarValue, Weekday,
+};
+use crate::context::foreground::ForegroundAppCache;
+use crate::context::power::{PowerState, PowerStateCache};
+use crate::context::screen::ScreenStateCache;
+use crate::context::switches::SwitchStates;
+use crate::context::variables::VariableStore;
+use chrono::{Datelike, Local, NaiveDateTime, Timelike};
+use std::sync::Arc;
+
+/// Outside state that rule conditions are checked against.
+pub trait ConditionContext: Send + Sync {
+    /// Current local wall-clock time.
+    fn local_now(&self) -> NaiveDateTime;
+
+    /// Package of the foreground app, if known.
+    fn foreground_app(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the screen is on, if known.
+    fn screen_state(&self) -> Option<ScreenState> {
+        None
+    }
+
+    /// Current value of a context variable.
+    fn variable(&self, _name: &str) -> Option<VarValue> {
+        None
+    }
+
+    /// Battery level and charging state, if known.
+    fn power_state(&self) -> Option<PowerState> {
+        None
+    }
+
+    /// Whether an EV_SW switch is set, if a device has reported it.
+    fn switch_state(&self, _code: u16) -> Option<bool> {
+        None
+    }
+}
+
+/// The live system: local time from the OS clock and time zone, the rest from
+/// caches kept fresh by background pollers.
+pub struct SystemContext {
+    pub foreground_app: Arc<ForegroundAppCache>,
+    pub screen_state: Arc<ScreenStateCache>,
+    pub variables: Arc<VariableStore>,
+    pub power_supply: Arc<PowerStateCache>,
+    pub switches: Arc<SwitchStates>,
+}
+
+impl Default for SystemContext {
+    fn default() -> Self {
+        Self {
+            foreground_app: Arc::new(ForegroundAppCache::new("Foreground app")),
+            screen_state: Arc::new(ScreenStateCache::new("Screen state")),
+            variables: Arc::new(VariableStore::default()),
+            power_supply: Arc::new(PowerStateCache::new("Battery")),
+            switches: Arc::new(SwitchStates::default()),
+        }
+    }
+}
+
+impl ConditionContext for SystemContext {
+    fn local_now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn foreground_app(&self) -> Option<String> {
+        self.foreground_app.get()
+    }
+
+    fn screen_state(&self) -> Option<ScreenState> {
+        self.screen_state.get()
+    }
+
+    fn variable(&self, name: &str) -> Option<VarValue> {
+        self.variables.get(name)
+    }
+
+    fn power_state(&self) -> Option<PowerState> {
+        self.power_supply.get()
+    }
+
+    fn switch_state(&self, code: u16) -> Option<bool> {
+        self.switches.get(code)
+    }
+}
+
+/// Whether every condition holds; an empty list always does.
+pub fn conditions_hold(conditions: &[Condition], ctx: &dyn ConditionContext) -> bool {
+    conditions.iter().all(|c| condition_holds(c, ctx))
+}
+
+pub fn condition_holds(condition: &Condition, ctx: &dyn ConditionContext) -> bool {
+    match condition {
+        Condition::TimeRange { start, end } => {
+            let (Some(start), Some(end)) = (parse_time_of_day(start), parse_time_of_day(end))
+            else {
+                return false;
+            };
+            let now = ctx.local_now();
+            let minute = now.hour() * 60 + now.minute();
+            if start <= end {
+                (start..end).contains(&minute)
+            } else {
+                minute >= start || minute < end
+            }
+        }
+        Condition::Weekdays { days } => {
+            let today = match ctx.local_now().weekday() {
+                chrono::Weekday::Mon => Weekday::Mon,
+                chrono::Weekday::Tue => Weekday::Tue,
+                chrono::Weekday::Wed => Weekday::Wed,
+                chrono::Weekday::Thu => Weekday::Thu,
+                chrono::Weekday::Fri => Weekday::Fri,
+                chrono::Weekday::Sat => Weekday::Sat,
+                chrono::Weekday::Sun => Weekday::Sun,
+            };
+            days.contains(&today)
+        }
+        Condition::ForegroundApp { packages, mode } => {
+            // An unknown foreground app matches no package
+            let listed = ctx
+                .foreground_app()
+                .is_some_and(|current| packages.contains(&current));
+            match mode {
+                AppMatch::Allow => listed,
+                AppMatch::Deny => !listed,
+            }
+        }
+        // An unknown screen state matches neither
+        Condition::ScreenState { state } => ctx.screen_state() == Some(*state),
+        Condition::Variable { name, op, value } => {
+            let current = ctx.variable(name);
+            match (op, current, value) {
+                (CompareOp::Equals, current, value) => current.as_ref() == Some(value),
+                (CompareOp::NotEquals, current, value) => current.as_ref() != Some(value),
+                (op, Some(VarValue::Int(current)), VarValue::Int(value)) => {
+                    op.compare(current, *value)
+                }
+                _ => false,
+            }
+        }
+        Condition::BatteryLevel { op, value } => ctx
+            .power_state()
+            .is_some_and(|power| op.compare(power.level, *value)),
+        // An unknown charging state matches neither
+        Condition::Charging { charging } => ctx
+            .power_state()
+            .is_some_and(|power| power.charging == *charging),
+        // A switch no device has reported matches neither
+        Condition::Switch { switch, on } => {
+            switch_code(switch).and_then(|code| ctx.switch_state(code)) == Some(*on)
+        }
+        Condition::All { conditions } => conditions_hold(conditions, ctx),
+        Condition::Any { conditions } => conditions.iter().any(|c| condition_holds(c, ctx)),
+        Condition::Not { condition } => !condition_holds(condition, ctx),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::collections::HashMap;
+
+    /// Fixed stand-in for the system context.
+    pub(crate) struct FixedContext {
+        pub now: NaiveDateTime,
+        pub foreground_app: Option<String>,
+        pub screen_state: Option<ScreenState>,
+        pub variables: HashMap<String, VarValue>,
+        pub power: Option<PowerState>,
+        pub switches: HashMap<u16, bool>,
+    }
+
+    impl ConditionContext for FixedContext {
+        fn local_now(&self) -> NaiveDateTime {
+            self.now
+        }
+
+        fn foreground_app(&self) -> Option<String> {
+            self.foreground_app.clone()
+        }
+
+        fn screen_state(&self) -> Option<ScreenState> {
+            self.screen_state
+        }
+
+        fn variable(&self, name: &str) -> Option<VarValue> {
+            self.variables.get(name).cloned()
+        }
+
+        fn power_state(&self) -> Option<PowerState> {
+            self.power
+        }
+
+        fn switch_state(&self, code: u16) -> Option<bool> {
+            self.switches.get(&code).copied()
+        }
+    }
+
+    /// 2026-10-16 is a Friday.
+    pub(crate) fn friday_at(hour: u32, minute: u32) -> FixedContext {
+        FixedContext {
+            now: NaiveDate::from_ymd_opt(2026, 10, 16)
+                .unwrap()
+                .and_hms_opt(hour, minute, 0)
+                .unwrap(),
+            foreground_app: None,
+            screen_state: None,
+            variables: HashMap::new(),
+            power: None,
+            switches: HashMap::new(),
+        }
+    }
+
+    fn time_range(start: &str, end: &str) -> Condition {
+        Condition::TimeRange {
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    #[test]
+    fn time_range_should_include_start_and_exclude_end() {
+        let range = time_range("09:00", "17:30");
+        assert!(!condition_holds(&range, &friday_at(8, 59)));
+        assert!(condition_holds(&range, &friday_at(9, 0)));
+        assert!(condition_holds(&range, &friday_at(17, 29)));
+        assert!(!condition_holds(&range, &friday_at(17, 30)));
+    }
+
+    #[test]
+    fn time_range_should_cross_midnight_when_end_is_before_start() {
+        let night = time_range("22:00", "06:00");
+        assert!(condition_holds(&night, &friday_at(23, 15)));
+        assert!(condition_holds(&night, &friday_at(0, 0)));
+        assert!(condition_holds(&night, &friday_at(5, 59)));
+        assert!(!condition_holds(&night, &friday_at(6, 0)));
+        assert!(!condition_holds(&night, &friday_at(12, 0)));
+    }
+
+    #[test]
+    fn weekdays_should_match_local_day_and_all_conditions_must_hold() {
+        let workdays = Condition::Weekdays {
+            days: vec![Weekday::Mon, Weekday::Fri],
+        };
+        let weekend = Condition::Weekdays {
+            days: vec![Weekday::Sat, Weekday::Sun],
+        };
+        let ctx = friday_at(10, 0);
+        assert!(condition_holds(&workdays, &ctx));
+        assert!(!condition_holds(&weekend, &ctx));
+
+        assert!(conditions_hold(&[], &ctx));
+        assert!(conditions_hold(
+            &[workdays.clone(), time_range("09:00", "17:00")],
+            &ctx
+        ));
+        assert!(!conditions_hold(
+            &[workdays, time_range("18:00", "20:00")],
+            &ctx
+        ));
+    }
+
+    #[test]
+    fn foreground_app_should_allow_or_deny_listed_packages() {
+        let reader = |mode| Condition::ForegroundApp {
+            packages: vec!["org.koreader.launcher".to_string()],
+            mode,
+        };
+        let mut ctx = friday_at(10, 0);
+        assert!(!condition_holds(&reader(AppMatch::Allow), &ctx));
+        assert!(condition_holds(&reader(AppMatch::Deny), &ctx));
+
+        ctx.foreground_app = Some("org.koreader.launcher".to_string());
+        assert!(condition_holds(&reader(AppMatch::Allow), &ctx));
+        assert!(!condition_holds(&reader(AppMatch::Deny), &ctx));
+
+        ctx.foreground_app = Some("com.android.launcher3".to_string());
+        assert!(!condition_holds(&reader(AppMatch::Allow), &ctx));
+        assert!(condition_holds(&reader(AppMatch::Deny), &ctx));
+    }
+
+    #[test]
+    fn screen_state_should_match_only_a_known_state() {
+        let screen_off = Condition::ScreenState {
+            state: ScreenState::Off,
+        };
+        let mut ctx = friday_at(10, 0);
+        assert!(!condition_holds(&screen_off, &ctx));
+        ctx.screen_state = Some(ScreenState::Off);
+        assert!(condition_holds(&screen_off, &ctx));
+        ctx.screen_state = Some(ScreenState::On);
+        assert!(!condition_holds(&screen_off, &ctx));
+    }
+
+    #[test]
+    fn battery_conditions_should_need_a_known_power_state() {
+        let low = Condition::BatteryLevel {
+            op: CompareOp::LessThan,
+            value: 15,
+        };
+        let charging = Condition::Charging { charging: true };
+        let discharging = Condition::Charging { charging: false };
+        let mut ctx = friday_at(10, 0);
+        assert!(!condition_holds(&low, &ctx));
+        assert!(!condition_holds(&charging, &ctx));
+        assert!(!condition_holds(&discharging, &ctx));
+
+        ctx.power = Some(PowerState {
+            level: 14,
+            charging: false,
+        });
+        assert!(condition_holds(&low, &ctx));
+        assert!(condition_holds(&discharging, &ctx));
+        ctx.power = Some(PowerState {
+            level: 15,
+            charging: true,
+        });
+        assert!(!condition_holds(&low, &ctx));
+        assert!(condition_holds(&charging, &ctx));
+    }
+
+    #[test]
+    fn switch_condition_should_match_a_reported_state() {
+        let lid_shut = Condition::Switch {
+            switch: "LID".to_string(),
+            on: true,
+        };
+        let lid_open = Condition::Switch {
+            switch: "LID".to_string(),
+            on: false,
+        };
+        let mut ctx = friday_at(10, 0);
+        assert!(!condition_holds(&lid_shut, &ctx));
+        assert!(!condition_holds(&lid_open, &ctx));
+
+        ctx.switches.insert(0, true);
+        assert!(condition_holds(&lid_shut, &ctx));
+        assert!(!condition_holds(&lid_open, &ctx));
+        ctx.switches.insert(0, false);
+        assert!(condition_holds(&lid_open, &ctx));
+    }
+
+    #[test]
+    fn all_any_not_groups_should_nest() {
+        let weekend = Condition::Weekdays {
+            days: vec![Weekday::Sat, Weekday::Sun],
+        };
+        // Weekends, or weekday evenings outside 19:00-19:30
+        let tree = Condition::Any {
+            conditions: vec![
+                weekend,
+                Condition::All {
+                    conditions: vec![
+                        time_range("18:00", "23:00"),
+                        Condition::Not {
+                            condition: Box::new(time_range("19:00", "19:30")),
+                        },
+                    ],
+                },
+            ],
+        };
+        assert!(!condition_holds(&tree, &friday_at(12, 0)));
+        assert!(condition_holds(&tree, &friday_at(18, 30)));
+        assert!(!condition_holds(&tree, &friday_at(19, 15)));
+
+        let mut saturday = friday_at(12, 0);
+        saturday.now += chrono::Duration::days(1);
+        assert!(condition_holds(&tree, &saturday));
+
+        assert!(condition_holds(
+            &Condition::All { conditions: vec![] },
+            &saturday
+        ));
+        assert!(!condition_holds(
+            &Condition::Any { conditions: vec![] },
+            &saturday
+        ));
+    }
+
+    #[test]
+    fn variable_should_compare_equality_and_int_ordering() {
+        let var = |op, value| Condition::Variable {
+            name: "speed".to_string(),
+            op,
+            value,
+        };
+        let mut ctx = friday_at(10, 0);
+        assert!(!condition_holds(
+            &var(CompareOp::Equals, VarValue::Int(0)),
+            &ctx
+        ));
+        assert!(condition_holds(
+            &var(CompareOp::NotEquals, VarValue::Int(0)),
+            &ctx
+        ));
+        assert!(!condition_holds(
+            &var(CompareOp::LessThan, VarValue::Int(10)),
+            &ctx
+        ));
+
+        ctx.variables.insert("speed".to_string(), VarValue::Int(3));
+        assert!(condition_holds(
+            &var(CompareOp::Equals, VarValue::Int(3)),
+            &ctx
+        ));
+        assert!(condition_holds(
+            &var(CompareOp::GreaterThan, VarValue::Int(2)),
+            &ctx
+        ));
+        assert!(condition_holds(
+            &var(CompareOp::GreaterOrEqual, VarValue::Int(3)),
+            &ctx
+        ));
+        assert!(!condition_holds(
+            &var(CompareOp::LessThan, VarValue::Int(3)),
+            &ctx
+        ));
+        assert!(condition_holds(
+            &var(CompareOp::LessOrEqual, VarValue::Int(3)),
+            &ctx
+        ));
+        assert!(!condition_holds(
+            &var(CompareOp::Equals, VarValue::Bool(true)),
+            &ctx
+        ));
+    }
+}
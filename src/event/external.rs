@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+
+/// A non-key event posted to `/api/trigger`, matched against EXTERNAL rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalEvent {
+    pub source: String,
+    pub event: String,
+    pub payload: HashMap<String, String>,
+}
+
+impl ExternalEvent {
+    /// Whether an EXTERNAL rule with this (source, event) trigger and `payload` filter
+    /// matches; no event matches every event of the source.
+    pub fn matches(
+        &self,
+        source: &str,
+        event: Option<&str>,
+        payload: &HashMap<String, String>,
+    ) -> bool {
+        self.source == source
+            && event.is_none_or(|e| e == self.event)
+            && payload
+                .iter()
+                .all(|(key, value)| self.payload.get(key) == Some(value))
+    }
+}
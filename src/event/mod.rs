@@ -1,5 +1,7 @@
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub mod action;
+pub mod conditions;
+pub mod external;
 pub mod processor;
 pub mod state_machine;
 
@@ -4,23 +4,43 @@ use anyhow::Result;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use log::debug;
 use log::{info, warn};
+use parking_lot::Mutex as StdMutex;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::Arc;
-use parking_lot::Mutex as StdMutex;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use std::time::Duration;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use tokio::sync::Mutex;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 
 use crate::config::Config;
+use crate::event::external::ExternalEvent;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::context::foreground::poll_foreground_app;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::context::power::poll_power_supply;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::context::screen::poll_screen_state;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::context::switches::{seed_switches, watch_switches};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::context::variables::VariableStore;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use crate::event::action::ActionExecutor;
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use crate::event::state_machine::StateMachine;
+use crate::event::conditions::SystemContext;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::event::state_machine::{RepeatOutcome, StateMachine};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use crate::hardware::uinput::UinputHandler;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::hardware::InputDeviceManager;
 use crate::webui::learn::LearnState;
+use crate::webui::status::RuleStats;
+
+const EXTERNAL_QUEUE: usize = 64;
 
 pub struct EventProcessor {
     config: Arc<RwLock<Config>>,
@@ -28,6 +48,10 @@ pub struct EventProcessor {
     device_path: PathBuf,
     debug_mode: bool,
     learn_state: Arc<StdMutex<LearnState>>,
+    rule_stats: Arc<StdMutex<RuleStats>>,
+    // Non-key events for the EXTERNAL rules (/api/trigger, battery changes)
+    external_tx: mpsc::Sender<ExternalEvent>,
+    external_rx: mpsc::Receiver<ExternalEvent>,
 }
 
 impl EventProcessor {
@@ -37,16 +61,26 @@ impl EventProcessor {
         device_path: PathBuf,
         debug: bool,
         learn_state: Arc<StdMutex<LearnState>>,
+        rule_stats: Arc<StdMutex<RuleStats>>,
     ) -> Result<Self> {
+        let (external_tx, external_rx) = mpsc::channel(EXTERNAL_QUEUE);
         Ok(Self {
             config,
             config_path,
             device_path,
             debug_mode: debug,
             learn_state,
+            rule_stats,
+            external_tx,
+            external_rx,
         })
     }
 
+    /// Sender for non-key events; they fire the matching EXTERNAL rules.
+    pub fn external_events(&self) -> mpsc::Sender<ExternalEvent> {
+        self.external_tx.clone()
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         info!("Starting Event Processor on {:?}", self.device_path);
 
@@ -57,23 +91,56 @@ impl EventProcessor {
 
             let uinput = Arc::new(Mutex::new(UinputHandler::new()?));
 
-            let (rules, settings, hw_map) = {
+            let (rules, settings, hw_map, remaps, layers, repeat_policies) = {
                 let cfg = self.config.read().await;
                 (
                     cfg.rules.clone(),
                     cfg.settings.clone(),
                     cfg.hardware_map.clone(),
+                    cfg.remap_table(),
+                    cfg.layers.clone(),
+                    cfg.key_repeat_policy_table(),
                 )
             };
 
             let mut state_machine = StateMachine::new(
                 rules,
-                hw_map,
+                hw_map.clone(),
                 settings.long_press_threshold_ms as u64,
                 settings.short_press_threshold_ms as u64,
                 settings.double_tap_interval_ms as u64,
                 settings.combination_timeout_ms as u64,
+                settings.sequence_gap_ms as u64,
             );
+            state_machine.update_layers(remaps, &layers, &hw_map);
+            state_machine.update_repeat_policies(repeat_policies);
+
+            // Rule conditions read cached system state, refreshed in the background
+            let variables_path = settings.variables_path.as_ref().map(PathBuf::from);
+            // Actions read the same caches, so screen actions never query inline
+            let context = Arc::new(SystemContext {
+                variables: Arc::new(VariableStore::open(variables_path)),
+                ..SystemContext::default()
+            });
+            let switches = context.switches.clone();
+            tokio::spawn(poll_foreground_app(
+                self.config.clone(),
+                context.foreground_app.clone(),
+            ));
+            tokio::spawn(poll_screen_state(
+                self.config.clone(),
+                context.screen_state.clone(),
+            ));
+            tokio::spawn(poll_power_supply(
+                self.config.clone(),
+                context.power_supply.clone(),
+                self.external_tx.clone(),
+            ));
+            state_machine.set_context(context.clone());
+
+            // Keys whose DOWN was forwarded raw: their UP / REPEAT stay raw
+            // even if a layer change maps the key meanwhile
+            let mut raw_down: HashSet<u16> = HashSet::new();
 
             let mut device = Device::open(&self.device_path)?;
 
@@ -84,12 +151,28 @@ impl EventProcessor {
                 );
             }
             info!("Device grabbed successfully.");
+            seed_switches(&device, &switches);
+
+            // Switches of other devices (e.g. a separate lid sensor) arrive as SWITCH events
+            for name in &settings.switch_devices {
+                match InputDeviceManager::find_device_path(name).await {
+                    Ok(path) => {
+                        tokio::spawn(watch_switches(
+                            path,
+                            switches.clone(),
+                            self.external_tx.clone(),
+                        ));
+                    }
+                    Err(e) => warn!("Switch device '{}' unavailable: {}", name, e),
+                }
+            }
 
             let mut events = device.into_event_stream()?;
-            let mut tick = tokio::time::interval(Duration::from_millis(50));
             let mut config_check = tokio::time::interval(Duration::from_secs(5));
 
             loop {
+                // Sleep until the state machine's next timer is due, or until input when idle
+                let deadline = state_machine.next_deadline();
                 tokio::select! {
                     Some(ev_res) = events.next() => {
                         match ev_res {
@@ -112,11 +195,52 @@ impl EventProcessor {
                                         }
                                     }
 
-                                    if state_machine.is_mapped(code) {
+                                    if let Some(target) = state_machine.remap_event(code, value) {
+                                        // 1:1 remap: keep DOWN/UP/REPEAT fidelity, no gesture delay
+                                        let mut dev = uinput.lock().await;
+                                        dev.send_key(target, value)?;
+                                    } else if value != 1 && raw_down.contains(&code) {
+                                        if value == 0 {
+                                            raw_down.remove(&code);
+                                        }
+                                        let mut dev = uinput.lock().await;
+                                        dev.send_key(code, value)?;
+                                    } else if state_machine.is_deferring() {
+                                        // A dual-role key is undecided: hold back every key event for in-order replay
+                                        let actions = state_machine.handle_key(code, value);
+                                        for action in actions {
+                                            ActionExecutor::execute(
+                                                &action,
+                                                uinput.clone(),
+                                                self.config.clone(),
+                                                Some(self.config_path.clone()),
+                                                context.clone(),
+                                            )
+                                            .await?;
+                                        }
+                                    } else if state_machine.is_mapped(code) {
                                         if value == 2 {
-                                            // Forward key repeat directly (state machine only handles DOWN/UP)
-                                            let mut dev = uinput.lock().await;
-                                            dev.send_key(code, value)?;
+                                            // Key repeat: dropped, forwarded raw or fired as a trigger,
+                                            // per the key's / rule's repeat policy
+                                            match state_machine.handle_repeat(code) {
+                                                RepeatOutcome::Drop => {}
+                                                RepeatOutcome::Forward => {
+                                                    let mut dev = uinput.lock().await;
+                                                    dev.send_key(code, value)?;
+                                                }
+                                                RepeatOutcome::Fire(actions) => {
+                                                    for action in actions {
+                                                        ActionExecutor::execute(
+                                                            &action,
+                                                            uinput.clone(),
+                                                            self.config.clone(),
+                                                            Some(self.config_path.clone()),
+                                                            context.clone(),
+                                                        )
+                                                        .await?;
+                                                    }
+                                                }
+                                            }
                                         } else {
                                             let actions = state_machine.handle_key(code, value);
                                             for action in actions {
@@ -125,15 +249,34 @@ impl EventProcessor {
                                                     uinput.clone(),
                                                     self.config.clone(),
                                                     Some(self.config_path.clone()),
+                                                    context.clone(),
                                                 )
                                                 .await?;
                                             }
                                         }
                                     } else {
                                         // Forward unmapped key events as-is (no sync here; SYN_REPORT handles it)
+                                        if value == 1 {
+                                            raw_down.insert(code);
+                                        }
                                         let mut dev = uinput.lock().await;
                                         dev.send_key(code, value)?;
                                     }
+                                } else if let InputEventKind::Switch(switch) = event.kind() {
+                                    // EV_SW transitions fire the SWITCH EXTERNAL rules; not forwarded
+                                    if let Some(external) = switches.update(switch.0, event.value() != 0) {
+                                        let actions = state_machine.handle_external(&external);
+                                        for action in actions {
+                                            ActionExecutor::execute(
+                                                &action,
+                                                uinput.clone(),
+                                                self.config.clone(),
+                                                Some(self.config_path.clone()),
+                                                context.clone(),
+                                            )
+                                            .await?;
+                                        }
+                                    }
                                 } else if event.kind() == InputEventKind::Synchronization(Synchronization::SYN_REPORT) {
                                     uinput.lock().await.sync()?;
                                 }
@@ -144,7 +287,20 @@ impl EventProcessor {
                             }
                         }
                     }
-                    _ = tick.tick() => {
+                    Some(external) = self.external_rx.recv() => {
+                        let actions = state_machine.handle_external(&external);
+                        for action in actions {
+                            ActionExecutor::execute(
+                                &action,
+                                uinput.clone(),
+                                self.config.clone(),
+                                Some(self.config_path.clone()),
+                                context.clone(),
+                            )
+                            .await?;
+                        }
+                    }
+                    _ = sleep_until_deadline(deadline) => {
                         {
                             let mut learn_guard = self.learn_state.lock();
                             learn_guard.refresh_timeout();
@@ -157,19 +313,35 @@ impl EventProcessor {
                                 uinput.clone(),
                                 self.config.clone(),
                                 Some(self.config_path.clone()),
+                                context.clone(),
                             )
                             .await?;
                         }
                     }
                     _ = config_check.tick() => {
-                        let (rules, settings, hw_map) = {
+                        let (rules, settings, hw_map, remaps, layers, repeat_policies) = {
                             let cfg = self.config.read().await;
-                            (cfg.rules.clone(), cfg.settings.clone(), cfg.hardware_map.clone())
+                            (
+                                cfg.rules.clone(),
+                                cfg.settings.clone(),
+                                cfg.hardware_map.clone(),
+                                cfg.remap_table(),
+                                cfg.layers.clone(),
+                                cfg.key_repeat_policy_table(),
+                            )
                         };
+                        state_machine.update_layers(remaps, &layers, &hw_map);
+                        state_machine.update_repeat_policies(repeat_policies);
                         state_machine.update_rules(rules, hw_map);
                         state_machine.update_settings(&settings);
                     }
                 }
+
+                // Publish the suppression counters for the status API
+                let mut stats = self.rule_stats.lock();
+                if stats.suppressed_fires != *state_machine.suppressed_fires() {
+                    stats.suppressed_fires = state_machine.suppressed_fires().clone();
+                }
             }
         }
 
@@ -180,6 +352,9 @@ impl EventProcessor {
                 &self.config_path,
                 self.debug_mode,
                 &self.learn_state,
+                &self.rule_stats,
+                &self.external_tx,
+                &self.external_rx,
             );
             warn!("Not on Linux/Android, EventProcessor loop is disabled.");
             tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
@@ -188,3 +363,11 @@ impl EventProcessor {
         Ok(())
     }
 }
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn sleep_until_deadline(deadline: Option<std::time::Instant>) {
+    match deadline {
+        Some(at) => tokio::time::sleep_until(tokio::time::Instant::from_std(at)).await,
+        None => std::future::pending().await,
+    }
+}
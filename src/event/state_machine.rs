@@ -1,5 +1,12 @@
-use crate::config::{Action, GlobalSettings, Rule, RuleType};
-use std::collections::{HashMap, HashSet};
+use crate::config::{
+    resolve_key, Action, GlobalSettings, HoldRepeat, Layer, RepeatPolicy, Rule, RuleType,
+    SequenceFallback, TapHoldFlavor,
+};
+use crate::event::conditions::{conditions_hold, ConditionContext, SystemContext};
+use crate::event::external::ExternalEvent;
+use log::debug;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
@@ -13,19 +20,99 @@ pub struct KeyState {
 #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
 struct PendingClick {
     key_code: u16,
+    // Rule the action belongs to; None for raw passthrough replays
+    rule_id: Option<String>,
     action: Action,
     available_at: Instant,
 }
 
+#[derive(Debug, Clone)]
+struct HoldRepeatState {
+    key_code: u16,
+    action: Action,
+    repeat: HoldRepeat,
+    step: usize,
+    next_at: Instant,
+}
+
+impl HoldRepeatState {
+    fn start(key_code: u16, rule: &Rule, now: Instant) -> Option<Self> {
+        let repeat = rule.repeat.clone()?;
+        let next_at = now + Duration::from_millis(repeat.delay_ms(0) as u64);
+        Some(Self {
+            key_code,
+            action: rule.action.clone(),
+            repeat,
+            step: 0,
+            next_at,
+        })
+    }
+}
+
+/// A dual-role key that is down but not yet resolved to tap or hold.
+#[derive(Debug)]
+struct PendingTapHold {
+    key_code: u16,
+    pressed_at: Instant,
+    tapping_term: Duration,
+    flavor: TapHoldFlavor,
+    hold_key: u16,
+    rule_id: String,
+    tap_action: Action,
+    // Keys pressed after the dual-role key (for permissive hold)
+    interrupted_by: HashSet<u16>,
+}
+
+/// Presses of a PATTERN key so far; each is classified per rule, by its own threshold.
+#[derive(Debug, Clone)]
+struct PatternInput {
+    holds: Vec<Duration>,
+    released_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SequenceTap {
+    key_code: u16,
+    hold: Duration,
+    released_at: Instant,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct ParsedRule {
     original: Rule,
     trigger_keys: Vec<u16>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LayerDef {
+    name: String,
+    rules: Vec<ParsedRule>,
+    remap: HashMap<u16, u16>,
+}
+
+/// Why an active layer is on the stack, i.e. what turns it off again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LayerHold {
+    Toggle,
+    // Until any of these (trigger) keys is released
+    Momentary(Vec<u16>),
+    // Until the next non-layer action fires
+    OneShot,
+}
+
+/// What an auto-repeat (value 2) event of a mapped key turns into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepeatOutcome {
+    Drop,
+    /// Send the raw repeat to the system
+    Forward,
+    Fire(Vec<Action>),
+}
+
 #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
 pub struct StateMachine {
     key_states: HashMap<u16, KeyState>,
+    // Effective rules: base rules merged with the active layers (see rebuild_active_rules)
     parsed_rules: Vec<ParsedRule>,
     pending_clicks: Vec<PendingClick>,
 
@@ -34,6 +121,7 @@ pub struct StateMachine {
     short_press_threshold: Duration,
     double_tap_interval: Duration,
     combination_timeout: Duration,
+    sequence_gap: Duration,
 
     // Tap history: KeyCode -> (Count, LastReleaseTime)
     tap_history: HashMap<u16, (u32, Instant)>,
@@ -41,11 +129,54 @@ pub struct StateMachine {
     // Track triggered rules to prevent repeats for Hold/ComboHold
     triggered_rules: HashSet<String>,
 
+    // Hold-repeat schedule of fired SHORT_PRESS / LONG_PRESS rules, by rule id
+    hold_repeats: HashMap<String, HoldRepeatState>,
+
     // Keys involved in a successful combo, to be ignored on release
     consumed_keys: HashSet<u16>,
 
+    // Taps held back while they match the prefix of a SEQUENCE rule
+    sequence_buffer: Vec<SequenceTap>,
+    pattern_inputs: HashMap<u16, PatternInput>,
+
+    // Undecided TAP_HOLD key and the (code, value, time) events held back meanwhile
+    pending_tap_hold: Option<PendingTapHold>,
+    deferred_events: Vec<(u16, i32, Instant)>,
+
+    // Dual-role keys resolved to hold: key_code -> hold_key currently pressed
+    held_dual_keys: HashMap<u16, u16>,
+
     // Cache of all keys that trigger any rule (for O(1) lookup)
     mapped_keys: HashSet<u16>,
+
+    // Rule sets the effective rules are built from
+    base_rules: Vec<ParsedRule>,
+    base_remap: HashMap<u16, u16>,
+    layers: Vec<LayerDef>,
+    // Active layers, bottom to top
+    active_layers: Vec<(String, LayerHold)>,
+
+    // Effective remap table, and the target each remapped key went DOWN as
+    remaps: HashMap<u16, u16>,
+    remapped_down: HashMap<u16, u16>,
+
+    // Raw DOWN/UP replays of unhandled gestures on passthrough keys, and the keys
+    // whose replayed (or live) DOWN has been sent but not their UP yet
+    passthrough_queue: Vec<PendingClick>,
+    passthrough_down: HashSet<u16>,
+
+    // Auto-repeat policy of keys whose rules set none
+    key_repeat_policy: HashMap<u16, RepeatPolicy>,
+
+    // Recent fires of rate-limited rules (within RATE_WINDOW), and the fires they held back
+    fire_log: HashMap<String, VecDeque<Instant>>,
+    suppressed_fires: HashMap<String, u64>,
+
+    // Outside state rule conditions are checked against
+    context: Arc<dyn ConditionContext>,
+
+    // Last tick since the latest key event or rule change; deadlines up to it are settled
+    ticked_at: Option<Instant>,
 }
 
 #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
@@ -57,6 +188,7 @@ impl StateMachine {
         short_press_ms: u64,
         double_tap_ms: u64,
         combination_timeout_ms: u64,
+        sequence_gap_ms: u64,
     ) -> Self {
         let mut sm = Self {
             key_states: HashMap::new(),
@@ -66,45 +198,237 @@ impl StateMachine {
             short_press_threshold: Duration::from_millis(short_press_ms),
             double_tap_interval: Duration::from_millis(double_tap_ms),
             combination_timeout: Duration::from_millis(combination_timeout_ms),
+            sequence_gap: Duration::from_millis(sequence_gap_ms),
             tap_history: HashMap::new(),
             triggered_rules: HashSet::new(),
+            hold_repeats: HashMap::new(),
             consumed_keys: HashSet::new(),
+            sequence_buffer: Vec::new(),
+            pattern_inputs: HashMap::new(),
+            pending_tap_hold: None,
+            deferred_events: Vec::new(),
+            held_dual_keys: HashMap::new(),
             mapped_keys: HashSet::new(),
+            base_rules: Vec::new(),
+            base_remap: HashMap::new(),
+            layers: Vec::new(),
+            active_layers: Vec::new(),
+            remaps: HashMap::new(),
+            remapped_down: HashMap::new(),
+            passthrough_queue: Vec::new(),
+            passthrough_down: HashSet::new(),
+            key_repeat_policy: HashMap::new(),
+            fire_log: HashMap::new(),
+            suppressed_fires: HashMap::new(),
+            context: Arc::new(SystemContext::default()),
+            ticked_at: None,
         };
         sm.update_rules(rules, hardware_map);
         sm
     }
 
     pub fn update_rules(&mut self, rules: Vec<Rule>, hardware_map: HashMap<u16, String>) {
-        let name_to_code: HashMap<String, u16> =
-            hardware_map.iter().map(|(k, v)| (v.clone(), *k)).collect();
+        let name_to_code = invert_map(&hardware_map);
+        let new_rules = parse_rules(rules, &name_to_code);
 
-        let new_rules: Vec<ParsedRule> = rules
-            .into_iter()
-            .map(|r| {
-                let keys = parse_trigger(&r.trigger, &name_to_code, r.rule_type);
-                ParsedRule {
-                    original: r,
-                    trigger_keys: keys,
-                }
+        if new_rules != self.base_rules {
+            self.base_rules = new_rules;
+            self.reset_gesture_state();
+            self.rebuild_active_rules();
+        }
+    }
+
+    /// Base remap table plus the named layers; active layers that no longer exist are dropped.
+    pub fn update_layers(
+        &mut self,
+        remap: HashMap<u16, u16>,
+        layers: &[Layer],
+        hardware_map: &HashMap<u16, String>,
+    ) {
+        let name_to_code = invert_map(hardware_map);
+        let new_layers: Vec<LayerDef> = layers
+            .iter()
+            .map(|layer| LayerDef {
+                name: layer.name.clone(),
+                rules: parse_rules(layer.rules.clone(), &name_to_code),
+                remap: layer
+                    .remap
+                    .iter()
+                    .filter_map(|(source, target)| {
+                        parse_trigger(source, &name_to_code, RuleType::Click)
+                            .first()
+                            .map(|code| (*code, *target))
+                    })
+                    .collect(),
             })
             .collect();
 
-        if new_rules != self.parsed_rules {
-            self.parsed_rules = new_rules;
-            self.triggered_rules.clear();
-            self.pending_clicks.clear();
-            self.tap_history.clear();
-            self.consumed_keys.clear();
+        if new_layers != self.layers || remap != self.base_remap {
+            if new_layers != self.layers {
+                self.reset_gesture_state();
+            }
+            self.layers = new_layers;
+            self.base_remap = remap;
+            let layers = &self.layers;
+            self.active_layers
+                .retain(|(name, _)| layers.iter().any(|l| &l.name == name));
+            self.rebuild_active_rules();
+        }
+    }
+
+    fn reset_gesture_state(&mut self) {
+        self.ticked_at = None;
+        self.triggered_rules.clear();
+        self.hold_repeats.clear();
+        self.pending_clicks.clear();
+        self.tap_history.clear();
+        self.consumed_keys.clear();
+        self.sequence_buffer.clear();
+        self.pattern_inputs.clear();
+    }
+
+    /// Merge the active layers (top first) over the base rules: a key used by a higher
+    /// layer's rules or remap hides every lower rule and remap on that key.
+    fn rebuild_active_rules(&mut self) {
+        let mut claimed: HashSet<u16> = HashSet::new();
+        let mut rules = Vec::new();
+        let mut remaps = HashMap::new();
+
+        let active = self
+            .active_layers
+            .iter()
+            .rev()
+            .filter_map(|(name, _)| self.layers.iter().find(|l| &l.name == name))
+            .map(|l| (&l.rules, &l.remap));
+        for (layer_rules, layer_remap) in active.chain([(&self.base_rules, &self.base_remap)]) {
+            let mut layer_keys: HashSet<u16> = layer_remap.keys().copied().collect();
+            for pr in layer_rules {
+                if pr.trigger_keys.iter().any(|k| claimed.contains(k)) {
+                    continue;
+                }
+                if pr.original.enabled {
+                    layer_keys.extend(pr.trigger_keys.iter().copied());
+                }
+                rules.push(pr.clone());
+            }
+            for (source, target) in layer_remap {
+                if !claimed.contains(source) {
+                    remaps.insert(*source, *target);
+                }
+            }
+            claimed.extend(layer_keys);
+        }
 
-            // Rebuild mapped_keys cache
-            self.mapped_keys = self
+        // Rules that appear under keys already held must not fire for this press.
+        for pr in &rules {
+            let is_new = !self
                 .parsed_rules
                 .iter()
-                .filter(|pr| pr.original.enabled)
-                .flat_map(|pr| pr.trigger_keys.iter())
-                .copied()
-                .collect();
+                .any(|old| old.original.id == pr.original.id);
+            if is_new
+                && !pr.trigger_keys.is_empty()
+                && pr
+                    .trigger_keys
+                    .iter()
+                    .all(|k| self.key_states.contains_key(k))
+            {
+                self.triggered_rules.insert(pr.original.id.clone());
+            }
+        }
+
+        self.parsed_rules = rules;
+        self.remaps = remaps;
+        self.mapped_keys = self
+            .parsed_rules
+            .iter()
+            .filter(|pr| pr.original.enabled)
+            .flat_map(|pr| pr.trigger_keys.iter())
+            .copied()
+            .collect();
+    }
+
+    /// Apply and strip the layer actions of a batch of fired actions.
+    fn apply_layer_actions(&mut self, actions: Vec<Action>) -> Vec<Action> {
+        if actions.is_empty() {
+            return actions;
+        }
+        let mut changed = false;
+        if actions.iter().any(|a| a.layer_target().is_none()) {
+            let before = self.active_layers.len();
+            self.active_layers
+                .retain(|(_, hold)| *hold != LayerHold::OneShot);
+            changed = self.active_layers.len() != before;
+        }
+
+        let mut remaining = Vec::with_capacity(actions.len());
+        for action in actions {
+            let Some(name) = action.layer_target() else {
+                remaining.push(action);
+                continue;
+            };
+            let active = self.active_layers.iter().position(|(n, _)| n == name);
+            let hold = match (&action, active) {
+                (Action::LayerToggle { .. }, Some(index)) => {
+                    self.active_layers.remove(index);
+                    changed = true;
+                    continue;
+                }
+                (_, Some(_)) => continue,
+                (Action::LayerToggle { .. }, None) => LayerHold::Toggle,
+                (Action::LayerOneShot { .. }, None) => LayerHold::OneShot,
+                _ => match self.momentary_keys(&action) {
+                    Some(keys) => LayerHold::Momentary(keys),
+                    None => continue,
+                },
+            };
+            self.active_layers.push((name.to_string(), hold));
+            changed = true;
+        }
+
+        if changed {
+            self.rebuild_active_rules();
+        }
+        remaining
+    }
+
+    /// Trigger keys of the held rule that fired a layer_momentary action.
+    fn momentary_keys(&self, action: &Action) -> Option<Vec<u16>> {
+        self.parsed_rules
+            .iter()
+            .find(|pr| {
+                pr.original.enabled
+                    && pr.original.action == *action
+                    && !pr.trigger_keys.is_empty()
+                    && pr
+                        .trigger_keys
+                        .iter()
+                        .all(|k| self.key_states.contains_key(k))
+            })
+            .map(|pr| pr.trigger_keys.clone())
+    }
+
+    fn release_momentary_layers(&mut self, key_code: u16) {
+        let before = self.active_layers.len();
+        self.active_layers.retain(|(_, hold)| match hold {
+            LayerHold::Momentary(keys) => !keys.contains(&key_code),
+            _ => true,
+        });
+        if self.active_layers.len() != before {
+            self.rebuild_active_rules();
+        }
+    }
+
+    /// Target of a 1:1 remap for this event, if any. UP / REPEAT follow the target the
+    /// key went DOWN with, so a layer change or config reload mid-press cannot leave a key stuck.
+    pub fn remap_event(&mut self, key_code: u16, value: i32) -> Option<u16> {
+        match value {
+            1 => {
+                let target = *self.remaps.get(&key_code)?;
+                self.remapped_down.insert(key_code, target);
+                Some(target)
+            }
+            0 => self.remapped_down.remove(&key_code),
+            _ => self.remapped_down.get(&key_code).copied(),
         }
     }
 
@@ -114,14 +438,47 @@ impl StateMachine {
             Duration::from_millis(settings.short_press_threshold_ms as u64);
         self.double_tap_interval = Duration::from_millis(settings.double_tap_interval_ms as u64);
         self.combination_timeout = Duration::from_millis(settings.combination_timeout_ms as u64);
+        self.sequence_gap = Duration::from_millis(settings.sequence_gap_ms as u64);
+        self.ticked_at = None;
     }
 
     pub fn handle_key(&mut self, key_code: u16, value: i32) -> Vec<Action> {
+        self.handle_key_at(key_code, value, Instant::now())
+    }
+
+    /// `handle_key` with an explicit event time instead of the current time.
+    pub fn handle_key_at(&mut self, key_code: u16, value: i32, now: Instant) -> Vec<Action> {
+        self.ticked_at = None;
+        let actions = self.process_key(key_code, value, now);
+        self.apply_layer_actions(actions)
+    }
+
+    fn process_key(&mut self, key_code: u16, value: i32, now: Instant) -> Vec<Action> {
         let mut actions = Vec::new();
-        let now = Instant::now();
+
+        if let Some(hold_key) = self.held_dual_keys.get(&key_code).copied() {
+            if value == 0 {
+                self.held_dual_keys.remove(&key_code);
+                actions.push(Action::KeyUp { key_code: hold_key });
+            }
+            return actions;
+        }
+
+        if self.pending_tap_hold.is_some() {
+            self.handle_deferred(key_code, value, now, &mut actions);
+            return actions;
+        }
+
+        if value == 1 && self.start_tap_hold(key_code, now) {
+            return actions;
+        }
 
         if value == 1 {
             // DOWN
+            self.cancel_replay(key_code, &mut actions);
+            if !self.sequence_buffer.is_empty() && !self.sequence_accepts(key_code) {
+                self.flush_sequence(now, &mut actions);
+            }
             self.key_states.insert(
                 key_code,
                 KeyState {
@@ -133,6 +490,14 @@ impl StateMachine {
         } else if value == 0 {
             // UP
             if let Some(state) = self.key_states.remove(&key_code) {
+                self.hold_repeats.retain(|_, r| r.key_code != key_code);
+
+                // The system already saw this press as-is, so it only needs the release.
+                let passed_through = self.passthrough_down.remove(&key_code);
+                if passed_through {
+                    actions.push(Action::KeyUp { key_code });
+                }
+
                 // Ghost-click fix:
                 // If a key participated in a successful combo, suppress its UP-triggered single click.
                 if self.consumed_keys.remove(&key_code) {
@@ -149,378 +514,2972 @@ impl StateMachine {
                 }
 
                 let hold_duration = now.duration_since(state.pressed_at);
-                let hold_handled = state.triggered_short_press || state.triggered_long_press;
+                let mut handled = state.triggered_short_press || state.triggered_long_press;
+
+                let released = self.release_actions(key_code, hold_duration);
+                if !released.is_empty() {
+                    self.settle_sequence_before_hold(state.pressed_at, now, &mut actions);
+                }
+                for (rule_id, action) in released {
+                    handled |= self.fire(&rule_id, action, now, &mut actions);
+                }
+                if !handled && self.handle_pattern_press(key_code, hold_duration, now, &mut actions)
+                {
+                    handled = true;
+                }
 
-                if !hold_handled && hold_duration < self.short_press_threshold {
+                if !handled && hold_duration < self.tap_threshold(key_code) {
                     let combo_clicks = self.check_combo_release(key_code, state.pressed_at, now);
                     if !combo_clicks.is_empty() {
                         actions.extend(combo_clicks);
+                        handled = true;
+                    } else if self.handle_sequence_tap(key_code, hold_duration, now, &mut actions) {
+                        handled = true;
                     } else {
-                        self.handle_tap(key_code, now, &mut actions);
+                        handled = self.handle_tap(key_code, hold_duration, now, &mut actions);
                     }
                 }
+
+                if !handled && !passed_through && self.is_passthrough_key(key_code) {
+                    self.queue_replay(key_code, 1, hold_duration, now);
+                    self.drain_replays(now, &mut actions);
+                }
             }
         }
 
         if self.key_states.is_empty() {
             self.consumed_keys.clear();
         }
+        if value == 0 {
+            self.release_momentary_layers(key_code);
+        }
 
         actions
     }
 
-    fn handle_tap(&mut self, key_code: u16, now: Instant, actions: &mut Vec<Action>) {
+    /// Count a tap towards the key's tap rules. False when the tap matched nothing and
+    /// nothing is left pending for it.
+    fn handle_tap(
+        &mut self,
+        key_code: u16,
+        hold: Duration,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) -> bool {
         let (count, last_time) = self.tap_history.get(&key_code).cloned().unwrap_or((0, now));
+        let interval = self.tap_interval(key_code);
 
-        let new_count = if now.duration_since(last_time) < self.double_tap_interval {
+        let new_count = if now.duration_since(last_time) < interval {
             count + 1
         } else {
             1
         };
         self.tap_history.insert(key_code, (new_count, now));
 
-        let double_click_rule = self.parsed_rules.iter().find(|pr| {
-            pr.original.enabled
-                && pr.trigger_keys.len() == 1
-                && pr.trigger_keys[0] == key_code
-                && pr.original.rule_type == RuleType::DoubleClick
-        });
-
-        let click_rules: Vec<&ParsedRule> = self
+        let tap_rules: Vec<(u32, &ParsedRule)> = self
             .parsed_rules
             .iter()
             .filter(|pr| {
                 pr.original.enabled
                     && pr.trigger_keys.len() == 1
                     && pr.trigger_keys[0] == key_code
-                    && pr.original.rule_type == RuleType::Click
+                    && hold < self.short_threshold(&pr.original)
             })
+            .filter_map(|pr| pr.original.tap_count().map(|count| (count, pr)))
             .collect();
 
-        if let Some(double_click_rule) = double_click_rule {
-            if new_count == 2 {
-                actions.push(double_click_rule.original.action.clone());
-                self.pending_clicks.retain(|p| p.key_code != key_code);
+        // Wait for the longest configured tap count before settling on a shorter one.
+        let max_count = tap_rules.iter().map(|(count, _)| *count).max().unwrap_or(0);
+        // Without multi-tap rules every tap is a fresh single click.
+        let new_count = if max_count <= 1 { 1 } else { new_count };
+        let matched: Vec<(String, Action)> = tap_rules
+            .iter()
+            .filter(|(count, _)| *count == new_count)
+            .map(|(_, pr)| (pr.original.id.clone(), pr.original.action.clone()))
+            .collect();
+
+        self.pending_clicks.retain(|p| p.key_code != key_code);
+        if new_count >= max_count {
+            // A tap whose rules were all held back is left to the passthrough replay
+            let fired = matched.into_iter().fold(false, |fired, (id, action)| {
+                self.fire(&id, action, now, actions) || fired
+            });
+            if max_count > 1 {
                 self.tap_history.remove(&key_code);
-            } else {
-                for pr in click_rules {
-                    self.pending_clicks.push(PendingClick {
-                        key_code,
-                        action: pr.original.action.clone(),
-                        available_at: now + self.double_tap_interval,
-                    });
-                }
             }
+            fired
         } else {
-            for pr in click_rules {
-                actions.push(pr.original.action.clone());
+            if self.is_passthrough_key(key_code) {
+                // Replay the taps so far unless a further tap or a pending rule takes them
+                self.queue_replay(key_code, new_count, hold, now + interval);
             }
+            for (rule_id, action) in matched {
+                self.pending_clicks.push(PendingClick {
+                    key_code,
+                    rule_id: Some(rule_id),
+                    action,
+                    available_at: now + interval,
+                });
+            }
+            true
         }
     }
 
-    pub fn tick(&mut self) -> Vec<Action> {
-        let mut actions = Vec::new();
-        let now = Instant::now();
-
-        let key_codes: Vec<u16> = self.key_states.keys().copied().collect();
+    /// Push a rule's action unless its `conditions` fail or its `cooldown_ms` /
+    /// `max_per_minute` holds it back; rate-suppressed fires are counted per rule.
+    /// Returns whether the action went out.
+    fn fire(
+        &mut self,
+        rule_id: &str,
+        action: Action,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) -> bool {
+        let Some(rule) = self
+            .parsed_rules
+            .iter()
+            .find(|pr| pr.original.id == rule_id)
+            .map(|pr| &pr.original)
+        else {
+            actions.push(action);
+            return true;
+        };
+        if !conditions_hold(&rule.conditions, self.context.as_ref()) {
+            debug!("Rule '{}' skipped: conditions not met", rule_id);
+            return false;
+        }
+        let limits = Some((rule.cooldown_ms, rule.max_per_minute))
+            .filter(|(cooldown, max)| cooldown.is_some() || max.is_some());
+        let Some((cooldown_ms, max_per_minute)) = limits else {
+            actions.push(action);
+            return true;
+        };
 
-        for key_code in key_codes {
-            if let Some(state) = self.key_states.get_mut(&key_code) {
-                let hold_time = now.duration_since(state.pressed_at);
+        let fires = self.fire_log.entry(rule_id.to_string()).or_default();
+        while fires
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= RATE_WINDOW)
+        {
+            fires.pop_front();
+        }
+        let cooling = cooldown_ms
+            .zip(fires.back())
+            .is_some_and(|(ms, last)| now.duration_since(*last) < Duration::from_millis(ms as u64));
+        let limited = max_per_minute.is_some_and(|max| fires.len() >= max as usize);
+        if cooling || limited {
+            let count = self
+                .suppressed_fires
+                .entry(rule_id.to_string())
+                .or_default();
+            *count += 1;
+            debug!(
+                "Rule '{}' fire suppressed by {} ({} so far)",
+                rule_id,
+                if cooling {
+                    "cooldown_ms"
+                } else {
+                    "max_per_minute"
+                },
+                count
+            );
+            return false;
+        }
+        fires.push_back(now);
+        actions.push(action);
+        true
+    }
 
-                let sp_rules: Vec<&ParsedRule> = self
-                    .parsed_rules
-                    .iter()
-                    .filter(|pr| {
-                        pr.original.enabled
-                            && pr.trigger_keys.len() == 1
-                            && pr.trigger_keys[0] == key_code
-                            && pr.original.rule_type == RuleType::ShortPress
-                    })
-                    .collect();
+    /// Replaces the source of outside state (local time, ...) rule conditions are checked against.
+    pub fn set_context(&mut self, context: Arc<dyn ConditionContext>) {
+        self.context = context;
+    }
 
-                for pr in sp_rules {
-                    if !self.triggered_rules.contains(&pr.original.id)
-                        && hold_time >= self.short_press_threshold
-                    {
-                        actions.push(pr.original.action.clone());
-                        self.triggered_rules.insert(pr.original.id.clone());
-                        state.triggered_short_press = true;
-                    }
-                }
+    /// Fires held back by cooldown_ms / max_per_minute, per rule id.
+    pub fn suppressed_fires(&self) -> &HashMap<String, u64> {
+        &self.suppressed_fires
+    }
 
-                let lp_rules: Vec<&ParsedRule> = self
-                    .parsed_rules
-                    .iter()
-                    .filter(|pr| {
-                        pr.original.enabled
-                            && pr.trigger_keys.len() == 1
-                            && pr.trigger_keys[0] == key_code
-                            && pr.original.rule_type == RuleType::LongPress
-                    })
-                    .collect();
+    fn is_passthrough_key(&self, key_code: u16) -> bool {
+        self.parsed_rules.iter().any(|pr| {
+            pr.original.enabled && pr.original.passthrough && pr.trigger_keys.contains(&key_code)
+        })
+    }
 
-                for pr in lp_rules {
-                    if !self.triggered_rules.contains(&pr.original.id)
-                        && hold_time >= self.long_press_threshold
-                    {
-                        actions.push(pr.original.action.clone());
-                        self.triggered_rules.insert(pr.original.id.clone());
-                        state.triggered_long_press = true;
-                    }
+    /// Actions of the single-key `on_release` press rules matched by a hold of `hold`:
+    /// SHORT_PRESS between its short and long thresholds, LONG_PRESS past its long threshold.
+    fn release_actions(&self, key_code: u16, hold: Duration) -> Vec<(String, Action)> {
+        self.parsed_rules
+            .iter()
+            .filter(|pr| {
+                pr.original.enabled && pr.original.on_release && pr.trigger_keys == [key_code]
+            })
+            .filter(|pr| match pr.original.rule_type {
+                RuleType::ShortPress => {
+                    hold >= self.short_threshold(&pr.original)
+                        && hold < self.long_threshold(&pr.original)
                 }
+                RuleType::LongPress => hold >= self.long_threshold(&pr.original),
+                _ => false,
+            })
+            .map(|pr| (pr.original.id.clone(), pr.original.action.clone()))
+            .collect()
+    }
+
+    /// Hold time after which no rule on the key can match the press anymore, or None when
+    /// a release-timed LONG_PRESS can still match however long the key is held.
+    fn passthrough_deadline(&self, key_code: u16) -> Option<Duration> {
+        let mut deadline = Duration::ZERO;
+        for pr in &self.parsed_rules {
+            if !pr.original.enabled || !pr.trigger_keys.contains(&key_code) {
+                continue;
             }
+            let threshold = match pr.original.rule_type {
+                // Decided on release, however long the key is held
+                RuleType::LongPress if pr.original.on_release => return None,
+                RuleType::Pattern => return None,
+                RuleType::LongPress | RuleType::ComboLongPress => self.long_threshold(&pr.original),
+                RuleType::ShortPress if pr.original.on_release => self.long_threshold(&pr.original),
+                _ => self.short_threshold(&pr.original),
+            };
+            deadline = deadline.max(threshold);
         }
+        Some(deadline)
+    }
 
-        actions.extend(self.check_combo_hold(
-            RuleType::ComboShortPress,
-            self.short_press_threshold,
-            now,
-        ));
-        actions.extend(self.check_combo_hold(
-            RuleType::ComboLongPress,
-            self.long_press_threshold,
-            now,
-        ));
+    /// Schedule `taps` raw presses of `hold` each, starting at `start`.
+    fn queue_replay(&mut self, key_code: u16, taps: u32, hold: Duration, start: Instant) {
+        self.passthrough_queue.retain(|p| p.key_code != key_code);
+        let mut down_at = start;
+        for _ in 0..taps {
+            down_at = self.append_replay(key_code, hold, down_at);
+        }
+    }
 
+    /// Queue one raw DOWN/UP held for `hold` behind the replays already queued. Returns
+    /// when the next press may go down, one `hold` after this one's UP.
+    fn append_replay(&mut self, key_code: u16, hold: Duration, down_at: Instant) -> Instant {
+        self.passthrough_queue.push(PendingClick {
+            key_code,
+            rule_id: None,
+            action: Action::KeyDown { key_code },
+            available_at: down_at,
+        });
+        self.passthrough_queue.push(PendingClick {
+            key_code,
+            rule_id: None,
+            action: Action::KeyUp { key_code },
+            available_at: down_at + hold,
+        });
+        down_at + hold * 2
+    }
+
+    /// Drop a not yet finished replay of the key, releasing it if its DOWN already went out.
+    fn cancel_replay(&mut self, key_code: u16, actions: &mut Vec<Action>) {
+        self.passthrough_queue.retain(|p| p.key_code != key_code);
+        if self.passthrough_down.remove(&key_code) {
+            actions.push(Action::KeyUp { key_code });
+        }
+    }
+
+    fn drain_replays(&mut self, now: Instant, actions: &mut Vec<Action>) {
         let mut retained = Vec::new();
-        for pending in self.pending_clicks.drain(..) {
-            if now >= pending.available_at {
-                actions.push(pending.action);
-            } else {
+        for pending in self.passthrough_queue.drain(..) {
+            if now < pending.available_at {
                 retained.push(pending);
+                continue;
             }
+            match pending.action {
+                Action::KeyDown { key_code } => self.passthrough_down.insert(key_code),
+                _ => self.passthrough_down.remove(&pending.key_code),
+            };
+            actions.push(pending.action);
         }
-        self.pending_clicks = retained;
+        self.passthrough_queue = retained;
+    }
 
-        actions
+    fn start_tap_hold(&mut self, key_code: u16, now: Instant) -> bool {
+        let Some(pr) = self.parsed_rules.iter().find(|pr| {
+            pr.original.enabled
+                && pr.original.rule_type == RuleType::TapHold
+                && pr.trigger_keys == [key_code]
+        }) else {
+            return false;
+        };
+        let Some(tap_hold) = pr.original.tap_hold.as_ref() else {
+            return false;
+        };
+
+        let tapping_term =
+            override_or(tap_hold.tapping_term_ms, self.short_threshold(&pr.original));
+        self.pending_tap_hold = Some(PendingTapHold {
+            key_code,
+            pressed_at: now,
+            tapping_term,
+            flavor: tap_hold.flavor,
+            hold_key: tap_hold.hold_key,
+            rule_id: pr.original.id.clone(),
+            tap_action: pr.original.action.clone(),
+            interrupted_by: HashSet::new(),
+        });
+        true
     }
 
-    fn check_combo_hold(
+    /// Key events while a dual-role key is undecided: the dual-role key itself resolves
+    /// it, everything else is held back and may resolve it early depending on the flavor.
+    fn handle_deferred(
         &mut self,
-        rtype: RuleType,
-        default_threshold: Duration,
+        key_code: u16,
+        value: i32,
         now: Instant,
-    ) -> Vec<Action> {
-        let mut actions = Vec::new();
+        actions: &mut Vec<Action>,
+    ) {
+        let Some(pending) = self.pending_tap_hold.as_mut() else {
+            return;
+        };
 
-        let indices: Vec<usize> = self
-            .parsed_rules
-            .iter()
-            .enumerate()
-            .filter(|(_, pr)| {
-                pr.original.enabled && pr.original.rule_type == rtype && pr.trigger_keys.len() == 2
-            })
-            .map(|(i, _)| i)
-            .collect();
+        if key_code == pending.key_code {
+            if value == 0 {
+                let dual_key = pending.key_code;
+                let hold = now.duration_since(pending.pressed_at) >= pending.tapping_term;
+                self.resolve_tap_hold(hold, now, actions);
+                if let Some(hold_key) = self.held_dual_keys.remove(&dual_key) {
+                    actions.push(Action::KeyUp { key_code: hold_key });
+                }
+            }
+            return;
+        }
 
-        for i in indices {
-            let pr = &self.parsed_rules[i];
-            if self.triggered_rules.contains(&pr.original.id) {
-                continue;
+        self.deferred_events.push((key_code, value, now));
+        let hold = match (pending.flavor, value) {
+            (TapHoldFlavor::HoldOnOtherKeyPress, 1) => true,
+            (TapHoldFlavor::PermissiveHold, 1) => {
+                pending.interrupted_by.insert(key_code);
+                false
             }
+            (TapHoldFlavor::PermissiveHold, 0) => pending.interrupted_by.contains(&key_code),
+            _ => false,
+        };
+        if hold {
+            self.resolve_tap_hold(true, now, actions);
+        }
+    }
 
-            let all_held_long_enough = pr.trigger_keys.iter().all(|k| {
-                self.key_states
-                    .get(k)
-                    .map(|state| now.duration_since(state.pressed_at) >= default_threshold)
-                    .unwrap_or(false)
+    /// Settle the pending dual-role key, then replay the held-back events in order, each
+    /// at the time it happened so the gestures they form keep their real durations.
+    fn resolve_tap_hold(&mut self, hold: bool, now: Instant, actions: &mut Vec<Action>) {
+        let Some(pending) = self.pending_tap_hold.take() else {
+            return;
+        };
+
+        if hold {
+            actions.push(Action::KeyDown {
+                key_code: pending.hold_key,
             });
+            self.held_dual_keys
+                .insert(pending.key_code, pending.hold_key);
+        } else {
+            self.fire(&pending.rule_id, pending.tap_action, now, actions);
+        }
 
-            if !all_held_long_enough {
-                continue;
+        for (code, value, at) in std::mem::take(&mut self.deferred_events) {
+            if self.pending_tap_hold.is_some() || self.is_mapped(code) {
+                actions.extend(self.process_key(code, value, at));
+            } else {
+                match value {
+                    1 => actions.push(Action::KeyDown { key_code: code }),
+                    0 => actions.push(Action::KeyUp { key_code: code }),
+                    // Auto-repeat of a held-back key is dropped; the OS restarts it on its own
+                    _ => {}
+                }
             }
+        }
+    }
 
-            let times: Vec<Instant> = pr
-                .trigger_keys
-                .iter()
-                .filter_map(|k| self.key_states.get(k).map(|s| s.pressed_at))
-                .collect();
+    /// True while a dual-role key is undecided: every key event must go through
+    /// `handle_key` so it can be held back and replayed in order.
+    pub fn is_deferring(&self) -> bool {
+        self.pending_tap_hold.is_some()
+    }
 
-            if let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) {
-                if max.duration_since(*min) <= self.combination_timeout {
-                    actions.push(pr.original.action.clone());
-                    self.triggered_rules.insert(pr.original.id.clone());
-                    for key in &pr.trigger_keys {
-                        self.consumed_keys.insert(*key);
-                        if let Some(state) = self.key_states.get_mut(key) {
-                            match rtype {
-                                RuleType::ComboShortPress => state.triggered_short_press = true,
-                                RuleType::ComboLongPress => state.triggered_long_press = true,
-                                _ => {}
-                            }
-                        }
-                    }
-                }
+    /// Enabled SEQUENCE rules whose steps start with `steps`.
+    fn sequence_candidates(&self, steps: &[u16]) -> Vec<&ParsedRule> {
+        self.parsed_rules
+            .iter()
+            .filter(|pr| {
+                pr.original.enabled
+                    && pr.original.rule_type == RuleType::Sequence
+                    && pr.trigger_keys.starts_with(steps)
+            })
+            .collect()
+    }
+
+    fn sequence_accepts(&self, key_code: u16) -> bool {
+        let mut steps: Vec<u16> = self.sequence_buffer.iter().map(|t| t.key_code).collect();
+        steps.push(key_code);
+        !self.sequence_candidates(&steps).is_empty()
+    }
+
+    /// Returns true if the tap was absorbed by SEQUENCE tracking.
+    fn handle_sequence_tap(
+        &mut self,
+        key_code: u16,
+        hold: Duration,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) -> bool {
+        if let Some(last) = self.sequence_buffer.last() {
+            if now.duration_since(last.released_at) > self.pending_sequence_gap() {
+                self.resolve_sequence(now, actions);
             }
         }
 
-        actions
+        if !self.sequence_accepts(key_code) {
+            if self.sequence_buffer.is_empty() {
+                return false;
+            }
+            // The tap breaks the sequence in progress; it may still start a new one.
+            self.flush_sequence(now, actions);
+            return self.handle_sequence_tap(key_code, hold, now, actions);
+        }
+
+        self.sequence_buffer.push(SequenceTap {
+            key_code,
+            hold,
+            released_at: now,
+        });
+
+        let steps: Vec<u16> = self.sequence_buffer.iter().map(|t| t.key_code).collect();
+        let candidates = self.sequence_candidates(&steps);
+        // Fire right away unless a longer sequence with the same prefix is still possible.
+        if candidates
+            .iter()
+            .all(|pr| pr.trigger_keys.len() == steps.len())
+        {
+            let matched: Vec<(String, Action)> = candidates
+                .iter()
+                .map(|pr| (pr.original.id.clone(), pr.original.action.clone()))
+                .collect();
+            self.fire_sequence(matched, now, actions);
+        }
+        true
     }
 
-    fn check_combo_release(
+    /// Called once the gap expires: fire an exact match, otherwise fall back.
+    fn resolve_sequence(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        let steps: Vec<u16> = self.sequence_buffer.iter().map(|t| t.key_code).collect();
+        let exact: Vec<(String, Action)> = self
+            .sequence_candidates(&steps)
+            .iter()
+            .filter(|pr| pr.trigger_keys.len() == steps.len())
+            .map(|pr| (pr.original.id.clone(), pr.original.action.clone()))
+            .collect();
+
+        self.fire_sequence(exact, now, actions);
+    }
+
+    /// Fire the rules a finished sequence matched; when none of them goes out the
+    /// buffered taps fall back as if the sequence had matched nothing.
+    fn fire_sequence(
         &mut self,
-        key_code: u16,
-        released_pressed_at: Instant,
+        matched: Vec<(String, Action)>,
         now: Instant,
-    ) -> Vec<Action> {
-        let mut actions = Vec::new();
-        for pr in &self.parsed_rules {
-            if !(pr.original.enabled
-                && pr.original.rule_type == RuleType::ComboClick
-                && pr.trigger_keys.len() == 2
-                && pr.trigger_keys.contains(&key_code))
-            {
+        actions: &mut Vec<Action>,
+    ) {
+        let fired = matched.into_iter().fold(false, |fired, (id, action)| {
+            self.fire(&id, action, now, actions) || fired
+        });
+        if fired {
+            self.sequence_buffer.clear();
+        } else {
+            self.flush_sequence(now, actions);
+        }
+    }
+
+    /// A key pressed after the last buffered tap was taken as the sequence's next step;
+    /// once a hold rule claims it the sequence cannot complete, so the buffered taps are
+    /// settled before the hold's action goes out.
+    fn settle_sequence_before_hold(
+        &mut self,
+        pressed_at: Instant,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) {
+        let next_step = self
+            .sequence_buffer
+            .last()
+            .is_some_and(|last| pressed_at >= last.released_at);
+        if next_step {
+            self.flush_sequence(now, actions);
+        }
+    }
+
+    /// Abandon an unfinished sequence; buffered taps are dropped only when every
+    /// rule they could have completed asks for it.
+    fn flush_sequence(&mut self, now: Instant, actions: &mut Vec<Action>) {
+        let buffer = std::mem::take(&mut self.sequence_buffer);
+        if buffer.is_empty() {
+            return;
+        }
+
+        let steps: Vec<u16> = buffer.iter().map(|t| t.key_code).collect();
+        let drop = self
+            .sequence_candidates(&steps)
+            .iter()
+            .all(|pr| pr.original.sequence_fallback.unwrap_or_default() == SequenceFallback::Drop);
+        if drop {
+            return;
+        }
+
+        let mut down_at = now;
+        for tap in buffer {
+            down_at = self.replay_tap(tap, down_at, actions);
+        }
+        self.drain_replays(now, actions);
+    }
+
+    /// Re-dispatch a tap as if no SEQUENCE rule existed: through tap rules if the
+    /// key has any, otherwise back to the system as a DOWN/UP with the original hold,
+    /// starting at `down_at`. Returns when a following raw replay may start.
+    fn replay_tap(
+        &mut self,
+        tap: SequenceTap,
+        down_at: Instant,
+        actions: &mut Vec<Action>,
+    ) -> Instant {
+        let key_code = tap.key_code;
+        let has_tap_rules = self.parsed_rules.iter().any(|pr| {
+            pr.original.enabled
+                && pr.original.rule_type != RuleType::Sequence
+                && pr.trigger_keys == [key_code]
+        });
+
+        let handled =
+            has_tap_rules && self.handle_tap(key_code, tap.hold, tap.released_at, actions);
+        if handled || (has_tap_rules && !self.is_passthrough_key(key_code)) {
+            return down_at;
+        }
+        self.append_replay(key_code, tap.hold, down_at)
+    }
+
+    pub fn tick(&mut self) -> Vec<Action> {
+        self.tick_at(Instant::now())
+    }
+
+    /// `tick` with an explicit current time.
+    pub fn tick_at(&mut self, now: Instant) -> Vec<Action> {
+        self.ticked_at = Some(now);
+        let actions = self.poll_timers(now);
+        self.apply_layer_actions(actions)
+    }
+
+    /// Feed `(offset_ms, code, value)` events (ascending offsets from a common start),
+    /// ticking exactly at each `next_deadline` like the event loop does, up to `until_ms`.
+    /// Returns the non-empty batches of emitted actions with their offset.
+    #[cfg(test)]
+    pub fn replay(&mut self, events: &[(u64, u16, i32)], until_ms: u64) -> Vec<(u64, Vec<Action>)> {
+        let start = Instant::now();
+        let until = start + Duration::from_millis(until_ms);
+        let mut emitted: Vec<(u64, Vec<Action>)> = Vec::new();
+        let mut pending = events.iter().peekable();
+        let mut clock = start;
+        let mut last_tick: Option<Instant> = None;
+        loop {
+            let next_event = pending
+                .peek()
+                .map(|(at, _, _)| start + Duration::from_millis(*at));
+            let deadline = self.next_deadline().filter(|d| *d <= until);
+            let actions = match (next_event, deadline) {
+                (Some(at), d) if d.is_none_or(|d| at <= d.max(clock)) => {
+                    let (_, code, value) = pending.next().unwrap();
+                    clock = at;
+                    last_tick = None;
+                    self.handle_key_at(*code, *value, at)
+                }
+                (_, Some(d)) => {
+                    // A tick must move the deadline on, otherwise the loop would spin
+                    assert!(last_tick.is_none_or(|t| d > t), "deadline did not advance");
+                    clock = d.max(clock);
+                    last_tick = Some(clock);
+                    self.tick_at(clock)
+                }
+                _ => break,
+            };
+            if actions.is_empty() {
                 continue;
             }
-            if self.triggered_rules.contains(&pr.original.id) {
+            let at = clock.duration_since(start).as_millis() as u64;
+            match emitted.last_mut() {
+                Some((last_at, batch)) if *last_at == at => batch.extend(actions),
+                _ => emitted.push((at, actions)),
+            }
+        }
+        emitted
+    }
+
+    fn poll_timers(&mut self, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        let term_expired = self
+            .pending_tap_hold
+            .as_ref()
+            .is_some_and(|p| now.duration_since(p.pressed_at) >= p.tapping_term);
+        if term_expired {
+            self.resolve_tap_hold(true, now, &mut actions);
+        }
+
+        let key_codes: Vec<u16> = self.key_states.keys().copied().collect();
+        let (short_default, long_default) = (self.short_press_threshold, self.long_press_threshold);
+
+        // (key, long press?, rule id, action) of every hold threshold crossed by this tick
+        let mut fired: Vec<(u16, bool, String, Action)> = Vec::new();
+        for key_code in key_codes {
+            // A key taken by a combo no longer counts as a single-key hold
+            if self.consumed_keys.contains(&key_code) {
                 continue;
             }
+            if let Some(state) = self.key_states.get_mut(&key_code) {
+                let hold_time = now.duration_since(state.pressed_at);
 
-            let mut pressed_times = Vec::with_capacity(2);
-            let mut valid_combo = true;
+                let sp_rules: Vec<&ParsedRule> = self
+                    .parsed_rules
+                    .iter()
+                    .filter(|pr| {
+                        pr.original.enabled
+                            && pr.trigger_keys.len() == 1
+                            && pr.trigger_keys[0] == key_code
+                            && pr.original.rule_type == RuleType::ShortPress
+                            && !pr.original.on_release
+                    })
+                    .collect();
 
-            for k in &pr.trigger_keys {
-                if *k == key_code {
-                    if now.duration_since(released_pressed_at) >= self.short_press_threshold {
-                        valid_combo = false;
-                        break;
+                for pr in sp_rules {
+                    if !self.triggered_rules.contains(&pr.original.id)
+                        && hold_time
+                            >= override_or(
+                                pr.original.timing.short_press_threshold_ms,
+                                short_default,
+                            )
+                    {
+                        let rule = &pr.original;
+                        fired.push((key_code, false, rule.id.clone(), rule.action.clone()));
+                        self.triggered_rules.insert(pr.original.id.clone());
+                        if let Some(r) = HoldRepeatState::start(key_code, &pr.original, now) {
+                            self.hold_repeats.insert(pr.original.id.clone(), r);
+                        }
                     }
-                    pressed_times.push(released_pressed_at);
-                    continue;
                 }
 
-                if let Some(state) = self.key_states.get(k) {
-                    if now.duration_since(state.pressed_at) >= self.short_press_threshold {
-                        valid_combo = false;
-                        break;
+                let lp_rules: Vec<&ParsedRule> = self
+                    .parsed_rules
+                    .iter()
+                    .filter(|pr| {
+                        pr.original.enabled
+                            && pr.trigger_keys.len() == 1
+                            && pr.trigger_keys[0] == key_code
+                            && pr.original.rule_type == RuleType::LongPress
+                            && !pr.original.on_release
+                    })
+                    .collect();
+
+                for pr in lp_rules {
+                    if !self.triggered_rules.contains(&pr.original.id)
+                        && hold_time
+                            >= override_or(pr.original.timing.long_press_threshold_ms, long_default)
+                    {
+                        let rule = &pr.original;
+                        fired.push((key_code, true, rule.id.clone(), rule.action.clone()));
+                        self.triggered_rules.insert(pr.original.id.clone());
+                        if let Some(r) = HoldRepeatState::start(key_code, &pr.original, now) {
+                            self.hold_repeats.insert(pr.original.id.clone(), r);
+                        }
                     }
-                    pressed_times.push(state.pressed_at);
-                } else {
-                    valid_combo = false;
-                    break;
                 }
             }
+        }
 
-            if !valid_combo {
+        for (key_code, long_press, rule_id, action) in fired {
+            if let Some(pressed_at) = self.key_states.get(&key_code).map(|s| s.pressed_at) {
+                self.settle_sequence_before_hold(pressed_at, now, &mut actions);
+            }
+            // A suppressed fire neither claims the press nor starts the hold repeat
+            if !self.fire(&rule_id, action, now, &mut actions) {
+                self.hold_repeats.remove(&rule_id);
                 continue;
             }
-
-            if let (Some(min), Some(max)) = (pressed_times.iter().min(), pressed_times.iter().max())
-            {
-                if max.duration_since(*min) <= self.combination_timeout {
-                    actions.push(pr.original.action.clone());
-                    self.triggered_rules.insert(pr.original.id.clone());
-                    // Mark all keys in this combo as consumed
-                    for k in &pr.trigger_keys {
-                        self.consumed_keys.insert(*k);
-                    }
+            if let Some(state) = self.key_states.get_mut(&key_code) {
+                if long_press {
+                    state.triggered_long_press = true;
+                } else {
+                    state.triggered_short_press = true;
                 }
             }
         }
-        actions
-    }
-
-    pub fn is_mapped(&self, key_code: u16) -> bool {
-        self.mapped_keys.contains(&key_code)
-    }
-}
 
-fn parse_trigger(trigger: &str, map: &HashMap<String, u16>, rule_type: RuleType) -> Vec<u16> {
-    let parse_token = |token: &str| -> Option<u16> {
-        let t = token.trim();
-        if t.is_empty() {
-            return None;
+        let due: Vec<(String, Action)> = self
+            .hold_repeats
+            .iter()
+            .filter(|(_, repeat)| now >= repeat.next_at)
+            .map(|(rule_id, repeat)| (rule_id.clone(), repeat.action.clone()))
+            .collect();
+        for (rule_id, action) in due {
+            // Repeats obey the rule's conditions and limits too; the first one held back
+            // ends the repeat for this press
+            if !self.fire(&rule_id, action, now, &mut actions) {
+                self.hold_repeats.remove(&rule_id);
+                continue;
+            }
+            let Some(repeat) = self.hold_repeats.get_mut(&rule_id) else {
+                continue;
+            };
+            repeat.step += 1;
+            let delay = Duration::from_millis(repeat.repeat.delay_ms(repeat.step) as u64);
+            repeat.next_at += delay;
+            if repeat.next_at <= now {
+                // Fell behind (late tick): resume the cadence from now instead of bursting
+                repeat.next_at = now + delay;
+            }
         }
-        if let Ok(code) = t.parse::<u16>() {
-            return Some(code);
+
+        actions.extend(self.check_combo_hold(RuleType::ComboShortPress, now));
+        actions.extend(self.check_combo_hold(RuleType::ComboLongPress, now));
+
+        // Held past every rule's threshold without a match: hand the press to the system
+        // while it is still down, so native long-press keeps working.
+        let unhandled: Vec<u16> = self
+            .key_states
+            .iter()
+            .filter(|(code, state)| {
+                !state.triggered_short_press
+                    && !state.triggered_long_press
+                    && !self.passthrough_down.contains(code)
+                    && !self.consumed_keys.contains(code)
+                    && self.is_passthrough_key(**code)
+                    && self
+                        .passthrough_deadline(**code)
+                        .is_some_and(|d| now.duration_since(state.pressed_at) >= d)
+            })
+            .map(|(code, _)| *code)
+            .collect();
+        for key_code in unhandled {
+            self.passthrough_down.insert(key_code);
+            actions.push(Action::KeyDown { key_code });
         }
-        map.get(t).copied()
-    };
 
-    match rule_type {
-        RuleType::ComboClick | RuleType::ComboShortPress | RuleType::ComboLongPress => {
-            let parts: Vec<&str> = trigger
-                .split('+')
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .collect();
-            if parts.len() != 2 {
-                return Vec::new();
+        if let Some(last) = self.sequence_buffer.last() {
+            if now.duration_since(last.released_at) > self.pending_sequence_gap() {
+                self.resolve_sequence(now, &mut actions);
             }
+        }
 
-            match (parse_token(parts[0]), parse_token(parts[1])) {
-                (Some(a), Some(b)) if a != b => vec![a, b],
-                _ => Vec::new(),
+        // A held key may still extend its pattern; it is settled on release then
+        let expired: Vec<u16> = self
+            .pattern_inputs
+            .iter()
+            .filter(|(key_code, input)| {
+                !self.key_states.contains_key(key_code)
+                    && now.duration_since(input.released_at) > self.pattern_gap(**key_code)
+            })
+            .map(|(key_code, _)| *key_code)
+            .collect();
+        for key_code in expired {
+            if let Some(input) = self.pattern_inputs.remove(&key_code) {
+                self.resolve_pattern(key_code, &input.holds, now, &mut actions);
             }
         }
-        _ => parse_token(trigger)
-            .map(|code| vec![code])
-            .unwrap_or_default(),
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    fn make_rule(id: &str, trigger: &str, rule_type: RuleType, action: Action) -> Rule {
-        Rule {
-            id: id.to_string(),
-            trigger: trigger.to_string(),
-            rule_type,
-            action,
-            enabled: true,
-            description: String::new(),
+        let mut retained = Vec::new();
+        let mut due = Vec::new();
+        for pending in self.pending_clicks.drain(..) {
+            if now >= pending.available_at {
+                due.push(pending);
+            } else {
+                retained.push(pending);
+            }
         }
+        self.pending_clicks = retained;
+        for pending in due {
+            match pending.rule_id {
+                Some(rule_id) => {
+                    // The replay queued alongside stands in for a rule that was held back
+                    if self.fire(&rule_id, pending.action, now, &mut actions) {
+                        self.passthrough_queue
+                            .retain(|p| p.key_code != pending.key_code);
+                    }
+                }
+                None => actions.push(pending.action),
+            }
+        }
+        self.drain_replays(now, &mut actions);
+
+        actions
     }
 
-    #[test]
-    fn combo_release_should_not_trigger_single_click_after_combo_consumed() {
-        let rules = vec![
-            make_rule(
-                "combo_click",
-                "115+114",
-                RuleType::ComboClick,
-                Action::SendKey { key_code: 42 },
-            ),
-            make_rule(
-                "single_click",
-                "115",
-                RuleType::Click,
-                Action::SendKey { key_code: 24 },
-            ),
-        ];
+    fn check_combo_hold(&mut self, rtype: RuleType, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
 
-        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200);
+        let indices: Vec<usize> = self
+            .parsed_rules
+            .iter()
+            .enumerate()
+            .filter(|(_, pr)| {
+                pr.original.enabled && pr.original.rule_type == rtype && pr.trigger_keys.len() >= 2
+            })
+            .map(|(i, _)| i)
+            .collect();
 
-        assert!(sm.handle_key(115, 1).is_empty());
-        assert!(sm.handle_key(114, 1).is_empty());
+        let mut matched = Vec::new();
+        for i in indices {
+            let pr = &self.parsed_rules[i];
+            if self.triggered_rules.contains(&pr.original.id) {
+                continue;
+            }
 
-        let combo_actions = sm.handle_key(114, 0);
-        assert_eq!(combo_actions.len(), 1);
-        assert_eq!(combo_actions[0], Action::SendKey { key_code: 42 });
+            let threshold = match rtype {
+                RuleType::ComboLongPress => self.long_threshold(&pr.original),
+                _ => self.short_threshold(&pr.original),
+            };
+            let all_held_long_enough = pr.trigger_keys.iter().all(|k| {
+                self.key_states
+                    .get(k)
+                    .map(|state| now.duration_since(state.pressed_at) >= threshold)
+                    .unwrap_or(false)
+            });
 
-        let release_first_key_actions = sm.handle_key(115, 0);
-        assert!(
-            release_first_key_actions.is_empty(),
-            "single click should be suppressed after combo is consumed"
-        );
-    }
+            if !all_held_long_enough {
+                continue;
+            }
 
-    #[test]
-    fn parse_trigger_should_support_named_single_key() {
-        let mut map = HashMap::new();
-        map.insert("POWER".to_string(), 116);
+            if self.combo_presses_ok(&pr.original, &self.combo_press_times(pr)) {
+                matched.push(i);
+            }
+        }
 
-        let keys = parse_trigger("POWER", &map, RuleType::Click);
-        assert_eq!(keys, vec![116]);
-    }
+        // A larger chord wins over its sub-chords: defer while a superset is still viable.
+        let winners: Vec<usize> = matched
+            .iter()
+            .copied()
+            .filter(|&i| {
+                let keys = &self.parsed_rules[i].trigger_keys;
+                !self.parsed_rules.iter().any(|other| {
+                    other.original.enabled
+                        && other.original.rule_type == rtype
+                        && !self.triggered_rules.contains(&other.original.id)
+                        && is_strict_subset(keys, &other.trigger_keys)
+                        && self.combo_still_viable(other, now)
+                })
+            })
+            .collect();
 
-    #[test]
-    fn parse_trigger_combo_should_require_exactly_two_keys() {
-        let map = HashMap::new();
-        let keys = parse_trigger("115+114+113", &map, RuleType::ComboClick);
-        assert!(keys.is_empty());
+        for i in winners {
+            let pr = &self.parsed_rules[i];
+            let (rule_id, action) = (pr.original.id.clone(), pr.original.action.clone());
+            let keys = pr.trigger_keys.clone();
+            self.triggered_rules.insert(rule_id.clone());
+            for sub in &self.parsed_rules {
+                if sub.original.rule_type == rtype && is_strict_subset(&sub.trigger_keys, &keys) {
+                    self.triggered_rules.insert(sub.original.id.clone());
+                }
+            }
+            // A held back chord leaves its keys to the single-key rules
+            if !self.fire(&rule_id, action, now, &mut actions) {
+                continue;
+            }
+            for key in &keys {
+                self.consumed_keys.insert(*key);
+                if let Some(state) = self.key_states.get_mut(key) {
+                    match rtype {
+                        RuleType::ComboShortPress => state.triggered_short_press = true,
+                        RuleType::ComboLongPress => state.triggered_long_press = true,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        actions
+    }
+
+    /// A combo can still complete if the keys held so far keep its press constraints and
+    /// either all of them are down or the window is still open for the rest.
+    fn combo_still_viable(&self, pr: &ParsedRule, now: Instant) -> bool {
+        let times = self.combo_press_times(pr);
+        if times.iter().all(Option::is_none) || !self.combo_presses_ok(&pr.original, &times) {
+            return false;
+        }
+        if times.iter().all(Option::is_some) {
+            return true;
+        }
+        // Only the modifier is down: the window has not started yet
+        combo_window_start(&pr.original, &times)
+            .is_none_or(|start| now.duration_since(start) <= self.combo_timeout(&pr.original))
+    }
+
+    /// Press time of each combo key in trigger order, None for keys not down.
+    fn combo_press_times(&self, pr: &ParsedRule) -> Vec<Option<Instant>> {
+        pr.trigger_keys
+            .iter()
+            .map(|k| self.key_states.get(k).map(|s| s.pressed_at))
+            .collect()
+    }
+
+    /// Whether the keys down so far (`times` in trigger order) were pressed the way the
+    /// combo requires: in trigger order for ordered combos, the second key only after the
+    /// modifier has been held `modifier_hold_ms`, and the remaining keys within the window.
+    fn combo_presses_ok(&self, rule: &Rule, times: &[Option<Instant>]) -> bool {
+        let held: Vec<Instant> = times.iter().flatten().copied().collect();
+        if rule.ordered {
+            let prefix = times.iter().take_while(|t| t.is_some()).count();
+            if prefix != held.len() || held.windows(2).any(|w| w[1] < w[0]) {
+                return false;
+            }
+        }
+        let windowed = match rule.modifier_hold_ms {
+            Some(ms) => {
+                if let (Some(first), Some(second)) = (held.first(), held.get(1)) {
+                    if second.duration_since(*first) < Duration::from_millis(ms as u64) {
+                        return false;
+                    }
+                }
+                &held[held.len().min(1)..]
+            }
+            None => &held[..],
+        };
+        windowed.len() <= 1 || within_window(windowed, self.combo_timeout(rule))
+    }
+
+    fn check_combo_release(
+        &mut self,
+        key_code: u16,
+        released_pressed_at: Instant,
+        now: Instant,
+    ) -> Vec<Action> {
+        let mut matched: Vec<usize> = Vec::new();
+        for (i, pr) in self.parsed_rules.iter().enumerate() {
+            if !(pr.original.enabled
+                && pr.original.rule_type == RuleType::ComboClick
+                && pr.trigger_keys.len() >= 2
+                && pr.trigger_keys.contains(&key_code))
+            {
+                continue;
+            }
+            if self.triggered_rules.contains(&pr.original.id) {
+                continue;
+            }
+
+            let mut pressed_times = Vec::with_capacity(pr.trigger_keys.len());
+            let mut valid_combo = true;
+            let short_threshold = self.short_threshold(&pr.original);
+
+            for (idx, k) in pr.trigger_keys.iter().enumerate() {
+                let pressed_at = if *k == key_code {
+                    released_pressed_at
+                } else if let Some(state) = self.key_states.get(k) {
+                    state.pressed_at
+                } else {
+                    valid_combo = false;
+                    break;
+                };
+                // A modifier is held on purpose; only the other keys have to be tapped
+                let is_modifier = idx == 0 && pr.original.modifier_hold_ms.is_some();
+                if !is_modifier && now.duration_since(pressed_at) >= short_threshold {
+                    valid_combo = false;
+                    break;
+                }
+                pressed_times.push(Some(pressed_at));
+            }
+
+            if valid_combo && self.combo_presses_ok(&pr.original, &pressed_times) {
+                matched.push(i);
+            }
+        }
+
+        // When a chord and one of its sub-chords both match, only the larger chord fires.
+        let winners: Vec<usize> = matched
+            .iter()
+            .copied()
+            .filter(|&i| {
+                !matched.iter().any(|&j| {
+                    is_strict_subset(
+                        &self.parsed_rules[i].trigger_keys,
+                        &self.parsed_rules[j].trigger_keys,
+                    )
+                })
+            })
+            .collect();
+
+        let mut actions = Vec::new();
+        for i in winners {
+            let pr = &self.parsed_rules[i];
+            let (rule_id, action) = (pr.original.id.clone(), pr.original.action.clone());
+            let keys = pr.trigger_keys.clone();
+            self.triggered_rules.insert(rule_id.clone());
+            // Mark all keys in this combo as consumed, unless the combo was held back
+            if self.fire(&rule_id, action, now, &mut actions) {
+                self.consumed_keys.extend(keys);
+            }
+        }
+        actions
+    }
+
+    fn short_threshold(&self, rule: &Rule) -> Duration {
+        override_or(
+            rule.timing.short_press_threshold_ms,
+            self.short_press_threshold,
+        )
+    }
+
+    fn long_threshold(&self, rule: &Rule) -> Duration {
+        override_or(
+            rule.timing.long_press_threshold_ms,
+            self.long_press_threshold,
+        )
+    }
+
+    fn combo_timeout(&self, rule: &Rule) -> Duration {
+        override_or(rule.timing.combination_timeout_ms, self.combination_timeout)
+    }
+
+    /// Longest hold that still counts as a tap for any tap-style rule on this key.
+    fn tap_threshold(&self, key_code: u16) -> Duration {
+        self.parsed_rules
+            .iter()
+            .filter(|pr| {
+                pr.original.enabled
+                    && pr.trigger_keys.contains(&key_code)
+                    && matches!(
+                        pr.original.rule_type,
+                        RuleType::Click
+                            | RuleType::DoubleClick
+                            | RuleType::MultiClick
+                            | RuleType::ComboClick
+                            | RuleType::Sequence
+                    )
+            })
+            .map(|pr| self.short_threshold(&pr.original))
+            .max()
+            .unwrap_or(self.short_press_threshold)
+    }
+
+    /// Longest multi-tap interval configured among the tap rules on this key.
+    fn tap_interval(&self, key_code: u16) -> Duration {
+        self.parsed_rules
+            .iter()
+            .filter(|pr| {
+                pr.original.enabled
+                    && pr.trigger_keys == [key_code]
+                    && pr.original.tap_count().is_some()
+            })
+            .map(|pr| {
+                override_or(
+                    pr.original.timing.double_tap_interval_ms,
+                    self.double_tap_interval,
+                )
+            })
+            .max()
+            .unwrap_or(self.double_tap_interval)
+    }
+
+    /// Enabled PATTERN rules on the key whose pattern `holds` is a prefix of (or equal to,
+    /// with `exact`), each press classified by the rule's short_press_threshold_ms.
+    fn pattern_candidates(
+        &self,
+        key_code: u16,
+        holds: &[Duration],
+        exact: bool,
+    ) -> Vec<&ParsedRule> {
+        self.parsed_rules
+            .iter()
+            .filter(|pr| {
+                pr.original.enabled
+                    && pr.original.rule_type == RuleType::Pattern
+                    && pr.trigger_keys == [key_code]
+            })
+            .filter(|pr| {
+                let pattern = pr.original.pattern.as_deref().unwrap_or_default();
+                let short = self.short_threshold(&pr.original);
+                let symbols = holds.iter().map(|h| if *h < short { '.' } else { '-' });
+                let len_ok = if exact {
+                    pattern.len() == holds.len()
+                } else {
+                    pattern.len() >= holds.len()
+                };
+                len_ok && pattern.chars().zip(symbols).all(|(p, s)| p == s)
+            })
+            .collect()
+    }
+
+    fn is_pattern_key(&self, key_code: u16) -> bool {
+        self.parsed_rules.iter().any(|pr| {
+            pr.original.enabled
+                && pr.original.rule_type == RuleType::Pattern
+                && pr.trigger_keys == [key_code]
+        })
+    }
+
+    fn pattern_gap(&self, key_code: u16) -> Duration {
+        self.parsed_rules
+            .iter()
+            .filter(|pr| {
+                pr.original.rule_type == RuleType::Pattern && pr.trigger_keys == [key_code]
+            })
+            .map(|pr| override_or(pr.original.timing.sequence_gap_ms, self.sequence_gap))
+            .max()
+            .unwrap_or(self.sequence_gap)
+    }
+
+    /// Add a press of `hold` to the key's pattern. Fires as soon as no longer pattern can
+    /// match anymore; otherwise waits for the gap. Returns false for non-PATTERN keys.
+    fn handle_pattern_press(
+        &mut self,
+        key_code: u16,
+        hold: Duration,
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) -> bool {
+        if !self.is_pattern_key(key_code) {
+            return false;
+        }
+
+        let mut holds = Vec::new();
+        if let Some(input) = self.pattern_inputs.remove(&key_code) {
+            // Pressed again after the gap (it is not settled while the key is down)
+            let pressed_at = now - hold;
+            if pressed_at.duration_since(input.released_at) > self.pattern_gap(key_code) {
+                self.resolve_pattern(key_code, &input.holds, now, actions);
+            } else {
+                holds = input.holds;
+            }
+        }
+
+        holds.push(hold);
+        if self.pattern_candidates(key_code, &holds, false).is_empty() {
+            // The press breaks the pattern in progress; it may still start a new one
+            holds.pop();
+            self.resolve_pattern(key_code, &holds, now, actions);
+            holds = vec![hold];
+        }
+
+        let candidates = self.pattern_candidates(key_code, &holds, false);
+        if candidates.is_empty() {
+            return true;
+        }
+        if candidates.iter().all(|pr| {
+            pr.original
+                .pattern
+                .as_ref()
+                .is_some_and(|p| p.len() == holds.len())
+        }) {
+            self.resolve_pattern(key_code, &holds, now, actions);
+        } else {
+            self.pattern_inputs.insert(
+                key_code,
+                PatternInput {
+                    holds,
+                    released_at: now,
+                },
+            );
+        }
+        true
+    }
+
+    /// Fire the PATTERN rules matching `holds` exactly; a pattern matching none is dropped.
+    fn resolve_pattern(
+        &mut self,
+        key_code: u16,
+        holds: &[Duration],
+        now: Instant,
+        actions: &mut Vec<Action>,
+    ) {
+        if holds.is_empty() {
+            return;
+        }
+        let fired: Vec<(String, Action)> = self
+            .pattern_candidates(key_code, holds, true)
+            .iter()
+            .map(|pr| (pr.original.id.clone(), pr.original.action.clone()))
+            .collect();
+        for (rule_id, action) in fired {
+            self.fire(&rule_id, action, now, actions);
+        }
+    }
+
+    /// Longest step gap among the SEQUENCE rules the buffered taps could still complete.
+    fn pending_sequence_gap(&self) -> Duration {
+        let steps: Vec<u16> = self.sequence_buffer.iter().map(|t| t.key_code).collect();
+        self.sequence_candidates(&steps)
+            .iter()
+            .map(|pr| override_or(pr.original.timing.sequence_gap_ms, self.sequence_gap))
+            .max()
+            .unwrap_or(self.sequence_gap)
+    }
+
+    /// Earliest instant at which `tick` may emit something or change state, or None when
+    /// nothing is pending. Checks that compare with `>` report a deadline just past the bound.
+    /// Deadlines already covered by a tick since the last key event are skipped, so a
+    /// condition that is due but blocked does not make the caller spin.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let mut deadlines: Vec<Instant> = Vec::new();
+
+        if let Some(pending) = self.pending_tap_hold.as_ref() {
+            deadlines.push(pending.pressed_at + pending.tapping_term);
+        }
+
+        for (key_code, state) in &self.key_states {
+            if self.consumed_keys.contains(key_code) {
+                continue;
+            }
+            for pr in &self.parsed_rules {
+                if !pr.original.enabled
+                    || pr.original.on_release
+                    || pr.trigger_keys != [*key_code]
+                    || self.triggered_rules.contains(&pr.original.id)
+                {
+                    continue;
+                }
+                match pr.original.rule_type {
+                    RuleType::ShortPress => {
+                        deadlines.push(state.pressed_at + self.short_threshold(&pr.original))
+                    }
+                    RuleType::LongPress => {
+                        deadlines.push(state.pressed_at + self.long_threshold(&pr.original))
+                    }
+                    _ => {}
+                }
+            }
+            if !state.triggered_short_press
+                && !state.triggered_long_press
+                && !self.passthrough_down.contains(key_code)
+                && self.is_passthrough_key(*key_code)
+            {
+                if let Some(deadline) = self.passthrough_deadline(*key_code) {
+                    deadlines.push(state.pressed_at + deadline);
+                }
+            }
+        }
+
+        for pr in &self.parsed_rules {
+            let threshold = match pr.original.rule_type {
+                RuleType::ComboShortPress => self.short_threshold(&pr.original),
+                RuleType::ComboLongPress => self.long_threshold(&pr.original),
+                _ => continue,
+            };
+            if !pr.original.enabled || self.triggered_rules.contains(&pr.original.id) {
+                continue;
+            }
+            let times = self.combo_press_times(pr);
+            if times.iter().all(Option::is_some) {
+                // All keys down: fires once the last one has been held long enough
+                if let Some(last) = times.iter().flatten().max() {
+                    deadlines.push(*last + threshold);
+                }
+            } else if let Some(start) = combo_window_start(&pr.original, &times) {
+                // Partly down: stops holding back its sub-chords when the window closes
+                deadlines.push(start + self.combo_timeout(&pr.original) + JUST_AFTER);
+            }
+        }
+
+        deadlines.extend(self.hold_repeats.values().map(|r| r.next_at));
+        if let Some(last) = self.sequence_buffer.last() {
+            deadlines.push(last.released_at + self.pending_sequence_gap() + JUST_AFTER);
+        }
+        for (key_code, input) in &self.pattern_inputs {
+            if !self.key_states.contains_key(key_code) {
+                deadlines.push(input.released_at + self.pattern_gap(*key_code) + JUST_AFTER);
+            }
+        }
+        deadlines.extend(self.pending_clicks.iter().map(|p| p.available_at));
+        deadlines.extend(self.passthrough_queue.iter().map(|p| p.available_at));
+
+        deadlines
+            .into_iter()
+            .filter(|d| self.ticked_at.is_none_or(|t| *d > t))
+            .min()
+    }
+
+    pub fn update_repeat_policies(&mut self, policies: HashMap<u16, RepeatPolicy>) {
+        self.key_repeat_policy = policies;
+    }
+
+    /// Fire the enabled EXTERNAL rules matching a posted event.
+    pub fn handle_external(&mut self, event: &ExternalEvent) -> Vec<Action> {
+        self.handle_external_at(event, Instant::now())
+    }
+
+    /// `handle_external` with an explicit event time instead of the current time.
+    pub fn handle_external_at(&mut self, event: &ExternalEvent, now: Instant) -> Vec<Action> {
+        let matched: Vec<(String, Action)> = self
+            .parsed_rules
+            .iter()
+            .map(|pr| &pr.original)
+            .filter(|rule| rule.enabled && rule.rule_type == RuleType::External)
+            .filter(|rule| {
+                let (source, name) = rule.external_trigger();
+                event.matches(source, name, &rule.payload)
+            })
+            .map(|rule| (rule.id.clone(), rule.action.clone()))
+            .collect();
+        if matched.is_empty() {
+            debug!("No EXTERNAL rule for {}:{}", event.source, event.event);
+        }
+
+        let mut actions = Vec::new();
+        for (rule_id, action) in matched {
+            self.fire(&rule_id, action, now, &mut actions);
+        }
+        self.apply_layer_actions(actions)
+    }
+
+    /// Decide what an auto-repeat (value 2) event of a mapped key turns into.
+    pub fn handle_repeat(&mut self, key_code: u16) -> RepeatOutcome {
+        self.handle_repeat_at(key_code, Instant::now())
+    }
+
+    /// `handle_repeat` with an explicit event time instead of the current time.
+    pub fn handle_repeat_at(&mut self, key_code: u16, now: Instant) -> RepeatOutcome {
+        match self.repeat_policy(key_code) {
+            RepeatPolicy::Drop => RepeatOutcome::Drop,
+            RepeatPolicy::Forward => RepeatOutcome::Forward,
+            RepeatPolicy::ForwardIfPassedThrough if self.passthrough_down.contains(&key_code) => {
+                RepeatOutcome::Forward
+            }
+            RepeatPolicy::ForwardIfPassedThrough => RepeatOutcome::Drop,
+            RepeatPolicy::Trigger => {
+                let fired: Vec<(String, Action)> = self
+                    .parsed_rules
+                    .iter()
+                    .filter(|pr| {
+                        pr.original.enabled
+                            && pr.original.repeat_policy == Some(RepeatPolicy::Trigger)
+                            && pr.trigger_keys.contains(&key_code)
+                            && self.triggered_rules.contains(&pr.original.id)
+                    })
+                    .map(|pr| (pr.original.id.clone(), pr.original.action.clone()))
+                    .collect();
+                let mut actions = Vec::new();
+                for (rule_id, action) in fired {
+                    self.fire(&rule_id, action, now, &mut actions);
+                }
+                if actions.is_empty() {
+                    RepeatOutcome::Drop
+                } else {
+                    RepeatOutcome::Fire(self.apply_layer_actions(actions))
+                }
+            }
+        }
+    }
+
+    /// Policy of the rule that fired on the key, else of any rule on the key, else the
+    /// per-key policy. Keys whose hold rules repeat on their own never take raw repeats.
+    fn repeat_policy(&self, key_code: u16) -> RepeatPolicy {
+        let rules: Vec<&Rule> = self
+            .parsed_rules
+            .iter()
+            .filter(|pr| pr.original.enabled && pr.trigger_keys.contains(&key_code))
+            .map(|pr| &pr.original)
+            .collect();
+        if rules.iter().any(|r| r.repeat.is_some()) {
+            return RepeatPolicy::Drop;
+        }
+        rules
+            .iter()
+            .filter(|r| r.repeat_policy.is_some())
+            .find(|r| self.triggered_rules.contains(&r.id))
+            .or_else(|| rules.iter().find(|r| r.repeat_policy.is_some()))
+            .and_then(|r| r.repeat_policy)
+            .or_else(|| self.key_repeat_policy.get(&key_code).copied())
+            .unwrap_or_default()
+    }
+
+    /// Keys still held keep going through the state machine even if a layer change
+    /// unmapped them, so their UP is seen.
+    pub fn is_mapped(&self, key_code: u16) -> bool {
+        self.mapped_keys.contains(&key_code) || self.key_states.contains_key(&key_code)
+    }
+}
+
+/// Span `max_per_minute` counts fires over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Offset for deadlines of strict (`>`) time comparisons.
+const JUST_AFTER: Duration = Duration::from_nanos(1);
+
+fn invert_map(hardware_map: &HashMap<u16, String>) -> HashMap<String, u16> {
+    hardware_map.iter().map(|(k, v)| (v.clone(), *k)).collect()
+}
+
+fn parse_rules(rules: Vec<Rule>, name_to_code: &HashMap<String, u16>) -> Vec<ParsedRule> {
+    rules
+        .into_iter()
+        .map(|r| {
+            let keys = parse_trigger(&r.trigger, name_to_code, r.rule_type);
+            ParsedRule {
+                original: r,
+                trigger_keys: keys,
+            }
+        })
+        .collect()
+}
+
+fn parse_trigger(trigger: &str, map: &HashMap<String, u16>, rule_type: RuleType) -> Vec<u16> {
+    let parse_token = |token: &str| resolve_key(token, map);
+
+    match rule_type {
+        RuleType::ComboClick | RuleType::ComboShortPress | RuleType::ComboLongPress => {
+            let parts: Vec<&str> = trigger
+                .split('+')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .collect();
+            if parts.len() < 2 {
+                return Vec::new();
+            }
+
+            let mut keys: Vec<u16> = Vec::with_capacity(parts.len());
+            for part in parts {
+                match parse_token(part) {
+                    Some(code) if !keys.contains(&code) => keys.push(code),
+                    _ => return Vec::new(),
+                }
+            }
+            keys
+        }
+        RuleType::Sequence => {
+            let steps: Option<Vec<u16>> = trigger.split("->").map(parse_token).collect();
+            match steps {
+                Some(steps) if steps.len() >= 2 => steps,
+                _ => Vec::new(),
+            }
+        }
+        // Names an event source, not a key
+        RuleType::External => Vec::new(),
+        _ => parse_token(trigger)
+            .map(|code| vec![code])
+            .unwrap_or_default(),
+    }
+}
+
+fn override_or(override_ms: Option<u32>, default: Duration) -> Duration {
+    override_ms
+        .map(|ms| Duration::from_millis(ms as u64))
+        .unwrap_or(default)
+}
+
+fn within_window(times: &[Instant], window: Duration) -> bool {
+    match (times.iter().min(), times.iter().max()) {
+        (Some(min), Some(max)) => max.duration_since(*min) <= window,
+        _ => false,
+    }
+}
+
+/// Earliest press among the combo keys that must fall in the window (all but the modifier).
+fn combo_window_start(rule: &Rule, times: &[Option<Instant>]) -> Option<Instant> {
+    let skip = usize::from(rule.modifier_hold_ms.is_some());
+    times.iter().skip(skip).flatten().min().copied()
+}
+
+fn is_strict_subset(keys: &[u16], of: &[u16]) -> bool {
+    keys.len() < of.len() && keys.iter().all(|k| of.contains(k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Condition, Layer, RuleTiming, TapHold};
+    use crate::event::conditions::tests::friday_at;
+
+    fn make_rule(id: &str, trigger: &str, rule_type: RuleType, action: Action) -> Rule {
+        Rule {
+            id: id.to_string(),
+            trigger: trigger.to_string(),
+            rule_type,
+            action,
+            enabled: true,
+            description: String::new(),
+            sequence_fallback: None,
+            count: None,
+            timing: RuleTiming::default(),
+            repeat: None,
+            tap_hold: None,
+            passthrough: false,
+            on_release: false,
+            repeat_policy: None,
+            ordered: false,
+            modifier_hold_ms: None,
+            cooldown_ms: None,
+            max_per_minute: None,
+            pattern: None,
+            conditions: Vec::new(),
+            payload: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn combo_release_should_not_trigger_single_click_after_combo_consumed() {
+        let rules = vec![
+            make_rule(
+                "combo_click",
+                "115+114",
+                RuleType::ComboClick,
+                Action::SendKey { key_code: 42 },
+            ),
+            make_rule(
+                "single_click",
+                "115",
+                RuleType::Click,
+                Action::SendKey { key_code: 24 },
+            ),
+        ];
+
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        assert!(sm.handle_key(115, 1).is_empty());
+        assert!(sm.handle_key(114, 1).is_empty());
+
+        let combo_actions = sm.handle_key(114, 0);
+        assert_eq!(combo_actions.len(), 1);
+        assert_eq!(combo_actions[0], Action::SendKey { key_code: 42 });
+
+        let release_first_key_actions = sm.handle_key(115, 0);
+        assert!(
+            release_first_key_actions.is_empty(),
+            "single click should be suppressed after combo is consumed"
+        );
+    }
+
+    #[test]
+    fn parse_trigger_should_support_named_single_key() {
+        let mut map = HashMap::new();
+        map.insert("POWER".to_string(), 116);
+
+        let keys = parse_trigger("POWER", &map, RuleType::Click);
+        assert_eq!(keys, vec![116]);
+    }
+
+    #[test]
+    fn parse_trigger_combo_should_accept_three_keys() {
+        let mut map = HashMap::new();
+        map.insert("POWER".to_string(), 116);
+        let keys = parse_trigger("115+114+POWER", &map, RuleType::ComboClick);
+        assert_eq!(keys, vec![115, 114, 116]);
+    }
+
+    #[test]
+    fn parse_trigger_combo_should_reject_single_or_duplicate_keys() {
+        let map = HashMap::new();
+        assert!(parse_trigger("115", &map, RuleType::ComboClick).is_empty());
+        assert!(parse_trigger("115+114+115", &map, RuleType::ComboClick).is_empty());
+    }
+
+    #[test]
+    fn three_key_combo_release_should_win_over_sub_chord() {
+        let rules = vec![
+            make_rule(
+                "pair",
+                "115+114",
+                RuleType::ComboClick,
+                Action::SendKey { key_code: 1 },
+            ),
+            make_rule(
+                "triple",
+                "115+114+116",
+                RuleType::ComboClick,
+                Action::SendKey { key_code: 2 },
+            ),
+        ];
+
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        assert!(sm.handle_key(115, 1).is_empty());
+        assert!(sm.handle_key(114, 1).is_empty());
+        assert!(sm.handle_key(116, 1).is_empty());
+
+        let actions = sm.handle_key(115, 0);
+        assert_eq!(actions, vec![Action::SendKey { key_code: 2 }]);
+        assert!(sm.handle_key(114, 0).is_empty());
+        assert!(sm.handle_key(116, 0).is_empty());
+    }
+
+    #[test]
+    fn three_key_combo_hold_should_suppress_sub_chord() {
+        let rules = vec![
+            make_rule(
+                "pair",
+                "115+114",
+                RuleType::ComboShortPress,
+                Action::SendKey { key_code: 1 },
+            ),
+            make_rule(
+                "triple",
+                "115+114+116",
+                RuleType::ComboShortPress,
+                Action::SendKey { key_code: 2 },
+            ),
+        ];
+
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 1, 300, 200, 500);
+
+        let t0 = Instant::now();
+        sm.handle_key_at(115, 1, t0);
+        sm.handle_key_at(114, 1, t0);
+        sm.handle_key_at(116, 1, t0);
+
+        let at = t0 + Duration::from_millis(5);
+        let actions = sm.tick_at(at);
+        assert_eq!(actions, vec![Action::SendKey { key_code: 2 }]);
+        assert!(
+            sm.tick_at(at).is_empty(),
+            "sub-chord must not fire after the larger chord"
+        );
+    }
+
+    #[test]
+    fn combo_hold_should_keep_its_keys_from_single_key_holds() {
+        let rules = vec![
+            make_rule(
+                "pair",
+                "115+114",
+                RuleType::ComboShortPress,
+                Action::SendKey { key_code: 1 },
+            ),
+            make_rule(
+                "vol_long",
+                "115",
+                RuleType::LongPress,
+                Action::SendKey { key_code: 2 },
+            ),
+        ];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 20, 1, 300, 200, 500);
+        let t0 = Instant::now();
+
+        sm.handle_key_at(115, 1, t0);
+        sm.handle_key_at(114, 1, t0);
+        assert_eq!(
+            sm.tick_at(t0 + Duration::from_millis(5)),
+            vec![Action::SendKey { key_code: 1 }]
+        );
+        assert!(
+            sm.tick_at(t0 + Duration::from_millis(30)).is_empty(),
+            "the chord's key must not fire its long press"
+        );
+    }
+
+    fn tap(sm: &mut StateMachine, key_code: u16) -> Vec<Action> {
+        let mut actions = sm.handle_key(key_code, 1);
+        actions.extend(sm.handle_key(key_code, 0));
+        actions
+    }
+
+    /// A 1 ms tap pressed at `at`.
+    fn tap_at(sm: &mut StateMachine, key_code: u16, at: Instant) -> Vec<Action> {
+        let mut actions = sm.handle_key_at(key_code, 1, at);
+        actions.extend(sm.handle_key_at(key_code, 0, at + Duration::from_millis(1)));
+        actions
+    }
+
+    #[test]
+    fn remap_should_release_the_pressed_target_across_a_config_reload() {
+        let mut sm = StateMachine::new(vec![], HashMap::new(), 500, 20, 300, 200, 500);
+        sm.update_layers(HashMap::from([(115, 30), (114, 48)]), &[], &HashMap::new());
+
+        assert_eq!(sm.remap_event(115, 1), Some(30));
+        assert_eq!(sm.remap_event(114, 1), Some(48));
+
+        // 115 now maps elsewhere and 114 is no longer remapped
+        sm.update_layers(HashMap::from([(115, 31)]), &[], &HashMap::new());
+        assert_eq!(sm.remap_event(115, 2), Some(30));
+        assert_eq!(sm.remap_event(115, 0), Some(30));
+        assert_eq!(sm.remap_event(114, 0), Some(48));
+
+        assert_eq!(sm.remap_event(115, 1), Some(31));
+        assert_eq!(sm.remap_event(114, 1), None);
+    }
+
+    #[test]
+    fn parse_trigger_sequence_should_allow_repeated_steps() {
+        let mut map = HashMap::new();
+        map.insert("VOL_UP".to_string(), 115);
+        map.insert("VOL_DOWN".to_string(), 114);
+
+        let keys = parse_trigger("VOL_UP->VOL_UP->VOL_DOWN", &map, RuleType::Sequence);
+        assert_eq!(keys, vec![115, 115, 114]);
+        assert!(parse_trigger("VOL_UP", &map, RuleType::Sequence).is_empty());
+    }
+
+    #[test]
+    fn sequence_should_fire_when_steps_complete() {
+        let rules = vec![make_rule(
+            "seq",
+            "115->115->114",
+            RuleType::Sequence,
+            Action::SendKey { key_code: 99 },
+        )];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        assert!(tap(&mut sm, 115).is_empty());
+        assert!(tap(&mut sm, 115).is_empty());
+        assert_eq!(tap(&mut sm, 114), vec![Action::SendKey { key_code: 99 }]);
+        assert!(sm.tick().is_empty());
+    }
+
+    #[test]
+    fn broken_sequence_should_replay_or_drop_buffered_taps() {
+        let mut replay = make_rule(
+            "seq",
+            "115->114",
+            RuleType::Sequence,
+            Action::SendKey { key_code: 99 },
+        );
+        let mut sm = StateMachine::new(
+            vec![replay.clone()],
+            HashMap::new(),
+            800,
+            300,
+            300,
+            200,
+            500,
+        );
+
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        sm.handle_key_at(115, 1, at(0));
+        assert!(sm.handle_key_at(115, 0, at(40)).is_empty());
+        // 116 breaks the sequence: the buffered 115 goes back to the system, held as long.
+        assert_eq!(
+            sm.handle_key_at(116, 1, at(100)),
+            vec![Action::KeyDown { key_code: 115 }]
+        );
+        assert_eq!(sm.tick_at(at(140)), vec![Action::KeyUp { key_code: 115 }]);
+
+        replay.sequence_fallback = Some(SequenceFallback::Drop);
+        let mut sm = StateMachine::new(vec![replay], HashMap::new(), 800, 300, 300, 200, 500);
+        assert!(tap(&mut sm, 115).is_empty());
+        assert!(sm.handle_key(116, 1).is_empty());
+    }
+
+    #[test]
+    fn single_double_and_triple_click_should_share_one_key() {
+        let mut triple = make_rule(
+            "triple",
+            "115",
+            RuleType::MultiClick,
+            Action::SendKey { key_code: 3 },
+        );
+        triple.count = Some(3);
+        let rules = vec![
+            make_rule(
+                "single",
+                "115",
+                RuleType::Click,
+                Action::SendKey { key_code: 1 },
+            ),
+            make_rule(
+                "double",
+                "115",
+                RuleType::DoubleClick,
+                Action::SendKey { key_code: 2 },
+            ),
+            triple,
+        ];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        assert!(tap(&mut sm, 115).is_empty());
+        assert!(tap(&mut sm, 115).is_empty());
+        assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 3 }]);
+        assert!(sm.pending_clicks.is_empty(), "no ghost single/double click");
+        assert!(sm.tap_history.is_empty());
+    }
+
+    #[test]
+    fn rapid_single_clicks_should_each_fire_without_multi_tap_rules() {
+        let rules = vec![make_rule(
+            "single",
+            "115",
+            RuleType::Click,
+            Action::SendKey { key_code: 1 },
+        )];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        for _ in 0..3 {
+            assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 1 }]);
+        }
+        assert!(sm.tick().is_empty());
+    }
+
+    #[test]
+    fn multi_click_should_defer_shorter_counts() {
+        let mut triple = make_rule(
+            "triple",
+            "115",
+            RuleType::MultiClick,
+            Action::SendKey { key_code: 3 },
+        );
+        triple.count = Some(3);
+        let rules = vec![
+            make_rule(
+                "double",
+                "115",
+                RuleType::DoubleClick,
+                Action::SendKey { key_code: 2 },
+            ),
+            triple,
+        ];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 20, 200, 500);
+
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        assert!(tap_at(&mut sm, 115, at(0)).is_empty());
+        assert!(tap_at(&mut sm, 115, at(5)).is_empty());
+        assert_eq!(sm.pending_clicks.len(), 1);
+
+        assert_eq!(sm.tick_at(at(30)), vec![Action::SendKey { key_code: 2 }]);
+    }
+
+    #[test]
+    fn per_rule_long_press_override_should_not_affect_other_keys() {
+        let mut power = make_rule(
+            "power_long",
+            "116",
+            RuleType::LongPress,
+            Action::SendKey { key_code: 1 },
+        );
+        power.timing.long_press_threshold_ms = Some(2000);
+        let rules = vec![
+            power,
+            make_rule(
+                "vol_long",
+                "115",
+                RuleType::LongPress,
+                Action::SendKey { key_code: 2 },
+            ),
+        ];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 5, 1, 300, 200, 500);
+
+        let t0 = Instant::now();
+        sm.handle_key_at(116, 1, t0);
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(
+            sm.tick_at(t0 + Duration::from_millis(10)),
+            vec![Action::SendKey { key_code: 2 }]
+        );
+    }
+
+    #[test]
+    fn per_rule_short_threshold_should_extend_click_window() {
+        let mut click = make_rule(
+            "click",
+            "115",
+            RuleType::Click,
+            Action::SendKey { key_code: 1 },
+        );
+        click.timing.short_press_threshold_ms = Some(1000);
+        let mut sm = StateMachine::new(vec![click], HashMap::new(), 2000, 1, 300, 200, 500);
+
+        let t0 = Instant::now();
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(
+            sm.handle_key_at(115, 0, t0 + Duration::from_millis(5)),
+            vec![Action::SendKey { key_code: 1 }]
+        );
+    }
+
+    #[test]
+    fn hold_repeat_should_fire_until_release_and_follow_acceleration() {
+        let mut rule = make_rule(
+            "vol_repeat",
+            "115",
+            RuleType::ShortPress,
+            Action::SendKey { key_code: 115 },
+        );
+        rule.repeat = Some(HoldRepeat {
+            interval_ms: 5,
+            acceleration_ms: vec![40],
+        });
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 1, 300, 200, 500);
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Drop);
+
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(sm.tick_at(at(3)).len(), 1, "initial fire at the threshold");
+        assert!(
+            sm.tick_at(at(3)).is_empty(),
+            "first repeat waits for the 40 ms step"
+        );
+
+        assert_eq!(sm.tick_at(at(48)).len(), 1);
+        assert_eq!(
+            sm.tick_at(at(58)).len(),
+            1,
+            "steady interval after the curve"
+        );
+
+        sm.handle_key_at(115, 0, at(58));
+        assert!(sm.tick_at(at(68)).is_empty(), "repeat stops on release");
+    }
+
+    #[test]
+    fn hold_repeat_should_stop_once_the_rate_limit_holds_it_back() {
+        let mut rule = make_rule("vol_repeat", "115", RuleType::ShortPress, send(1));
+        rule.repeat = Some(HoldRepeat {
+            interval_ms: 10,
+            acceleration_ms: Vec::new(),
+        });
+        rule.max_per_minute = Some(3);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 1, 300, 200, 500);
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(sm.tick_at(at(2)), vec![send(1)]);
+        assert_eq!(sm.tick_at(at(12)), vec![send(1)]);
+        assert_eq!(sm.tick_at(at(22)), vec![send(1)]);
+        assert!(sm.tick_at(at(32)).is_empty());
+        assert!(sm.tick_at(at(42)).is_empty());
+        assert_eq!(sm.suppressed_fires().get("vol_repeat"), Some(&1));
+    }
+
+    #[test]
+    fn repeat_should_only_be_forwarded_for_passed_through_presses_by_default() {
+        let mut sm = passthrough_machine("115", RuleType::Click);
+        let t0 = Instant::now();
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Drop);
+
+        // Held past the thresholds: the DOWN went to the system, so its repeats follow
+        sm.tick_at(t0 + Duration::from_millis(100));
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Forward);
+
+        let rule = make_rule("long", "115", RuleType::LongPress, send(1));
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 30, 10, 20, 200, 20);
+        sm.handle_key_at(115, 1, t0);
+        sm.tick_at(t0 + Duration::from_millis(100));
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Drop);
+    }
+
+    #[test]
+    fn rule_repeat_policy_should_override_key_policy() {
+        let mut click = make_rule("click", "115", RuleType::Click, send(1));
+        let long = make_rule("long", "114", RuleType::LongPress, send(2));
+        click.repeat_policy = Some(RepeatPolicy::Drop);
+        let mut sm = StateMachine::new(vec![click, long], HashMap::new(), 30, 10, 20, 200, 20);
+        sm.update_repeat_policies(HashMap::from([
+            (115, RepeatPolicy::Forward),
+            (114, RepeatPolicy::Forward),
+        ]));
+
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Drop);
+        assert_eq!(sm.handle_repeat(114), RepeatOutcome::Forward);
+    }
+
+    #[test]
+    fn trigger_repeat_policy_should_fire_once_the_rule_has_fired() {
+        let mut rule = make_rule("short", "115", RuleType::ShortPress, send(1));
+        rule.repeat_policy = Some(RepeatPolicy::Trigger);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 30, 10, 20, 200, 20);
+        let t0 = Instant::now();
+
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Drop);
+        assert_eq!(sm.tick_at(t0 + Duration::from_millis(10)), vec![send(1)]);
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Fire(vec![send(1)]));
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Fire(vec![send(1)]));
+
+        sm.handle_key_at(115, 0, t0 + Duration::from_millis(50));
+        assert_eq!(sm.handle_repeat(115), RepeatOutcome::Drop);
+    }
+
+    #[test]
+    fn cooldown_should_suppress_and_count_fires_in_between() {
+        let mut rule = make_rule("click", "115", RuleType::Click, send(1));
+        rule.cooldown_ms = Some(1000);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        let t0 = Instant::now();
+        let tap_at = |sm: &mut StateMachine, ms: u64| {
+            sm.handle_key_at(115, 1, t0 + Duration::from_millis(ms));
+            sm.handle_key_at(115, 0, t0 + Duration::from_millis(ms + 50))
+        };
+
+        assert_eq!(tap_at(&mut sm, 0), vec![send(1)]);
+        assert!(tap_at(&mut sm, 400).is_empty());
+        assert!(tap_at(&mut sm, 800).is_empty());
+        assert_eq!(tap_at(&mut sm, 1100), vec![send(1)]);
+        assert_eq!(sm.suppressed_fires().get("click"), Some(&2));
+    }
+
+    #[test]
+    fn max_per_minute_should_limit_fires_over_a_sliding_minute() {
+        let mut rule = make_rule("long", "115", RuleType::LongPress, send(1));
+        rule.max_per_minute = Some(2);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        let t0 = Instant::now();
+        let hold_at = |sm: &mut StateMachine, s: u64| {
+            let start = t0 + Duration::from_secs(s);
+            sm.handle_key_at(115, 1, start);
+            let fired = sm.tick_at(start + Duration::from_millis(800));
+            sm.handle_key_at(115, 0, start + Duration::from_millis(900));
+            fired
+        };
+
+        assert_eq!(hold_at(&mut sm, 0), vec![send(1)]);
+        assert_eq!(hold_at(&mut sm, 10), vec![send(1)]);
+        assert!(hold_at(&mut sm, 20).is_empty());
+        assert_eq!(hold_at(&mut sm, 61), vec![send(1)]);
+        assert_eq!(sm.suppressed_fires().get("long"), Some(&1));
+    }
+
+    #[test]
+    fn conditions_should_skip_fires_outside_their_time_range() {
+        let mut rule = make_rule("night", "115", RuleType::Click, send(1));
+        rule.conditions = vec![Condition::TimeRange {
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+        }];
+        rule.cooldown_ms = Some(1000);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        let t0 = Instant::now();
+        let tap_at = |sm: &mut StateMachine, ms: u64| {
+            sm.handle_key_at(115, 1, t0 + Duration::from_millis(ms));
+            sm.handle_key_at(115, 0, t0 + Duration::from_millis(ms + 50))
+        };
+
+        sm.set_context(Arc::new(friday_at(12, 0)));
+        assert!(tap_at(&mut sm, 0).is_empty());
+        assert!(sm.suppressed_fires().is_empty());
+
+        // Without passthrough the skipped tap is swallowed
+        assert!(sm.tick_at(t0 + Duration::from_millis(300)).is_empty());
+
+        // A skipped fire does not start the cooldown
+        sm.set_context(Arc::new(friday_at(23, 30)));
+        assert_eq!(tap_at(&mut sm, 400), vec![send(1)]);
+
+        // With passthrough it goes back to the system
+        let mut rule = make_rule("night", "115", RuleType::Click, send(1));
+        rule.conditions = vec![Condition::TimeRange {
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+        }];
+        rule.passthrough = true;
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        sm.set_context(Arc::new(friday_at(12, 0)));
+        assert_eq!(tap_at(&mut sm, 0), vec![Action::KeyDown { key_code: 115 }]);
+        assert_eq!(
+            sm.tick_at(t0 + Duration::from_millis(100)),
+            vec![Action::KeyUp { key_code: 115 }]
+        );
+    }
+
+    #[test]
+    fn external_events_should_fire_rules_by_source_event_and_payload() {
+        let mut home = make_rule("home", "GEOFENCE:ENTER", RuleType::External, send(1));
+        home.payload = HashMap::from([("location_id".to_string(), "HOME".to_string())]);
+        let any = make_rule("any", "GEOFENCE", RuleType::External, send(2));
+        // A numeric source names no key
+        let numeric = make_rule("numeric", "115", RuleType::External, send(3));
+        let mut sm = StateMachine::new(
+            vec![home, any, numeric],
+            HashMap::new(),
+            800,
+            300,
+            300,
+            200,
+            500,
+        );
+        let event = |source: &str, name: &str, location: &str| ExternalEvent {
+            source: source.to_string(),
+            event: name.to_string(),
+            payload: HashMap::from([("location_id".to_string(), location.to_string())]),
+        };
+
+        assert_eq!(
+            sm.handle_external(&event("GEOFENCE", "ENTER", "HOME")),
+            vec![send(1), send(2)]
+        );
+        assert_eq!(
+            sm.handle_external(&event("GEOFENCE", "ENTER", "WORK")),
+            vec![send(2)]
+        );
+        assert_eq!(
+            sm.handle_external(&event("GEOFENCE", "EXIT", "HOME")),
+            vec![send(2)]
+        );
+        assert!(sm
+            .handle_external(&event("NFC", "ENTER", "HOME"))
+            .is_empty());
+        assert!(!sm.is_mapped(115));
+        assert_eq!(sm.handle_key(115, 1), Vec::new());
+    }
+
+    #[test]
+    fn external_rules_should_respect_conditions_and_cooldown() {
+        let mut rule = make_rule("night", "DOORBELL", RuleType::External, send(1));
+        rule.conditions = vec![Condition::TimeRange {
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+        }];
+        rule.cooldown_ms = Some(1000);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        let ring = ExternalEvent {
+            source: "DOORBELL".to_string(),
+            event: "RING".to_string(),
+            payload: HashMap::new(),
+        };
+        let t0 = Instant::now();
+
+        sm.set_context(Arc::new(friday_at(12, 0)));
+        assert!(sm.handle_external_at(&ring, t0).is_empty());
+        sm.set_context(Arc::new(friday_at(23, 0)));
+        assert_eq!(sm.handle_external_at(&ring, t0), vec![send(1)]);
+        assert!(sm
+            .handle_external_at(&ring, t0 + Duration::from_millis(500))
+            .is_empty());
+        assert_eq!(sm.suppressed_fires().get("night"), Some(&1));
+    }
+
+    fn dual_role_rule(flavor: TapHoldFlavor, tapping_term_ms: u32) -> Rule {
+        let mut rule = make_rule(
+            "dual",
+            "114",
+            RuleType::TapHold,
+            Action::SendKey { key_code: 30 },
+        );
+        rule.tap_hold = Some(TapHold {
+            hold_key: 42,
+            tapping_term_ms: Some(tapping_term_ms),
+            flavor,
+        });
+        rule
+    }
+
+    #[test]
+    fn tap_hold_should_tap_on_quick_release() {
+        let rules = vec![dual_role_rule(TapHoldFlavor::TapPreferred, 200)];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        assert!(sm.handle_key(114, 1).is_empty());
+        assert!(sm.is_deferring());
+        assert_eq!(
+            sm.handle_key(114, 0),
+            vec![Action::SendKey { key_code: 30 }]
+        );
+        assert!(!sm.is_deferring());
+    }
+
+    #[test]
+    fn tap_hold_should_hold_after_tapping_term() {
+        let rules = vec![dual_role_rule(TapHoldFlavor::TapPreferred, 5)];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        let t0 = Instant::now();
+        sm.handle_key_at(114, 1, t0);
+        assert_eq!(
+            sm.tick_at(t0 + Duration::from_millis(10)),
+            vec![Action::KeyDown { key_code: 42 }]
+        );
+        assert_eq!(
+            sm.handle_key_at(114, 0, t0 + Duration::from_millis(15)),
+            vec![Action::KeyUp { key_code: 42 }]
+        );
+    }
+
+    #[test]
+    fn deferred_events_should_replay_with_their_own_times() {
+        let mut click = make_rule("vol_click", "115", RuleType::Click, send(1));
+        click.timing.short_press_threshold_ms = Some(100);
+        let mut press = make_rule("vol_press", "115", RuleType::ShortPress, send(2));
+        press.timing.short_press_threshold_ms = Some(100);
+        press.on_release = true;
+        let rules = vec![
+            dual_role_rule(TapHoldFlavor::TapPreferred, 200),
+            click,
+            press,
+        ];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+
+        sm.handle_key_at(114, 1, at(0));
+        assert!(sm.handle_key_at(115, 1, at(10)).is_empty());
+        assert!(sm.handle_key_at(115, 0, at(150)).is_empty());
+        // 115 was held for 140 ms, not for the instant of the replay
+        assert_eq!(sm.handle_key_at(114, 0, at(180)), vec![send(30), send(2)]);
+    }
+
+    #[test]
+    fn tap_preferred_should_replay_interrupting_key_after_tap() {
+        let rules = vec![dual_role_rule(TapHoldFlavor::TapPreferred, 200)];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        sm.handle_key(114, 1);
+        assert!(sm.handle_key(30, 1).is_empty(), "other key is held back");
+        assert_eq!(
+            sm.handle_key(114, 0),
+            vec![
+                Action::SendKey { key_code: 30 },
+                Action::KeyDown { key_code: 30 },
+            ]
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press_should_hold_before_replaying() {
+        let rules = vec![dual_role_rule(TapHoldFlavor::HoldOnOtherKeyPress, 200)];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        sm.handle_key(114, 1);
+        assert_eq!(
+            sm.handle_key(30, 1),
+            vec![
+                Action::KeyDown { key_code: 42 },
+                Action::KeyDown { key_code: 30 },
+            ]
+        );
+        assert_eq!(sm.handle_key(114, 0), vec![Action::KeyUp { key_code: 42 }]);
+    }
+
+    #[test]
+    fn permissive_hold_should_wait_for_nested_tap() {
+        let rules = vec![dual_role_rule(TapHoldFlavor::PermissiveHold, 200)];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+
+        sm.handle_key(114, 1);
+        assert!(sm.handle_key(30, 1).is_empty());
+        assert_eq!(
+            sm.handle_key(30, 0),
+            vec![
+                Action::KeyDown { key_code: 42 },
+                Action::KeyDown { key_code: 30 },
+                Action::KeyUp { key_code: 30 },
+            ]
+        );
+        assert_eq!(sm.handle_key(114, 0), vec![Action::KeyUp { key_code: 42 }]);
+    }
+
+    fn media_layer() -> Layer {
+        let mut remap = HashMap::new();
+        remap.insert("114".to_string(), 109);
+        Layer {
+            name: "media".to_string(),
+            rules: vec![make_rule(
+                "media_next",
+                "115",
+                RuleType::Click,
+                Action::SendKey { key_code: 163 },
+            )],
+            remap,
+        }
+    }
+
+    fn layered_machine(layer_action: Action) -> StateMachine {
+        let layer_rule_type = match layer_action {
+            Action::LayerMomentary { .. } => RuleType::ShortPress,
+            _ => RuleType::Click,
+        };
+        let rules = vec![
+            make_rule("fn", "116", layer_rule_type, layer_action),
+            make_rule(
+                "vol_up",
+                "115",
+                RuleType::Click,
+                Action::SendKey { key_code: 1 },
+            ),
+            make_rule(
+                "vol_down",
+                "114",
+                RuleType::Click,
+                Action::SendKey { key_code: 2 },
+            ),
+            make_rule(
+                "home",
+                "102",
+                RuleType::Click,
+                Action::SendKey { key_code: 3 },
+            ),
+        ];
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 5, 300, 200, 500);
+        sm.update_layers(HashMap::new(), &[media_layer()], &HashMap::new());
+        sm
+    }
+
+    #[test]
+    fn momentary_layer_should_override_keys_only_while_held() {
+        let mut sm = layered_machine(Action::LayerMomentary {
+            layer: "media".to_string(),
+        });
+
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        sm.handle_key_at(116, 1, at(0));
+        assert!(
+            sm.tick_at(at(10)).is_empty(),
+            "layer actions are not executed"
+        );
+
+        assert_eq!(
+            tap_at(&mut sm, 115, at(20)),
+            vec![Action::SendKey { key_code: 163 }]
+        );
+        // Unused keys fall through; layer remaps hide the base rule on the key
+        assert_eq!(
+            tap_at(&mut sm, 102, at(30)),
+            vec![Action::SendKey { key_code: 3 }]
+        );
+        assert!(!sm.is_mapped(114));
+        assert_eq!(sm.remap_event(114, 1), Some(109));
+
+        sm.handle_key_at(116, 0, at(40));
+        // The remapped key keeps its DOWN target until released
+        assert_eq!(sm.remap_event(114, 0), Some(109));
+        assert_eq!(sm.remap_event(114, 1), None);
+        assert_eq!(
+            tap_at(&mut sm, 115, at(50)),
+            vec![Action::SendKey { key_code: 1 }]
+        );
+    }
+
+    #[test]
+    fn toggle_layer_should_stay_until_toggled_again() {
+        let mut sm = layered_machine(Action::LayerToggle {
+            layer: "media".to_string(),
+        });
+
+        assert!(tap(&mut sm, 116).is_empty());
+        assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 163 }]);
+        assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 163 }]);
+
+        assert!(tap(&mut sm, 116).is_empty());
+        assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 1 }]);
+    }
+
+    #[test]
+    fn one_shot_layer_should_apply_to_next_fired_rule_only() {
+        let mut sm = layered_machine(Action::LayerOneShot {
+            layer: "media".to_string(),
+        });
+
+        assert!(tap(&mut sm, 116).is_empty());
+        assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 163 }]);
+        assert_eq!(tap(&mut sm, 115), vec![Action::SendKey { key_code: 1 }]);
+    }
+
+    fn passthrough_machine(trigger: &str, rule_type: RuleType) -> StateMachine {
+        let mut rule = make_rule("pt", trigger, rule_type, Action::SendKey { key_code: 1 });
+        rule.passthrough = true;
+        if rule_type == RuleType::MultiClick {
+            rule.count = Some(3);
+        }
+        StateMachine::new(vec![rule], HashMap::new(), 30, 10, 20, 200, 20)
+    }
+
+    fn raw_tap(key_code: u16) -> Vec<Action> {
+        vec![Action::KeyDown { key_code }, Action::KeyUp { key_code }]
+    }
+
+    #[test]
+    fn passthrough_click_should_hand_over_long_hold_while_down() {
+        let mut sm = passthrough_machine("115", RuleType::Click);
+
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        sm.handle_key_at(115, 1, at(0));
+        assert_eq!(sm.tick_at(at(15)), vec![Action::KeyDown { key_code: 115 }]);
+        assert!(sm.tick_at(at(15)).is_empty());
+        assert_eq!(
+            sm.handle_key_at(115, 0, at(20)),
+            vec![Action::KeyUp { key_code: 115 }]
+        );
+    }
+
+    #[test]
+    fn passthrough_multi_tap_rules_should_replay_unfinished_taps() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut sm = passthrough_machine("115", RuleType::DoubleClick);
+        assert!(tap_at(&mut sm, 115, at(0)).is_empty());
+        assert_eq!(sm.tick_at(at(30)), raw_tap(115));
+
+        let mut sm = passthrough_machine("115", RuleType::MultiClick);
+        tap_at(&mut sm, 115, at(0));
+        tap_at(&mut sm, 115, at(5));
+        let mut expected = raw_tap(115);
+        expected.extend(raw_tap(115));
+        assert_eq!(sm.tick_at(at(30)), expected);
+
+        // A completed gesture is not replayed
+        let mut sm = passthrough_machine("115", RuleType::DoubleClick);
+        tap_at(&mut sm, 115, at(0));
+        assert_eq!(
+            tap_at(&mut sm, 115, at(5)),
+            vec![Action::SendKey { key_code: 1 }]
+        );
+        assert!(sm.tick_at(at(30)).is_empty());
+    }
+
+    #[test]
+    fn passthrough_hold_rules_should_replay_quick_taps() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        for rule_type in [RuleType::ShortPress, RuleType::LongPress] {
+            let mut sm = passthrough_machine("115", rule_type);
+            assert_eq!(
+                tap_at(&mut sm, 115, at(0)),
+                vec![Action::KeyDown { key_code: 115 }],
+                "{:?}",
+                rule_type
+            );
+            assert_eq!(sm.tick_at(at(2)), vec![Action::KeyUp { key_code: 115 }]);
+        }
+
+        // Holding long enough fires the rule instead
+        let mut sm = passthrough_machine("115", RuleType::LongPress);
+        sm.handle_key_at(115, 1, at(0));
+        assert_eq!(sm.tick_at(at(35)), vec![Action::SendKey { key_code: 1 }]);
+        assert!(sm.handle_key_at(115, 0, at(40)).is_empty());
+    }
+
+    #[test]
+    fn passthrough_should_replay_gestures_blocked_by_conditions() {
+        let night_machine = |rule_type: RuleType| {
+            let mut rule = make_rule("pt", "115", rule_type, Action::SendKey { key_code: 1 });
+            rule.passthrough = true;
+            rule.conditions = vec![Condition::TimeRange {
+                start: "22:00".to_string(),
+                end: "06:00".to_string(),
+            }];
+            let mut sm = StateMachine::new(vec![rule], HashMap::new(), 30, 10, 20, 200, 20);
+            sm.set_context(Arc::new(friday_at(12, 0)));
+            sm
+        };
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+
+        let mut sm = night_machine(RuleType::Click);
+        assert!(sm.handle_key_at(115, 1, at(0)).is_empty());
+        assert_eq!(
+            sm.handle_key_at(115, 0, at(5)),
+            vec![Action::KeyDown { key_code: 115 }]
+        );
+        assert_eq!(sm.tick_at(at(10)), vec![Action::KeyUp { key_code: 115 }]);
+
+        // A blocked hold hands the press over while the key is still down
+        let mut sm = night_machine(RuleType::LongPress);
+        sm.handle_key_at(115, 1, at(0));
+        assert_eq!(sm.tick_at(at(35)), vec![Action::KeyDown { key_code: 115 }]);
+        assert_eq!(
+            sm.handle_key_at(115, 0, at(40)),
+            vec![Action::KeyUp { key_code: 115 }]
+        );
+    }
+
+    #[test]
+    fn passthrough_combo_rules_should_replay_lone_key() {
+        for rule_type in [
+            RuleType::ComboClick,
+            RuleType::ComboShortPress,
+            RuleType::ComboLongPress,
+        ] {
+            let t0 = Instant::now();
+            let mut sm = passthrough_machine("115+114", rule_type);
+            assert_eq!(
+                tap_at(&mut sm, 114, t0),
+                vec![Action::KeyDown { key_code: 114 }],
+                "{:?}",
+                rule_type
+            );
+            assert_eq!(
+                sm.tick_at(t0 + Duration::from_millis(2)),
+                vec![Action::KeyUp { key_code: 114 }]
+            );
+        }
+    }
+
+    #[test]
+    fn passthrough_sequence_should_replay_broken_prefix() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut sm = passthrough_machine("115->114", RuleType::Sequence);
+        sm.handle_key_at(115, 1, at(0));
+        assert!(sm.handle_key_at(115, 0, at(5)).is_empty());
+        assert_eq!(sm.tick_at(at(30)), vec![Action::KeyDown { key_code: 115 }]);
+        assert_eq!(sm.tick_at(at(35)), vec![Action::KeyUp { key_code: 115 }]);
+
+        // A tap the key's own rules leave unhandled is replayed raw as well
+        let mut hold = make_rule("hold", "115", RuleType::LongPress, send(1));
+        hold.passthrough = true;
+        let seq = make_rule("seq", "115->114", RuleType::Sequence, send(2));
+        let mut sm = StateMachine::new(vec![hold, seq], HashMap::new(), 30, 10, 20, 200, 20);
+        sm.handle_key_at(115, 1, at(0));
+        assert!(sm.handle_key_at(115, 0, at(5)).is_empty());
+        assert_eq!(sm.tick_at(at(30)), vec![Action::KeyDown { key_code: 115 }]);
+        assert_eq!(sm.tick_at(at(35)), vec![Action::KeyUp { key_code: 115 }]);
+    }
+
+    #[test]
+    fn new_press_should_release_pending_replay_first() {
+        let mut sm = passthrough_machine("115", RuleType::LongPress);
+        assert_eq!(tap(&mut sm, 115), vec![Action::KeyDown { key_code: 115 }]);
+        assert_eq!(sm.handle_key(115, 1), vec![Action::KeyUp { key_code: 115 }]);
+    }
+
+    // Deterministic replays (default thresholds: long 800, short 300, double 300, combo 200)
+
+    fn send(key_code: u16) -> Action {
+        Action::SendKey { key_code }
+    }
+
+    fn replay_machine(rules: &[(&str, &str, RuleType, u16)]) -> StateMachine {
+        let rules = rules
+            .iter()
+            .map(|(id, trigger, rule_type, code)| make_rule(id, trigger, *rule_type, send(*code)))
+            .collect();
+        StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500)
+    }
+
+    #[test]
+    fn replay_click_should_fire_on_release_only_below_short_threshold() {
+        let mut sm = replay_machine(&[("click", "115", RuleType::Click, 1)]);
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (80, 115, 0)], 1000),
+            vec![(80, vec![send(1)])]
+        );
+        assert!(sm.replay(&[(0, 115, 1), (400, 115, 0)], 1000).is_empty());
+    }
+
+    #[test]
+    fn replay_click_should_wait_out_double_click_window() {
+        let rules = [
+            ("click", "115", RuleType::Click, 1),
+            ("double", "115", RuleType::DoubleClick, 2),
+        ];
+
+        let mut sm = replay_machine(&rules);
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (50, 115, 0)], 1000),
+            vec![(350, vec![send(1)])]
+        );
+
+        let mut sm = replay_machine(&rules);
+        let events = [(0, 115, 1), (50, 115, 0), (150, 115, 1), (200, 115, 0)];
+        assert_eq!(sm.replay(&events, 1000), vec![(200, vec![send(2)])]);
+
+        // Taps further apart than the interval are two single clicks
+        let mut sm = replay_machine(&rules);
+        let events = [(0, 115, 1), (50, 115, 0), (400, 115, 1), (450, 115, 0)];
+        assert_eq!(
+            sm.replay(&events, 1000),
+            vec![(350, vec![send(1)]), (750, vec![send(1)])]
+        );
+    }
+
+    #[test]
+    fn replay_short_and_long_press_should_fire_at_thresholds_while_held() {
+        let rules = [
+            ("click", "115", RuleType::Click, 1),
+            ("short", "115", RuleType::ShortPress, 3),
+            ("long", "115", RuleType::LongPress, 4),
+        ];
+
+        let mut sm = replay_machine(&rules);
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (1000, 115, 0)], 1200),
+            vec![(300, vec![send(3)]), (800, vec![send(4)])]
+        );
+
+        // Released before the short threshold: only the click
+        let mut sm = replay_machine(&rules);
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (200, 115, 0)], 1200),
+            vec![(200, vec![send(1)])]
+        );
+    }
+
+    #[test]
+    fn replay_release_timed_presses_should_fire_on_release_by_hold_time() {
+        let mut short = make_rule("short", "115", RuleType::ShortPress, send(3));
+        short.on_release = true;
+        let mut long = make_rule("long", "115", RuleType::LongPress, send(4));
+        long.on_release = true;
+        let rules = vec![
+            make_rule("click", "115", RuleType::Click, send(1)),
+            short,
+            long,
+        ];
+        let release_machine =
+            || StateMachine::new(rules.clone(), HashMap::new(), 800, 300, 300, 200, 500);
+
+        let mut sm = release_machine();
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (500, 115, 0)], 1200),
+            vec![(500, vec![send(3)])]
+        );
+
+        let mut sm = release_machine();
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (1000, 115, 0)], 1200),
+            vec![(1000, vec![send(4)])]
+        );
+
+        let mut sm = release_machine();
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (200, 115, 0)], 1200),
+            vec![(200, vec![send(1)])]
+        );
+    }
+
+    #[test]
+    fn release_timed_long_press_should_keep_passthrough_key_until_release() {
+        let mut rule = make_rule("pt", "115", RuleType::LongPress, send(1));
+        rule.passthrough = true;
+        rule.on_release = true;
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 30, 10, 20, 200, 20);
+
+        let t0 = Instant::now();
+        sm.handle_key_at(115, 1, t0);
+        assert!(sm.tick_at(t0 + Duration::from_millis(500)).is_empty());
+        assert_eq!(sm.next_deadline(), None);
+        assert_eq!(
+            sm.handle_key_at(115, 0, t0 + Duration::from_millis(600)),
+            vec![send(1)]
+        );
+    }
+
+    fn ordered_combo(id: &str, trigger: &str, rule_type: RuleType, code: u16) -> Rule {
+        let mut rule = make_rule(id, trigger, rule_type, send(code));
+        rule.ordered = true;
+        rule
+    }
+
+    #[test]
+    fn replay_ordered_combos_should_depend_on_press_order() {
+        let rules = vec![
+            ordered_combo("power_first", "116+115", RuleType::ComboClick, 1),
+            ordered_combo("vol_first", "115+116", RuleType::ComboClick, 2),
+        ];
+        let events = |first: u16, second: u16| {
+            [
+                (0, first, 1),
+                (50, second, 1),
+                (100, second, 0),
+                (120, first, 0),
+            ]
+        };
+
+        let mut sm = StateMachine::new(rules.clone(), HashMap::new(), 800, 300, 300, 200, 500);
+        assert_eq!(
+            sm.replay(&events(116, 115), 1000),
+            vec![(100, vec![send(1)])]
+        );
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+        assert_eq!(
+            sm.replay(&events(115, 116), 1000),
+            vec![(100, vec![send(2)])]
+        );
+
+        let rule = ordered_combo("hold", "116+115", RuleType::ComboShortPress, 3);
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        assert!(sm
+            .replay(
+                &[(0, 115, 1), (50, 116, 1), (600, 116, 0), (650, 115, 0)],
+                1000
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn replay_modifier_combo_should_need_first_key_held_before_the_next() {
+        let modifier = |rule_type: RuleType| {
+            let mut rule = ordered_combo("mod", "116+115", rule_type, 1);
+            rule.modifier_hold_ms = Some(400);
+            StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500)
+        };
+
+        let mut sm = modifier(RuleType::ComboClick);
+        assert_eq!(
+            sm.replay(
+                &[(0, 116, 1), (500, 115, 1), (550, 115, 0), (700, 116, 0)],
+                1000
+            ),
+            vec![(550, vec![send(1)])]
+        );
+
+        // Second key pressed before the modifier hold has passed
+        let mut sm = modifier(RuleType::ComboClick);
+        assert!(sm
+            .replay(
+                &[(0, 116, 1), (100, 115, 1), (150, 115, 0), (700, 116, 0)],
+                1000
+            )
+            .is_empty());
+
+        let mut sm = modifier(RuleType::ComboShortPress);
+        assert_eq!(
+            sm.replay(
+                &[(0, 116, 1), (500, 115, 1), (900, 115, 0), (950, 116, 0)],
+                1200
+            ),
+            vec![(800, vec![send(1)])]
+        );
+    }
+
+    #[test]
+    fn replay_combo_click_should_not_leave_ghost_single_clicks() {
+        let rules = [
+            ("up", "115", RuleType::Click, 1),
+            ("down", "114", RuleType::Click, 2),
+            ("both", "115+114", RuleType::ComboClick, 9),
+        ];
+
+        for (first_up, second_up) in [(115, 114), (114, 115)] {
+            let mut sm = replay_machine(&rules);
+            let events = [
+                (0, 115, 1),
+                (50, 114, 1),
+                (120, first_up, 0),
+                (150, second_up, 0),
+            ];
+            assert_eq!(sm.replay(&events, 1000), vec![(120, vec![send(9)])]);
+        }
+
+        // Presses further apart than the combo window are plain clicks
+        let mut sm = replay_machine(&rules);
+        let events = [(0, 115, 1), (250, 114, 1), (280, 115, 0), (300, 114, 0)];
+        assert_eq!(
+            sm.replay(&events, 1000),
+            vec![(280, vec![send(1)]), (300, vec![send(2)])]
+        );
+    }
+
+    #[test]
+    fn replay_combo_hold_should_fire_once_and_consume_both_releases() {
+        let rules = [
+            ("up", "115", RuleType::Click, 1),
+            ("down", "114", RuleType::Click, 2),
+            ("both_short", "115+114", RuleType::ComboShortPress, 8),
+            ("both_long", "115+114", RuleType::ComboLongPress, 9),
+        ];
+        let mut sm = replay_machine(&rules);
+        let events = [(0, 115, 1), (50, 114, 1), (1000, 115, 0), (1020, 114, 0)];
+        let emitted = sm.replay(&events, 1500);
+        let fired: Vec<Action> = emitted.iter().flat_map(|(_, a)| a.clone()).collect();
+        assert_eq!(fired, vec![send(8), send(9)]);
+        assert!(emitted.iter().all(|(at, _)| *at < 1000));
+    }
+
+    #[test]
+    fn replay_key_repressed_after_combo_should_click_again() {
+        let rules = [
+            ("up", "115", RuleType::Click, 1),
+            ("down", "114", RuleType::Click, 2),
+            ("both_short", "115+114", RuleType::ComboShortPress, 8),
+        ];
+        let mut sm = replay_machine(&rules);
+        let events = [
+            (0, 115, 1),
+            (50, 114, 1),
+            (600, 114, 0),
+            (700, 114, 1),
+            (750, 114, 0),
+            (900, 115, 0),
+        ];
+        let emitted = sm.replay(&events, 1500);
+        assert_eq!(emitted.len(), 2, "{:?}", emitted);
+        assert_eq!(emitted[0].1, vec![send(8)]);
+        // 115 still counts as consumed by the combo; the fresh 114 tap does not
+        assert_eq!(emitted[1], (750, vec![send(2)]));
+    }
+
+    #[test]
+    fn replay_single_long_press_should_not_fire_when_combo_took_the_key() {
+        let rules = [
+            ("long", "115", RuleType::LongPress, 4),
+            ("both_short", "115+114", RuleType::ComboShortPress, 8),
+        ];
+        let mut sm = replay_machine(&rules);
+        let events = [(0, 115, 1), (50, 114, 1), (1000, 115, 0), (1000, 114, 0)];
+        let fired: Vec<Action> = sm
+            .replay(&events, 1500)
+            .into_iter()
+            .flat_map(|(_, a)| a)
+            .collect();
+        assert_eq!(fired, vec![send(8)]);
+    }
+
+    #[test]
+    fn next_deadline_should_follow_pending_timers_and_idle_when_settled() {
+        let mut sm = replay_machine(&[
+            ("click", "115", RuleType::Click, 1),
+            ("double", "115", RuleType::DoubleClick, 2),
+            ("short", "115", RuleType::ShortPress, 3),
+        ]);
+        let t0 = Instant::now();
+        assert_eq!(sm.next_deadline(), None);
+
+        sm.handle_key_at(115, 1, t0);
+        assert_eq!(sm.next_deadline(), Some(t0 + Duration::from_millis(300)));
+
+        let released = t0 + Duration::from_millis(50);
+        sm.handle_key_at(115, 0, released);
+        assert_eq!(
+            sm.next_deadline(),
+            Some(released + Duration::from_millis(300))
+        );
+
+        let due = released + Duration::from_millis(300);
+        assert_eq!(sm.tick_at(due), vec![send(1)]);
+        assert_eq!(sm.next_deadline(), None);
+    }
+
+    #[test]
+    fn next_deadline_should_not_report_blocked_timers_twice() {
+        // The sub-chord is held back by its superset until the combo window closes
+        let mut sm = replay_machine(&[
+            ("pair", "115+114", RuleType::ComboShortPress, 8),
+            ("triple", "115+114+116", RuleType::ComboShortPress, 9),
+        ]);
+        let t0 = Instant::now();
+        sm.handle_key_at(115, 1, t0);
+        sm.handle_key_at(114, 1, t0 + Duration::from_millis(10));
+        let close = t0 + Duration::from_millis(200) + JUST_AFTER;
+        assert_eq!(sm.next_deadline(), Some(close));
+
+        let ready = t0 + Duration::from_millis(310);
+        assert_eq!(sm.tick_at(close), vec![]);
+        assert_eq!(sm.next_deadline(), Some(ready));
+        assert_eq!(sm.tick_at(ready), vec![send(8)]);
+        assert_eq!(sm.next_deadline(), None);
+    }
+
+    fn pattern_machine(patterns: &[(&str, u16)]) -> StateMachine {
+        let rules = patterns
+            .iter()
+            .map(|(pattern, code)| {
+                let mut rule = make_rule(pattern, "116", RuleType::Pattern, send(*code));
+                rule.pattern = Some(pattern.to_string());
+                rule
+            })
+            .collect();
+        StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500)
+    }
+
+    /// DOWN/UP events for presses of `holds` ms, 100 ms apart.
+    fn presses(key_code: u16, holds: &[u64]) -> Vec<(u64, u16, i32)> {
+        let mut at = 0;
+        let mut events = Vec::new();
+        for hold in holds {
+            events.push((at, key_code, 1));
+            events.push((at + hold, key_code, 0));
+            at += hold + 100;
+        }
+        events
+    }
+
+    #[test]
+    fn replay_pattern_should_classify_presses_and_wait_for_longer_patterns() {
+        let patterns = [(".-", 1), ("..", 2), (".-..", 3)];
+
+        // ".-" could still become ".-..": fires once the gap has passed
+        let mut sm = pattern_machine(&patterns);
+        assert_eq!(
+            sm.replay(&presses(116, &[100, 400]), 2000),
+            vec![(1100, vec![send(1)])]
+        );
+
+        // ".." is not a prefix of anything longer: fires on release
+        let mut sm = pattern_machine(&patterns);
+        assert_eq!(
+            sm.replay(&presses(116, &[100, 100]), 2000),
+            vec![(300, vec![send(2)])]
+        );
+
+        let mut sm = pattern_machine(&patterns);
+        assert_eq!(
+            sm.replay(&presses(116, &[100, 400, 100, 100]), 2000),
+            vec![(1000, vec![send(3)])]
+        );
+
+        // "-" matches nothing and is dropped
+        let mut sm = pattern_machine(&patterns);
+        assert!(sm.replay(&presses(116, &[400]), 2000).is_empty());
+    }
+
+    #[test]
+    fn replay_pattern_should_restart_after_a_breaking_press_or_gap() {
+        let mut sm = pattern_machine(&[(".-", 1), (".-..", 3)]);
+        // ".--": the second dash breaks ".-..", so ".-" fires and "-" starts over
+        assert_eq!(
+            sm.replay(&presses(116, &[100, 400, 400]), 2000),
+            vec![(1100, vec![send(1)])]
+        );
+
+        // A dash held past the gap does not settle the pattern before its release
+        let mut sm = pattern_machine(&[(".-", 1), (".-..", 3)]);
+        assert_eq!(
+            sm.replay(
+                &[(0, 116, 1), (100, 116, 0), (200, 116, 1), (900, 116, 0)],
+                2000
+            ),
+            vec![(1400, vec![send(1)])]
+        );
+    }
+
+    #[test]
+    fn replay_hold_on_a_sequence_step_should_settle_the_buffered_taps_first() {
+        let mut long = make_rule("long", "115", RuleType::LongPress, send(2));
+        long.timing.long_press_threshold_ms = Some(300);
+        let rules = vec![
+            make_rule("seq", "115->115->114", RuleType::Sequence, send(9)),
+            make_rule("click", "115", RuleType::Click, send(1)),
+            long,
+        ];
+        let mut sm = StateMachine::new(rules.clone(), HashMap::new(), 800, 300, 300, 200, 500);
+        assert_eq!(
+            sm.replay(
+                &[(0, 115, 1), (50, 115, 0), (100, 115, 1), (600, 115, 0)],
+                2000
+            ),
+            vec![(400, vec![send(1), send(2)])]
+        );
+
+        // Same for a hold that fires on release
+        let rules: Vec<Rule> = rules
+            .into_iter()
+            .map(|mut rule| {
+                rule.on_release = rule.rule_type == RuleType::LongPress;
+                rule
+            })
+            .collect();
+        let mut sm = StateMachine::new(rules, HashMap::new(), 800, 300, 300, 200, 500);
+        assert_eq!(
+            sm.replay(
+                &[(0, 115, 1), (50, 115, 0), (100, 115, 1), (500, 115, 0)],
+                2000
+            ),
+            vec![(500, vec![send(1), send(2)])]
+        );
+    }
+
+    #[test]
+    fn replay_hold_repeat_and_sequence_gap_should_fire_on_time() {
+        let mut rule = make_rule("vol", "115", RuleType::ShortPress, send(3));
+        rule.repeat = Some(HoldRepeat {
+            interval_ms: 100,
+            acceleration_ms: vec![200],
+        });
+        let mut sm = StateMachine::new(vec![rule], HashMap::new(), 800, 300, 300, 200, 500);
+        let at: Vec<u64> = sm
+            .replay(&[(0, 115, 1), (750, 115, 0)], 2000)
+            .into_iter()
+            .map(|(at, _)| at)
+            .collect();
+        assert_eq!(at, vec![300, 500, 600, 700]);
+
+        let mut sm = replay_machine(&[("seq", "115->114", RuleType::Sequence, 7)]);
+        assert_eq!(
+            sm.replay(&[(0, 115, 1), (50, 115, 0)], 2000),
+            vec![
+                (550, vec![Action::KeyDown { key_code: 115 }]),
+                (600, vec![Action::KeyUp { key_code: 115 }])
+            ]
+        );
     }
 }
@@ -7,6 +7,7 @@ use parking_lot::Mutex;
 use tokio::sync::RwLock;
 
 mod config;
+mod context;
 mod event;
 mod hardware;
 mod utils;
@@ -17,6 +18,7 @@ use event::EventProcessor;
 use hardware::InputDeviceManager;
 use webui::app_cache::AppCache;
 use webui::learn::LearnState;
+use webui::status::RuleStats;
 use webui::WebServer;
 
 #[derive(Parser, Debug)]
@@ -45,6 +47,10 @@ struct Args {
     /// Input device path (skip auto-discovery)
     #[arg(long)]
     device: Option<PathBuf>,
+
+    /// Validate the config, print rule-conflict warnings and exit
+    #[arg(long)]
+    check: bool,
 }
 
 #[tokio::main]
@@ -71,9 +77,26 @@ async fn main() -> Result<()> {
         return Err(e);
     }
 
+    let warnings = config.analyze();
+    if args.check {
+        for w in &warnings {
+            println!("warning [{}]: {}", w.rule_ids.join(", "), w.message);
+        }
+        println!(
+            "{}: valid, {} warning(s)",
+            args.config.display(),
+            warnings.len()
+        );
+        return Ok(());
+    }
+    for w in &warnings {
+        warn!("Rule conflict [{}]: {}", w.rule_ids.join(", "), w.message);
+    }
+
     let config = Arc::new(RwLock::new(config));
     let learn_state = Arc::new(Mutex::new(LearnState::default()));
     let app_cache = Arc::new(tokio::sync::RwLock::new(AppCache::new()));
+    let rule_stats = Arc::new(Mutex::new(RuleStats::default()));
 
     let device_path = if let Some(path) = args.device {
         path
@@ -95,11 +118,25 @@ async fn main() -> Result<()> {
 
     info!("Target input device: {:?}", device_path);
 
+    let debug_mode = args.log_level.eq_ignore_ascii_case("debug")
+        || args.log_level.eq_ignore_ascii_case("trace");
     let config_for_web = config.clone();
     let web_config_path = args.config.clone();
     let web_port = args.webui_port;
     let learn_state_for_web = learn_state.clone();
     let app_cache_for_web = app_cache.clone();
+    let rule_stats_for_web = rule_stats.clone();
+    let mut processor = EventProcessor::new(
+        config.clone(),
+        args.config.clone(),
+        device_path,
+        debug_mode,
+        learn_state,
+        rule_stats,
+    )
+    .await?;
+    let external_events = processor.external_events();
+
     tokio::spawn(async move {
         if let Err(e) = WebServer::run(
             config_for_web,
@@ -107,6 +144,8 @@ async fn main() -> Result<()> {
             web_port,
             learn_state_for_web,
             app_cache_for_web,
+            rule_stats_for_web,
+            external_events,
         )
         .await
         {
@@ -114,17 +153,6 @@ async fn main() -> Result<()> {
         }
     });
 
-    let debug_mode = args.log_level.eq_ignore_ascii_case("debug")
-        || args.log_level.eq_ignore_ascii_case("trace");
-    let mut processor = EventProcessor::new(
-        config.clone(),
-        args.config.clone(),
-        device_path,
-        debug_mode,
-        learn_state,
-    )
-    .await?;
-
     match processor.run().await {
         Ok(_) => info!("Event processor terminated normally"),
         Err(e) => {
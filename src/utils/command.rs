@@ -0,0 +1,21 @@
+use anyhow::{bail, Result};
+use tokio::process::Command;
+
+/// Run a command to completion and return its stdout; a non-zero exit is an error.
+pub async fn command_output(program: &str, args: &[String]) -> Result<String> {
+    let out = Command::new(program).args(args).output().await?;
+    if !out.status.success() {
+        bail!(
+            "Command '{} {:?}' failed: {}",
+            program,
+            args,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// `command_output` of `sh -c <cmd>`.
+pub async fn shell_output(cmd: &str) -> Result<String> {
+    command_output("sh", &["-c".to_string(), cmd.to_string()]).await
+}
@@ -5,9 +5,12 @@ use std::collections::BTreeMap;
 use uuid::Uuid;
 
 use crate::config::{
-    Action, BrightnessDirection, BuiltinCommand, Config, IntentSpec, Rule, RuleType,
-    VolumeDirection,
+    Action, AppMatch, BrightnessDirection, BuiltinCommand, CompareOp, Condition, Config,
+    IntentSpec, Layer, RepeatPolicy, Rule, RuleType, HoldRepeat, RuleTiming, ScreenState,
+    SequenceFallback, TapHold, TapHoldFlavor, VarValue, VolumeDirection, Weekday,
 };
+use crate::config::validator::{ConfigWarning, WarningKind};
+use crate::event::external::ExternalEvent;
 use crate::utils::logger::append_webui_log;
 use crate::webui::learn::LearnResultSnapshot;
 use crate::webui::learn::LearnStatus;
@@ -29,6 +32,9 @@ pub(crate) struct WebUiConfigDto {
     device_name: String,
     #[serde(default)]
     hardware_map: BTreeMap<u16, String>,
+    /// 1:1 remap table; omitted (null) keeps the current remaps
+    #[serde(default)]
+    remap: Option<BTreeMap<String, u16>>,
     double_press_interval_ms: u32,
     long_press_min_ms: u32,
     short_press_min_ms: u32,
@@ -36,8 +42,26 @@ pub(crate) struct WebUiConfigDto {
     combination_timeout_ms: u32,
     #[serde(default = "default_rule_timeout_ms")]
     rule_timeout_ms: u32,
+    #[serde(default = "default_sequence_gap_ms")]
+    sequence_gap_ms: u32,
+    #[serde(default)]
+    rules: Vec<WebUiRuleDto>,
+    /// Named layers; omitted (null) keeps the current layers
+    #[serde(default)]
+    layers: Option<Vec<WebUiLayerDto>>,
+    /// Per-key auto-repeat policies; omitted (null) keeps the current ones
+    #[serde(default)]
+    key_repeat_policy: Option<BTreeMap<String, RepeatPolicy>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct WebUiLayerDto {
+    name: String,
     #[serde(default)]
     rules: Vec<WebUiRuleDto>,
+    #[serde(default)]
+    remap: BTreeMap<String, u16>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -49,16 +73,91 @@ struct WebUiRuleDto {
     enabled: bool,
     #[serde(default)]
     description: String,
-    /// "and" or "or" — reserved for phase 2 multi-condition logic
+    /// "and" or "or": how the non-trigger conditions combine; deeper groups nest
+    /// as all / any / not conditions
     #[serde(default = "default_condition_logic")]
     condition_logic: String,
-    /// V1: exactly 1 condition of type "key_event"
+    /// Exactly 1 trigger condition of type "key_event" or "external", plus any
+    /// time_range / weekdays / foreground_app / screen_state / variable / battery_level /
+    /// charging / switch / all / any / not conditions
     conditions: Vec<WebUiConditionDto>,
     /// Multiple actions executed sequentially
     actions: Vec<WebUiActionDto>,
+    /// SEQUENCE only: "replay" or "drop" buffered taps of an unfinished sequence
+    #[serde(default)]
+    sequence_fallback: Option<SequenceFallback>,
+    /// MULTI_CLICK only: required tap count
+    #[serde(default)]
+    count: Option<u32>,
+    /// Per-rule threshold overrides; unset fields fall back to the global values
+    #[serde(default)]
+    timing: WebUiRuleTimingDto,
+    /// SHORT_PRESS / LONG_PRESS only: repeat the actions while held
+    #[serde(default)]
+    repeat: Option<WebUiHoldRepeatDto>,
+    /// TAP_HOLD only: key held while the dual-role key is held (actions are the tap side)
+    #[serde(default)]
+    tap_hold: Option<WebUiTapHoldDto>,
+    /// Replay the raw key to the system when no rule matches the gesture
+    #[serde(default)]
+    passthrough: bool,
+    /// SHORT_PRESS / LONG_PRESS: fire on release instead of while held
+    #[serde(default)]
+    on_release: bool,
+    /// Key auto-repeat handling; null falls back to the key's policy
+    #[serde(default)]
+    repeat_policy: Option<RepeatPolicy>,
+    /// COMBO_*: keys must go down in trigger order
+    #[serde(default)]
+    ordered: bool,
+    /// Ordered COMBO_*: minimum hold of the first key before the next one
+    #[serde(default)]
+    modifier_hold_ms: Option<u32>,
+    /// Minimum time between two fires
+    #[serde(default)]
+    cooldown_ms: Option<u32>,
+    /// Fires allowed within any 60 s span
+    #[serde(default)]
+    max_per_minute: Option<u32>,
+    /// PATTERN only: `.` short / `-` long presses, e.g. ".-.."
+    #[serde(default)]
+    pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebUiTapHoldDto {
+    hold_key: u16,
+    #[serde(default)]
+    tapping_term_ms: Option<u32>,
+    #[serde(default)]
+    flavor: TapHoldFlavor,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebUiHoldRepeatDto {
+    interval_ms: u32,
+    #[serde(default)]
+    acceleration_ms: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebUiRuleTimingDto {
+    #[serde(default)]
+    double_press_interval_ms: Option<u32>,
+    #[serde(default)]
+    long_press_min_ms: Option<u32>,
+    #[serde(default)]
+    short_press_min_ms: Option<u32>,
+    #[serde(default)]
+    combination_timeout_ms: Option<u32>,
+    #[serde(default)]
+    sequence_gap_ms: Option<u32>,
 }
 
-/// Extensible condition type — V1 only implements key_event
+/// Extensible condition type — key_event is the trigger, the others gate the rule
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum WebUiConditionDto {
@@ -67,11 +166,66 @@ enum WebUiConditionDto {
         key_code: Option<u16>,
         #[serde(default)]
         combo_key_code: Option<u16>,
+        /// Additional chord keys beyond the first two (3+ key combos)
+        #[serde(default)]
+        extra_key_codes: Vec<u16>,
+        /// Ordered steps for SEQUENCE behavior
+        #[serde(default)]
+        sequence_key_codes: Vec<u16>,
         behavior: WebUiBehaviorDto,
     },
-    // Phase 2 stubs — currently rejected by save_config
-    // Geofence { lat: f64, lon: f64, radius_m: f64 },
-    // TimeRange { start: String, end: String },
+    /// Trigger of an EXTERNAL rule: an event posted to /api/trigger
+    External {
+        source: String,
+        /// None matches every event of the source
+        #[serde(default)]
+        event: Option<String>,
+        #[serde(default)]
+        payload: BTreeMap<String, String>,
+    },
+    TimeRange {
+        start: String,
+        end: String,
+    },
+    Weekdays {
+        days: Vec<Weekday>,
+    },
+    ForegroundApp {
+        packages: Vec<String>,
+        #[serde(default)]
+        mode: AppMatch,
+    },
+    ScreenState {
+        state: ScreenState,
+    },
+    Variable {
+        name: String,
+        #[serde(default)]
+        op: CompareOp,
+        value: VarValue,
+    },
+    BatteryLevel {
+        op: CompareOp,
+        value: u8,
+    },
+    Charging {
+        #[serde(default = "default_true_bool")]
+        charging: bool,
+    },
+    Switch {
+        switch: String,
+        #[serde(default = "default_true_bool")]
+        on: bool,
+    },
+    All {
+        conditions: Vec<WebUiConditionDto>,
+    },
+    Any {
+        conditions: Vec<WebUiConditionDto>,
+    },
+    Not {
+        condition: Box<WebUiConditionDto>,
+    },
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -81,9 +235,13 @@ enum WebUiBehaviorDto {
     ShortPress,
     LongPress,
     DoubleClick,
+    MultiClick,
     ComboClick,
     ComboShortPress,
     ComboLongPress,
+    Sequence,
+    TapHold,
+    Pattern,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,6 +254,14 @@ enum WebUiActionDto {
         #[serde(rename = "keyCode")]
         key_code: u16,
     },
+    KeyDown {
+        #[serde(rename = "keyCode")]
+        key_code: u16,
+    },
+    KeyUp {
+        #[serde(rename = "keyCode")]
+        key_code: u16,
+    },
     BuiltinCommand {
         command: WebUiBuiltinCommandDto,
     },
@@ -113,9 +279,23 @@ enum WebUiActionDto {
         interval_ms: u32,
     },
     ToggleScreen,
+    ScreenOn,
+    ScreenOff,
     ToggleRule {
         rule_id: String,
     },
+    SetVariable {
+        name: String,
+        value: VarValue,
+    },
+    IncrementVariable {
+        name: String,
+        #[serde(default = "default_increment")]
+        by: i64,
+    },
+    ToggleVariable {
+        name: String,
+    },
     VolumeControl {
         direction: WebUiVolumeDirectionDto,
     },
@@ -128,6 +308,15 @@ enum WebUiActionDto {
         duration_ms: u32,
     },
     Intercept,
+    LayerMomentary {
+        layer: String,
+    },
+    LayerToggle {
+        layer: String,
+    },
+    LayerOneShot {
+        layer: String,
+    },
     Macro {
         actions: Vec<WebUiActionDto>,
     },
@@ -174,6 +363,86 @@ struct WebUiIntentDto {
     extras: std::collections::HashMap<String, String>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigWarningDto {
+    kind: WarningKind,
+    rule_ids: Vec<String>,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigWarningsDto {
+    warnings: Vec<ConfigWarningDto>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RuleStatsDto {
+    total_suppressed: u64,
+    suppressed_fires: BTreeMap<String, u64>,
+}
+
+/// Body of POST /api/trigger.
+#[derive(Debug, Deserialize)]
+pub(crate) struct TriggerDto {
+    source: String,
+    #[serde(default)]
+    event: String,
+    #[serde(default)]
+    payload: BTreeMap<String, PayloadValueDto>,
+}
+
+/// Payload values are matched as strings; numbers and booleans are accepted as-is.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PayloadValueDto {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl PayloadValueDto {
+    fn into_string(self) -> String {
+        match self {
+            PayloadValueDto::Bool(b) => b.to_string(),
+            PayloadValueDto::Int(n) => n.to_string(),
+            PayloadValueDto::Float(n) => n.to_string(),
+            PayloadValueDto::Str(s) => s,
+        }
+    }
+}
+
+impl TriggerDto {
+    fn into_event(self) -> Result<ExternalEvent, String> {
+        let source = self.source.trim().to_string();
+        if source.is_empty() || source.contains(':') {
+            return Err("source must be non-empty and must not contain ':'".to_string());
+        }
+        Ok(ExternalEvent {
+            source,
+            event: self.event.trim().to_string(),
+            payload: self
+                .payload
+                .into_iter()
+                .map(|(key, value)| (key, value.into_string()))
+                .collect(),
+        })
+    }
+}
+
+impl From<ConfigWarning> for ConfigWarningDto {
+    fn from(value: ConfigWarning) -> Self {
+        Self {
+            kind: value.kind,
+            rule_ids: value.rule_ids,
+            message: value.message,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AppItemDto {
@@ -203,21 +472,34 @@ fn default_rule_timeout_ms() -> u32 {
     5000
 }
 
+fn default_sequence_gap_ms() -> u32 {
+    500
+}
+
+fn default_increment() -> i64 {
+    1
+}
+
 fn default_condition_logic() -> String {
     "and".to_string()
 }
 
 impl WebUiBehaviorDto {
-    fn from_rule_type(t: RuleType) -> Self {
-        match t {
+    fn from_rule_type(t: RuleType) -> Result<Self> {
+        Ok(match t {
             RuleType::Click => Self::Click,
             RuleType::ShortPress => Self::ShortPress,
             RuleType::LongPress => Self::LongPress,
             RuleType::DoubleClick => Self::DoubleClick,
+            RuleType::MultiClick => Self::MultiClick,
             RuleType::ComboClick => Self::ComboClick,
             RuleType::ComboShortPress => Self::ComboShortPress,
             RuleType::ComboLongPress => Self::ComboLongPress,
-        }
+            RuleType::Sequence => Self::Sequence,
+            RuleType::TapHold => Self::TapHold,
+            RuleType::Pattern => Self::Pattern,
+            RuleType::External => bail!("EXTERNAL rules use the external condition"),
+        })
     }
 
     fn into_rule_type(self) -> RuleType {
@@ -226,9 +508,13 @@ impl WebUiBehaviorDto {
             Self::ShortPress => RuleType::ShortPress,
             Self::LongPress => RuleType::LongPress,
             Self::DoubleClick => RuleType::DoubleClick,
+            Self::MultiClick => RuleType::MultiClick,
             Self::ComboClick => RuleType::ComboClick,
             Self::ComboShortPress => RuleType::ComboShortPress,
             Self::ComboLongPress => RuleType::ComboLongPress,
+            Self::Sequence => RuleType::Sequence,
+            Self::TapHold => RuleType::TapHold,
+            Self::Pattern => RuleType::Pattern,
         }
     }
 
@@ -240,6 +526,68 @@ impl WebUiBehaviorDto {
     }
 }
 
+impl From<RuleTiming> for WebUiRuleTimingDto {
+    fn from(value: RuleTiming) -> Self {
+        Self {
+            double_press_interval_ms: value.double_tap_interval_ms,
+            long_press_min_ms: value.long_press_threshold_ms,
+            short_press_min_ms: value.short_press_threshold_ms,
+            combination_timeout_ms: value.combination_timeout_ms,
+            sequence_gap_ms: value.sequence_gap_ms,
+        }
+    }
+}
+
+impl From<WebUiRuleTimingDto> for RuleTiming {
+    fn from(value: WebUiRuleTimingDto) -> Self {
+        Self {
+            long_press_threshold_ms: value.long_press_min_ms,
+            short_press_threshold_ms: value.short_press_min_ms,
+            double_tap_interval_ms: value.double_press_interval_ms,
+            combination_timeout_ms: value.combination_timeout_ms,
+            sequence_gap_ms: value.sequence_gap_ms,
+        }
+    }
+}
+
+impl From<HoldRepeat> for WebUiHoldRepeatDto {
+    fn from(value: HoldRepeat) -> Self {
+        Self {
+            interval_ms: value.interval_ms,
+            acceleration_ms: value.acceleration_ms,
+        }
+    }
+}
+
+impl From<WebUiHoldRepeatDto> for HoldRepeat {
+    fn from(value: WebUiHoldRepeatDto) -> Self {
+        Self {
+            interval_ms: value.interval_ms,
+            acceleration_ms: value.acceleration_ms,
+        }
+    }
+}
+
+impl From<TapHold> for WebUiTapHoldDto {
+    fn from(value: TapHold) -> Self {
+        Self {
+            hold_key: value.hold_key,
+            tapping_term_ms: value.tapping_term_ms,
+            flavor: value.flavor,
+        }
+    }
+}
+
+impl From<WebUiTapHoldDto> for TapHold {
+    fn from(value: WebUiTapHoldDto) -> Self {
+        Self {
+            hold_key: value.hold_key,
+            tapping_term_ms: value.tapping_term_ms,
+            flavor: value.flavor,
+        }
+    }
+}
+
 impl From<&IntentSpec> for WebUiIntentDto {
     fn from(value: &IntentSpec) -> Self {
         Self {
@@ -340,6 +688,12 @@ fn action_to_webui_dto(action: &Action) -> WebUiActionDto {
         Action::SendKey { key_code } => WebUiActionDto::SendKey {
             key_code: *key_code,
         },
+        Action::KeyDown { key_code } => WebUiActionDto::KeyDown {
+            key_code: *key_code,
+        },
+        Action::KeyUp { key_code } => WebUiActionDto::KeyUp {
+            key_code: *key_code,
+        },
         Action::BuiltinCommand { command } => WebUiActionDto::BuiltinCommand {
             command: (*command).into(),
         },
@@ -355,9 +709,20 @@ fn action_to_webui_dto(action: &Action) -> WebUiActionDto {
             interval_ms: *interval_ms,
         },
         Action::ToggleScreen => WebUiActionDto::ToggleScreen,
+        Action::ScreenOn => WebUiActionDto::ScreenOn,
+        Action::ScreenOff => WebUiActionDto::ScreenOff,
         Action::ToggleRule { rule_id } => WebUiActionDto::ToggleRule {
             rule_id: rule_id.clone(),
         },
+        Action::SetVariable { name, value } => WebUiActionDto::SetVariable {
+            name: name.clone(),
+            value: value.clone(),
+        },
+        Action::IncrementVariable { name, by } => WebUiActionDto::IncrementVariable {
+            name: name.clone(),
+            by: *by,
+        },
+        Action::ToggleVariable { name } => WebUiActionDto::ToggleVariable { name: name.clone() },
         Action::VolumeControl { direction } => WebUiActionDto::VolumeControl {
             direction: (*direction).into(),
         },
@@ -374,6 +739,15 @@ fn action_to_webui_dto(action: &Action) -> WebUiActionDto {
             duration_ms: *duration_ms,
         },
         Action::Intercept => WebUiActionDto::Intercept,
+        Action::LayerMomentary { layer } => WebUiActionDto::LayerMomentary {
+            layer: layer.clone(),
+        },
+        Action::LayerToggle { layer } => WebUiActionDto::LayerToggle {
+            layer: layer.clone(),
+        },
+        Action::LayerOneShot { layer } => WebUiActionDto::LayerOneShot {
+            layer: layer.clone(),
+        },
         Action::Macro { actions } => WebUiActionDto::Macro {
             actions: actions.iter().map(action_to_webui_dto).collect(),
         },
@@ -385,6 +759,8 @@ impl From<WebUiActionDto> for Action {
         match value {
             WebUiActionDto::RunShell { command } => Action::Shell { cmd: command },
             WebUiActionDto::SendKey { key_code } => Action::SendKey { key_code },
+            WebUiActionDto::KeyDown { key_code } => Action::KeyDown { key_code },
+            WebUiActionDto::KeyUp { key_code } => Action::KeyUp { key_code },
             WebUiActionDto::BuiltinCommand { command } => Action::BuiltinCommand {
                 command: command.into(),
             },
@@ -398,7 +774,14 @@ impl From<WebUiActionDto> for Action {
                 Action::MultiTap { codes, interval_ms }
             }
             WebUiActionDto::ToggleScreen => Action::ToggleScreen,
+            WebUiActionDto::ScreenOn => Action::ScreenOn,
+            WebUiActionDto::ScreenOff => Action::ScreenOff,
             WebUiActionDto::ToggleRule { rule_id } => Action::ToggleRule { rule_id },
+            WebUiActionDto::SetVariable { name, value } => Action::SetVariable { name, value },
+            WebUiActionDto::IncrementVariable { name, by } => {
+                Action::IncrementVariable { name, by }
+            }
+            WebUiActionDto::ToggleVariable { name } => Action::ToggleVariable { name },
             WebUiActionDto::VolumeControl { direction } => Action::VolumeControl {
                 direction: direction.into(),
             },
@@ -415,6 +798,9 @@ impl From<WebUiActionDto> for Action {
                 duration_ms,
             },
             WebUiActionDto::Intercept => Action::Intercept,
+            WebUiActionDto::LayerMomentary { layer } => Action::LayerMomentary { layer },
+            WebUiActionDto::LayerToggle { layer } => Action::LayerToggle { layer },
+            WebUiActionDto::LayerOneShot { layer } => Action::LayerOneShot { layer },
             WebUiActionDto::Macro { actions } => Action::Macro {
                 actions: actions.into_iter().map(Into::into).collect(),
             },
@@ -438,39 +824,168 @@ fn condition_from_rule(
     trigger: &str,
     rule_type: RuleType,
     name_to_code: &std::collections::HashMap<String, u16>,
-) -> WebUiConditionDto {
-    let behavior = WebUiBehaviorDto::from_rule_type(rule_type);
+) -> Result<WebUiConditionDto> {
+    let behavior = WebUiBehaviorDto::from_rule_type(rule_type)?;
+    if matches!(behavior, WebUiBehaviorDto::Sequence) {
+        // A dropped step would save back a different sequence
+        let sequence_key_codes = trigger
+            .split("->")
+            .map(|step| {
+                parse_token_to_code(step.trim(), name_to_code).ok_or_else(|| {
+                    anyhow!("sequence trigger '{}' has unknown step '{}'", trigger, step.trim())
+                })
+            })
+            .collect::<Result<Vec<u16>>>()?;
+        return Ok(WebUiConditionDto::KeyEvent {
+            key_code: None,
+            combo_key_code: None,
+            extra_key_codes: Vec::new(),
+            sequence_key_codes,
+            behavior,
+        });
+    }
     if behavior.is_combo() {
-        if let Some((left, right)) = trigger.split_once('+') {
-            let key_code = parse_token_to_code(left.trim(), name_to_code);
-            let combo_key_code = parse_token_to_code(right.trim(), name_to_code);
-            return WebUiConditionDto::KeyEvent { key_code, combo_key_code, behavior };
+        // Every chord key must resolve; dropping one would save back a smaller chord
+        let codes = trigger
+            .split('+')
+            .map(|part| {
+                parse_token_to_code(part.trim(), name_to_code).ok_or_else(|| {
+                    anyhow!("combo trigger '{}' has unknown key '{}'", trigger, part.trim())
+                })
+            })
+            .collect::<Result<Vec<u16>>>()?;
+        if let [key_code, combo_key_code, extra_key_codes @ ..] = codes.as_slice() {
+            return Ok(WebUiConditionDto::KeyEvent {
+                key_code: Some(*key_code),
+                combo_key_code: Some(*combo_key_code),
+                extra_key_codes: extra_key_codes.to_vec(),
+                sequence_key_codes: Vec::new(),
+                behavior,
+            });
         }
     }
     let key_code = parse_token_to_code(trigger.trim(), name_to_code);
-    WebUiConditionDto::KeyEvent { key_code, combo_key_code: None, behavior }
+    Ok(WebUiConditionDto::KeyEvent {
+        key_code,
+        combo_key_code: None,
+        extra_key_codes: Vec::new(),
+        sequence_key_codes: Vec::new(),
+        behavior,
+    })
 }
 
 /// Convert a condition DTO back to (trigger_string, RuleType)
 fn condition_to_trigger(cond: &WebUiConditionDto) -> Result<(String, RuleType)> {
     match cond {
-        WebUiConditionDto::KeyEvent { key_code, combo_key_code, behavior } => {
+        WebUiConditionDto::KeyEvent {
+            key_code,
+            combo_key_code,
+            extra_key_codes,
+            sequence_key_codes,
+            behavior,
+        } => {
             let rule_type = behavior.into_rule_type();
+            if matches!(behavior, WebUiBehaviorDto::Sequence) {
+                if sequence_key_codes.len() < 2 {
+                    bail!("sequence rule requires at least 2 sequenceKeyCodes");
+                }
+                let trigger = sequence_key_codes
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("->");
+                return Ok((trigger, rule_type));
+            }
+            if !sequence_key_codes.is_empty() {
+                bail!("sequenceKeyCodes is only valid for sequence rules");
+            }
             if behavior.is_combo() {
                 let k1 = key_code.ok_or_else(|| anyhow!("combo rule requires keyCode"))?;
                 let k2 = combo_key_code.ok_or_else(|| anyhow!("combo rule requires comboKeyCode"))?;
                 if k1 == k2 {
                     bail!("combo key codes cannot be identical");
                 }
-                Ok((format!("{}+{}", k1, k2), rule_type))
+                let mut codes = vec![k1, k2];
+                for k in extra_key_codes {
+                    if codes.contains(k) {
+                        bail!("combo key codes cannot be identical");
+                    }
+                    codes.push(*k);
+                }
+                let trigger = codes
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("+");
+                Ok((trigger, rule_type))
             } else {
-                if combo_key_code.is_some() {
+                if combo_key_code.is_some() || !extra_key_codes.is_empty() {
                     bail!("comboKeyCode is only valid for combo rules");
                 }
                 let k = key_code.ok_or_else(|| anyhow!("rule requires keyCode"))?;
                 Ok((k.to_string(), rule_type))
             }
         }
+        _ => bail!("not a key_event condition"),
+    }
+}
+
+impl From<Condition> for WebUiConditionDto {
+    fn from(c: Condition) -> Self {
+        match c {
+            Condition::TimeRange { start, end } => Self::TimeRange { start, end },
+            Condition::Weekdays { days } => Self::Weekdays { days },
+            Condition::ForegroundApp { packages, mode } => Self::ForegroundApp { packages, mode },
+            Condition::ScreenState { state } => Self::ScreenState { state },
+            Condition::Variable { name, op, value } => Self::Variable { name, op, value },
+            Condition::BatteryLevel { op, value } => Self::BatteryLevel { op, value },
+            Condition::Charging { charging } => Self::Charging { charging },
+            Condition::Switch { switch, on } => Self::Switch { switch, on },
+            Condition::All { conditions } => Self::All {
+                conditions: conditions.into_iter().map(Into::into).collect(),
+            },
+            Condition::Any { conditions } => Self::Any {
+                conditions: conditions.into_iter().map(Into::into).collect(),
+            },
+            Condition::Not { condition } => Self::Not {
+                condition: Box::new((*condition).into()),
+            },
+        }
+    }
+}
+
+impl WebUiConditionDto {
+    fn is_trigger(&self) -> bool {
+        matches!(self, Self::KeyEvent { .. } | Self::External { .. })
+    }
+
+    /// The rule condition this DTO stands for; trigger conditions have none.
+    fn into_rule_condition(self) -> Result<Condition> {
+        let nested = |conditions: Vec<Self>| -> Result<Vec<Condition>> {
+            conditions.into_iter().map(Self::into_rule_condition).collect()
+        };
+        Ok(match self {
+            Self::KeyEvent { .. } | Self::External { .. } => {
+                bail!("key_event / external is only valid as the rule trigger")
+            }
+            Self::TimeRange { start, end } => Condition::TimeRange { start, end },
+            Self::Weekdays { days } => Condition::Weekdays { days },
+            Self::ForegroundApp { packages, mode } => Condition::ForegroundApp { packages, mode },
+            Self::ScreenState { state } => Condition::ScreenState { state },
+            Self::Variable { name, op, value } => Condition::Variable { name, op, value },
+            Self::BatteryLevel { op, value } => Condition::BatteryLevel { op, value },
+            Self::Charging { charging } => Condition::Charging { charging },
+            Self::Switch { switch, on } => Condition::Switch { switch, on },
+            Self::All { conditions } => Condition::All {
+                conditions: nested(conditions)?,
+            },
+            Self::Any { conditions } => Condition::Any {
+                conditions: nested(conditions)?,
+            },
+            Self::Not { condition } => Condition::Not {
+                condition: Box::new(condition.into_rule_condition()?),
+            },
+        })
     }
 }
 
@@ -496,29 +1011,39 @@ fn dto_list_to_action(actions: Vec<WebUiActionDto>) -> Result<Action> {
     }
 }
 
-fn config_to_webui_dto(cfg: &Config) -> WebUiConfigDto {
+fn config_to_webui_dto(cfg: &Config) -> Result<WebUiConfigDto> {
     let name_to_code: std::collections::HashMap<String, u16> = cfg
         .hardware_map
         .iter()
         .map(|(code, name)| (name.clone(), *code))
         .collect();
 
-    let mut rules = Vec::with_capacity(cfg.rules.len());
-    for r in &cfg.rules {
-        let condition = condition_from_rule(&r.trigger, r.rule_type, &name_to_code);
-        let actions = action_to_dto_list(&r.action);
-
-        rules.push(WebUiRuleDto {
-            id: Some(r.id.clone()),
-            enabled: r.enabled,
-            description: r.description.clone(),
-            condition_logic: "and".to_string(),
-            conditions: vec![condition],
-            actions,
-        });
-    }
+    let rules = cfg
+        .rules
+        .iter()
+        .map(|r| rule_to_webui_dto(r, &name_to_code))
+        .collect::<Result<_>>()?;
+    let layers = cfg
+        .layers
+        .iter()
+        .map(|layer| {
+            Ok(WebUiLayerDto {
+                name: layer.name.clone(),
+                rules: layer
+                    .rules
+                    .iter()
+                    .map(|r| rule_to_webui_dto(r, &name_to_code))
+                    .collect::<Result<_>>()?,
+                remap: layer
+                    .remap
+                    .iter()
+                    .map(|(source, target)| (source.clone(), *target))
+                    .collect(),
+            })
+        })
+        .collect::<Result<_>>()?;
 
-    WebUiConfigDto {
+    Ok(WebUiConfigDto {
         version: 1,
         device_name: cfg.device_name.clone(),
         hardware_map: cfg
@@ -526,18 +1051,172 @@ fn config_to_webui_dto(cfg: &Config) -> WebUiConfigDto {
             .iter()
             .map(|(code, name)| (*code, name.clone()))
             .collect(),
+        remap: Some(
+            cfg.remap
+                .iter()
+                .map(|(source, target)| (source.clone(), *target))
+                .collect(),
+        ),
         double_press_interval_ms: cfg.settings.double_tap_interval_ms,
         long_press_min_ms: cfg.settings.long_press_threshold_ms,
         short_press_min_ms: cfg.settings.short_press_threshold_ms,
         combination_timeout_ms: cfg.settings.combination_timeout_ms,
         rule_timeout_ms: cfg.settings.rule_timeout_ms,
+        sequence_gap_ms: cfg.settings.sequence_gap_ms,
         rules,
+        layers: Some(layers),
+        key_repeat_policy: Some(
+            cfg.key_repeat_policy
+                .iter()
+                .map(|(key, policy)| (key.clone(), *policy))
+                .collect(),
+        ),
+    })
+}
+
+fn rule_to_webui_dto(
+    r: &Rule,
+    name_to_code: &std::collections::HashMap<String, u16>,
+) -> Result<WebUiRuleDto> {
+    let trigger = if r.rule_type == RuleType::External {
+        let (source, event) = r.external_trigger();
+        WebUiConditionDto::External {
+            source: source.to_string(),
+            event: event.map(str::to_string),
+            payload: r
+                .payload
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect(),
+        }
+    } else {
+        condition_from_rule(&r.trigger, r.rule_type, name_to_code)
+            .map_err(|e| anyhow!("Rule '{}': {}", r.id, e))?
+    };
+    // A lone top-level any group is shown as conditionLogic "or" (see webui_dto_to_rule)
+    let (condition_logic, rule_conditions) = match r.conditions.as_slice() {
+        [Condition::Any { conditions }] => ("or", conditions),
+        _ => ("and", &r.conditions),
+    };
+    let mut conditions = vec![trigger];
+    conditions.extend(rule_conditions.iter().cloned().map(Into::into));
+    let actions = action_to_dto_list(&r.action);
+
+    Ok(WebUiRuleDto {
+        id: Some(r.id.clone()),
+        enabled: r.enabled,
+        description: r.description.clone(),
+        condition_logic: condition_logic.to_string(),
+        conditions,
+        actions,
+        sequence_fallback: r.sequence_fallback,
+        count: r.count,
+        timing: r.timing.into(),
+        repeat: r.repeat.clone().map(Into::into),
+        tap_hold: r.tap_hold.clone().map(Into::into),
+        passthrough: r.passthrough,
+        on_release: r.on_release,
+        repeat_policy: r.repeat_policy,
+        ordered: r.ordered,
+        modifier_hold_ms: r.modifier_hold_ms,
+        cooldown_ms: r.cooldown_ms,
+        max_per_minute: r.max_per_minute,
+        pattern: r.pattern.clone(),
+    })
+}
+
+/// Convert a rule DTO back to a Rule; the error is the client-facing message.
+fn webui_dto_to_rule(r: WebUiRuleDto) -> std::result::Result<Rule, String> {
+    // Exactly 1 key_event / external trigger condition; the others are rule conditions
+    let triggers = r.conditions.iter().filter(|c| c.is_trigger()).count();
+    if triggers != 1 {
+        return Err(format!(
+            "Rule {:?}: requires exactly 1 key_event or external condition, got {}",
+            r.id, triggers
+        ));
+    }
+    if r.condition_logic != "and" && r.condition_logic != "or" {
+        return Err(format!(
+            "Rule {:?}: unsupported conditionLogic {:?}",
+            r.id, r.condition_logic
+        ));
     }
+
+    let (trigger, rule_type, payload) = match r.conditions.iter().find(|c| c.is_trigger()) {
+        Some(WebUiConditionDto::External {
+            source,
+            event,
+            payload,
+        }) => {
+            let trigger = match event {
+                Some(event) => format!("{}:{}", source, event),
+                None => source.clone(),
+            };
+            let payload = payload
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect();
+            (trigger, RuleType::External, payload)
+        }
+        Some(key_event) => {
+            let (trigger, rule_type) = condition_to_trigger(key_event)
+                .map_err(|e| format!("Invalid condition for rule {:?}: {}", r.id, e))?;
+            (trigger, rule_type, Default::default())
+        }
+        None => unreachable!("counted above"),
+    };
+    let conditions: Vec<Condition> = r
+        .conditions
+        .into_iter()
+        .filter(|c| !c.is_trigger())
+        .map(WebUiConditionDto::into_rule_condition)
+        .collect::<Result<_>>()
+        .map_err(|e| format!("Invalid condition for rule {:?}: {}", r.id, e))?;
+    // "or" keeps its conditions as one any group, even a single one, so the tree round-trips
+    let conditions = if r.condition_logic == "or" && !conditions.is_empty() {
+        vec![Condition::Any { conditions }]
+    } else {
+        conditions
+    };
+    let action = dto_list_to_action(r.actions)
+        .map_err(|e| format!("Invalid action for rule {:?}: {}", r.id, e))?;
+
+    let id =
+        r.id.clone()
+            .filter(|s| !s.trim().is_empty())
+            .unwrap_or_else(|| format!("{}_{}", Uuid::new_v4(), trigger));
+
+    Ok(Rule {
+        id,
+        trigger,
+        rule_type,
+        action,
+        enabled: r.enabled,
+        description: r.description,
+        sequence_fallback: r.sequence_fallback,
+        count: r.count,
+        timing: r.timing.into(),
+        repeat: r.repeat.map(Into::into),
+        tap_hold: r.tap_hold.map(Into::into),
+        passthrough: r.passthrough,
+        on_release: r.on_release,
+        repeat_policy: r.repeat_policy,
+        ordered: r.ordered,
+        modifier_hold_ms: r.modifier_hold_ms,
+        cooldown_ms: r.cooldown_ms,
+        max_per_minute: r.max_per_minute,
+        pattern: r.pattern,
+        conditions,
+        payload,
+    })
 }
 
 pub async fn get_config(State(state): State<AppState>) -> impl IntoResponse {
     let cfg = state.config.read().await;
-    Json(config_to_webui_dto(&cfg)).into_response()
+    match config_to_webui_dto(&cfg) {
+        Ok(dto) => Json(dto).into_response(),
+        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
 }
 
 pub async fn save_config(
@@ -568,58 +1247,46 @@ pub async fn save_config(
             .collect();
     }
 
+    if let Some(remap) = dto.remap {
+        new_config.remap = remap.into_iter().collect();
+    }
+    if let Some(policies) = dto.key_repeat_policy {
+        new_config.key_repeat_policy = policies.into_iter().collect();
+    }
+
     new_config.settings.double_tap_interval_ms = dto.double_press_interval_ms;
     new_config.settings.long_press_threshold_ms = dto.long_press_min_ms;
     new_config.settings.short_press_threshold_ms = dto.short_press_min_ms;
     new_config.settings.combination_timeout_ms = dto.combination_timeout_ms;
     new_config.settings.rule_timeout_ms = dto.rule_timeout_ms;
+    new_config.settings.sequence_gap_ms = dto.sequence_gap_ms;
     new_config.rules.clear();
 
+    let bad_request =
+        |msg: String| (axum::http::StatusCode::BAD_REQUEST, msg).into_response();
     for r in dto.rules {
-        // V1: exactly 1 condition of type key_event
-        if r.conditions.len() != 1 {
-            return (
-                axum::http::StatusCode::BAD_REQUEST,
-                format!("Rule {:?}: V1 requires exactly 1 condition, got {}", r.id, r.conditions.len()),
-            )
-                .into_response();
+        match webui_dto_to_rule(r) {
+            Ok(rule) => new_config.rules.push(rule),
+            Err(msg) => return bad_request(msg),
         }
+    }
 
-        let (trigger, rule_type) = match condition_to_trigger(&r.conditions[0]) {
-            Ok(v) => v,
-            Err(e) => {
-                return (
-                    axum::http::StatusCode::BAD_REQUEST,
-                    format!("Invalid condition for rule {:?}: {}", r.id, e),
-                )
-                    .into_response();
-            }
-        };
-
-        let action = match dto_list_to_action(r.actions) {
-            Ok(v) => v,
-            Err(e) => {
-                return (
-                    axum::http::StatusCode::BAD_REQUEST,
-                    format!("Invalid action for rule {:?}: {}", r.id, e),
-                )
-                    .into_response();
+    if let Some(layers) = dto.layers {
+        new_config.layers.clear();
+        for layer in layers {
+            let mut rules = Vec::with_capacity(layer.rules.len());
+            for r in layer.rules {
+                match webui_dto_to_rule(r) {
+                    Ok(rule) => rules.push(rule),
+                    Err(msg) => return bad_request(msg),
+                }
             }
-        };
-
-        let id =
-            r.id.clone()
-                .filter(|s| !s.trim().is_empty())
-                .unwrap_or_else(|| format!("{}_{}", Uuid::new_v4(), trigger));
-
-        new_config.rules.push(Rule {
-            id,
-            trigger,
-            rule_type,
-            action,
-            enabled: r.enabled,
-            description: r.description,
-        });
+            new_config.layers.push(Layer {
+                name: layer.name,
+                rules,
+                remap: layer.remap.into_iter().collect(),
+            });
+        }
     }
 
     if let Err(e) = new_config.validate() {
@@ -660,6 +1327,53 @@ pub async fn save_config(
     (axum::http::StatusCode::OK, "Saved").into_response()
 }
 
+/// Rule-conflict warnings of the current config (valid but ambiguous or shadowed rules).
+pub async fn get_config_warnings(State(state): State<AppState>) -> impl IntoResponse {
+    let cfg = state.config.read().await;
+    let warnings = cfg.analyze().into_iter().map(Into::into).collect();
+    Json(ConfigWarningsDto { warnings })
+}
+
+/// Runtime rule counters (fires suppressed by cooldown_ms / max_per_minute).
+pub async fn get_rule_stats(State(state): State<AppState>) -> impl IntoResponse {
+    let stats = state.rule_stats.lock();
+    let suppressed_fires: BTreeMap<String, u64> = stats
+        .suppressed_fires
+        .iter()
+        .map(|(id, count)| (id.clone(), *count))
+        .collect();
+    Json(RuleStatsDto {
+        total_suppressed: suppressed_fires.values().sum(),
+        suppressed_fires,
+    })
+}
+
+/// Queue a non-key event for the EXTERNAL rules; 202 once the event processor has it.
+pub async fn post_trigger(
+    State(state): State<AppState>,
+    Json(dto): Json<TriggerDto>,
+) -> impl IntoResponse {
+    use tokio::sync::mpsc::error::TrySendError;
+    let event = match dto.into_event() {
+        Ok(event) => event,
+        Err(msg) => return (axum::http::StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+    log::debug!("External trigger: {:?}", event);
+    match state.external_tx.try_send(event) {
+        Ok(()) => (axum::http::StatusCode::ACCEPTED, "Queued").into_response(),
+        Err(TrySendError::Full(_)) => (
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "Trigger queue is full",
+        )
+            .into_response(),
+        Err(TrySendError::Closed(_)) => (
+            axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            "Event processor is not running",
+        )
+            .into_response(),
+    }
+}
+
 pub async fn list_apps(State(state): State<AppState>) -> impl IntoResponse {
     use crate::webui::app_cache::update_app_cache;
     if let Err(e) = update_app_cache(&state.app_cache).await {
@@ -721,14 +1435,16 @@ mod tests {
         // 1. Rule -> DTO
         let trigger = "115+114";
         let rule_type = RuleType::ComboShortPress;
-        let dto = condition_from_rule(trigger, rule_type, &map);
+        let dto = condition_from_rule(trigger, rule_type, &map).unwrap();
 
         match dto {
-            WebUiConditionDto::KeyEvent { key_code, combo_key_code, behavior } => {
+            WebUiConditionDto::KeyEvent { key_code, combo_key_code, ref extra_key_codes, behavior, .. } => {
                 assert_eq!(key_code, Some(115));
                 assert_eq!(combo_key_code, Some(114));
+                assert!(extra_key_codes.is_empty());
                 assert!(behavior.is_combo());
             }
+            other => panic!("expected key_event, got {:?}", other),
         }
 
         // 2. DTO -> Rule
@@ -742,14 +1458,15 @@ mod tests {
         let map = HashMap::new();
         let trigger = "115";
         let rule_type = RuleType::LongPress;
-        let dto = condition_from_rule(trigger, rule_type, &map);
+        let dto = condition_from_rule(trigger, rule_type, &map).unwrap();
 
         match dto {
-            WebUiConditionDto::KeyEvent { key_code, combo_key_code, behavior } => {
+            WebUiConditionDto::KeyEvent { key_code, combo_key_code, behavior, .. } => {
                 assert_eq!(key_code, Some(115));
                 assert_eq!(combo_key_code, None);
                 assert!(!behavior.is_combo());
             }
+            other => panic!("expected key_event, got {:?}", other),
         }
 
         let (out_trigger, out_type) = condition_to_trigger(&dto).expect("conversion failed");
@@ -757,6 +1474,91 @@ mod tests {
         assert_eq!(out_type, RuleType::LongPress);
     }
 
+    #[test]
+    fn condition_conversion_three_key_combo_roundtrip() {
+        let mut map = HashMap::new();
+        map.insert("POWER".to_string(), 116);
+
+        let dto = condition_from_rule("115+114+POWER", RuleType::ComboClick, &map).unwrap();
+        let (out_trigger, out_type) = condition_to_trigger(&dto).expect("conversion failed");
+        assert_eq!(out_trigger, "115+114+116");
+        assert_eq!(out_type, RuleType::ComboClick);
+
+        // An unresolved chord key fails instead of shrinking the chord
+        let err = condition_from_rule("115+114+HOME", RuleType::ComboClick, &map).unwrap_err();
+        assert!(err.to_string().contains("HOME"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn condition_conversion_sequence_roundtrip() {
+        let mut map = HashMap::new();
+        map.insert("VOL_UP".to_string(), 115);
+
+        let dto = condition_from_rule("VOL_UP->VOL_UP->114", RuleType::Sequence, &map).unwrap();
+        let (out_trigger, out_type) = condition_to_trigger(&dto).expect("conversion failed");
+        assert_eq!(out_trigger, "115->115->114");
+        assert_eq!(out_type, RuleType::Sequence);
+
+        let err = condition_from_rule("VOL_UP->HOME", RuleType::Sequence, &map).unwrap_err();
+        assert!(err.to_string().contains("HOME"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn condition_from_rule_should_reject_external_rules() {
+        let err = condition_from_rule("GEOFENCE", RuleType::External, &HashMap::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("EXTERNAL"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn external_rule_roundtrip_and_trigger_payload() {
+        let yaml = "id: home\ntrigger: \"GEOFENCE:ENTER\"\nrule_type: EXTERNAL\npayload:\n  location_id: HOME\naction:\n  type: send_key\n  key_code: 30\n";
+        let rule: Rule = serde_yaml::from_str(yaml).unwrap();
+        let restored = webui_dto_to_rule(rule_to_webui_dto(&rule, &HashMap::new()).unwrap())
+            .expect("conversion failed");
+        assert_eq!(restored.trigger, "GEOFENCE:ENTER");
+        assert_eq!(restored.rule_type, RuleType::External);
+        assert_eq!(restored.payload, rule.payload);
+
+        let body = r#"{"source":"GEOFENCE","event":"ENTER","payload":{"location_id":"HOME","floor":2}}"#;
+        let dto: TriggerDto = serde_yaml::from_str(body).unwrap();
+        let event = dto.into_event().unwrap();
+        assert_eq!(event.payload["location_id"], "HOME");
+        assert_eq!(event.payload["floor"], "2");
+
+        let dto: TriggerDto = serde_yaml::from_str(r#"{"source":" "}"#).unwrap();
+        assert!(dto.into_event().is_err());
+    }
+
+    #[test]
+    fn condition_tree_roundtrip_and_or_logic() {
+        let yaml = "id: quiet\ntrigger: \"114\"\nrule_type: CLICK\nconditions:\n  - type: any\n    conditions:\n      - type: weekdays\n        days: [sat]\n      - type: not\n        condition:\n          type: all\n          conditions:\n            - type: time_range\n              start: \"09:00\"\n              end: \"17:00\"\naction:\n  type: send_key\n  key_code: 113\n";
+        let rule: Rule = serde_yaml::from_str(yaml).unwrap();
+        let dto = rule_to_webui_dto(&rule, &HashMap::new()).unwrap();
+        assert_eq!(dto.condition_logic, "or");
+        assert_eq!(dto.conditions.len(), 3);
+        let restored = webui_dto_to_rule(dto).expect("conversion failed");
+        assert_eq!(restored.conditions, rule.conditions);
+
+        // Two top-level groups stay "and"
+        let mut rule = restored;
+        rule.conditions.push(rule.conditions[0].clone());
+        let dto = rule_to_webui_dto(&rule, &HashMap::new()).unwrap();
+        assert_eq!(dto.condition_logic, "and");
+        assert_eq!(webui_dto_to_rule(dto).unwrap().conditions, rule.conditions);
+
+        // A trigger is not a condition inside a group
+        let mut dto = rule_to_webui_dto(&rule, &HashMap::new()).unwrap();
+        let trigger = dto.conditions[0].clone();
+        dto.conditions.push(WebUiConditionDto::Not {
+            condition: Box::new(trigger),
+        });
+        assert!(webui_dto_to_rule(dto).is_err());
+    }
+
     #[test]
     fn action_list_macro_roundtrip() {
         let original = Action::Macro {
@@ -2,5 +2,6 @@ pub mod app_cache;
 pub mod handlers;
 pub mod learn;
 pub mod server;
+pub mod status;
 
 pub use server::WebServer;
@@ -1,6 +1,8 @@
 use crate::config::Config;
+use crate::event::external::ExternalEvent;
 use crate::webui::app_cache::AppCache;
 use crate::webui::learn::LearnState;
+use crate::webui::status::RuleStats;
 use anyhow::Result;
 use axum::{
     routing::{get, post},
@@ -11,7 +13,7 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use parking_lot::Mutex;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use tower_http::services::ServeDir;
 
 pub struct WebServer;
@@ -21,6 +23,8 @@ pub struct AppState {
     pub config_path: Arc<PathBuf>,
     pub learn_state: Arc<Mutex<LearnState>>,
     pub app_cache: Arc<tokio::sync::RwLock<AppCache>>,
+    pub rule_stats: Arc<Mutex<RuleStats>>,
+    pub external_tx: mpsc::Sender<ExternalEvent>,
 }
 
 impl WebServer {
@@ -30,12 +34,16 @@ impl WebServer {
         port: u16,
         learn_state: Arc<Mutex<LearnState>>,
         app_cache: Arc<tokio::sync::RwLock<AppCache>>,
+        rule_stats: Arc<Mutex<RuleStats>>,
+        external_tx: mpsc::Sender<ExternalEvent>,
     ) -> Result<()> {
         let state = AppState {
             config,
             config_path: Arc::new(config_path),
             learn_state,
             app_cache: app_cache.clone(),
+            rule_stats,
+            external_tx,
         };
 
         let app = Router::new()
@@ -43,7 +51,13 @@ impl WebServer {
                 "/api/config",
                 get(super::handlers::get_config).post(super::handlers::save_config),
             )
+            .route(
+                "/api/config/warnings",
+                get(super::handlers::get_config_warnings),
+            )
+            .route("/api/status/rules", get(super::handlers::get_rule_stats))
             .route("/api/apps", get(super::handlers::list_apps))
+            .route("/api/trigger", post(super::handlers::post_trigger))
             .route(
                 "/api/system/learn-start",
                 post(super::handlers::start_learning),
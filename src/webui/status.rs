@@ -0,0 +1,8 @@
+use std::collections::HashMap;
+
+/// Runtime counters published by the event loop for the status API.
+#[derive(Debug, Default, Clone)]
+pub struct RuleStats {
+    /// Fires held back by a rule's cooldown_ms / max_per_minute, per rule id
+    pub suppressed_fires: HashMap<String, u64>,
+}